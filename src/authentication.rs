@@ -1,13 +1,40 @@
+use crate::gcloud::auth::service_account::ServiceAccountKey;
+use crate::gcloud::auth::AuthError;
+
 use chrono::prelude::*;
+use chrono::Duration;
 use serde::{Deserialize, Serialize};
 
 const URL_AUTH: &'static str = "https://oauth2.googleapis.com/token";
+// the maximum of google: exp - iat <= 1 hour
+const MAX_LIFETIME_SECS: i64 = 3600;
+// the clocks of the client and of google differ, an iat in the future is rejected
+const DEFAULT_CLOCK_SKEW_SECS: i64 = 10;
+
+/// The oauth2 scopes, see: https://developers.google.com/identity/protocols/oauth2/scopes
+pub mod scope {
+    pub const CLOUD_PLATFORM: &str = "https://www.googleapis.com/auth/cloud-platform";
+    pub const CLOUD_PLATFORM_READ_ONLY: &str =
+        "https://www.googleapis.com/auth/cloud-platform.read-only";
+    pub const DATASTORE: &str = "https://www.googleapis.com/auth/datastore";
+    pub const DEVSTORAGE_READ_ONLY: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+    pub const DEVSTORAGE_READ_WRITE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+    pub const DEVSTORAGE_FULL_CONTROL: &str =
+        "https://www.googleapis.com/auth/devstorage.full_control";
+    pub const PUBSUB: &str = "https://www.googleapis.com/auth/pubsub";
+    pub const LOGGING_WRITE: &str = "https://www.googleapis.com/auth/logging.write";
+    pub const BIGQUERY: &str = "https://www.googleapis.com/auth/bigquery";
+    pub const USERINFO_EMAIL: &str = "https://www.googleapis.com/auth/userinfo.email";
+}
+
+const DEFAULT_SCOPES: &[&str] = &[scope::DEVSTORAGE_READ_ONLY, scope::DATASTORE];
 
 // https://developers.google.com/identity/protocols/oauth2/service-account
 //
 // iss	The email address of the service account.
 // scope	A space-delimited list of the permissions that the application requests.
 // aud	A descriptor of the intended target of the assertion. When making an access token request this value is always https://oauth2.googleapis.com/token.
+// sub	The email address of the user, for which the application requests delegated access (domain-wide delegation).
 // exp	The expiration time of the assertion, specified as seconds since 00:00:00 UTC, January 1, 1970. This value has a maximum of 1 hour after the issued time.
 // iat	The time the assertion was issued, specified as seconds since 00:00:00 UTC, January 1, 1970.
 
@@ -17,21 +44,249 @@ const URL_AUTH: &'static str = "https://oauth2.googleapis.com/token";
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Claim {
-    iss: String,   // client_email
-    scope: String, // scope: https://www.googleapis.com/auth/devstorage.read_only
-    aud: String,
-    iat: i64,
-    exp: i64,
+    pub iss: String,   // client_email
+    pub scope: String, // scope: https://www.googleapis.com/auth/devstorage.read_only
+    pub aud: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    pub iat: i64,
+    pub exp: i64,
 }
 
 impl Claim {
-    pub fn new() -> Claim {
-        Claim {
-            iss: "bucket@goheros-207118.iam.gserviceaccount.com".to_string(),
-            scope: "https://www.googleapis.com/auth/devstorage.read_only https://www.googleapis.com/auth/datastore".to_string(),
+    /// Create a claim for the service account (client_email) from the json key file.
+    pub fn from_service_account(key: &ServiceAccountKey) -> Result<Claim, AuthError> {
+        let mut builder =
+            ClaimBuilder::new(key.client_email.clone()).audience(key.token_uri.clone());
+        if !key.scopes.is_empty() {
+            builder = builder.scopes(&key.scopes);
+        }
+        if let Some(subject) = &key.subject {
+            builder = builder.subject(subject.clone());
+        }
+        builder.build()
+    }
+}
+
+/// ClaimBuilder validates the claim before it is signed, so an invalid claim
+/// is an error here and not a `400 invalid_grant` of the token endpoint.
+///
+/// ```ignore
+/// let claim = ClaimBuilder::new("bucket@goheros-207118.iam.gserviceaccount.com")
+///     .scope(scope::DATASTORE)
+///     .lifetime(Duration::minutes(30))
+///     .build()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClaimBuilder {
+    iss: String,
+    scopes: Vec<String>,
+    aud: String,
+    sub: Option<String>,
+    lifetime: Duration,
+    clock_skew: Duration,
+    issued_at: Option<DateTime<Utc>>,
+}
+
+impl ClaimBuilder {
+    /// The issuer is the email of the service account (client_email).
+    pub fn new<S: Into<String>>(iss: S) -> Self {
+        ClaimBuilder {
+            iss: iss.into(),
+            scopes: vec![],
             aud: URL_AUTH.to_string(),
-            iat: Utc::now().timestamp(),
-            exp: Utc::now().timestamp() + chrono::Duration::minutes(1).num_seconds(),
+            sub: None,
+            lifetime: Duration::seconds(MAX_LIFETIME_SECS),
+            clock_skew: Duration::seconds(DEFAULT_CLOCK_SKEW_SECS),
+            issued_at: None,
+        }
+    }
+
+    /// Add a scope, without any scope the default is: devstorage.read_only and datastore.
+    pub fn scope<S: Into<String>>(mut self, scope: S) -> Self {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    pub fn scopes<S: AsRef<str>>(mut self, scopes: &[S]) -> Self {
+        self.scopes
+            .extend(scopes.iter().map(|scope| scope.as_ref().to_string()));
+        self
+    }
+
+    /// The token endpoint, default: `https://oauth2.googleapis.com/token`.
+    pub fn audience<S: Into<String>>(mut self, aud: S) -> Self {
+        self.aud = aud.into();
+        self
+    }
+
+    /// The email of the user for domain-wide delegation (G Suite).
+    pub fn subject<S: Into<String>>(mut self, sub: S) -> Self {
+        self.sub = Some(sub.into());
+        self
+    }
+
+    /// The lifetime of the claim, maximum and default: 1 hour.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// The iat is set back by the clock skew, default: 10 seconds.
+    pub fn clock_skew(mut self, clock_skew: Duration) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// The issued time, default: now.
+    pub fn issued_at(mut self, issued_at: DateTime<Utc>) -> Self {
+        self.issued_at = Some(issued_at);
+        self
+    }
+
+    pub fn build(self) -> Result<Claim, AuthError> {
+        if !is_email(&self.iss) {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid iss: '{}', expected an email",
+                self.iss
+            )));
+        }
+        if let Some(sub) = &self.sub {
+            if !is_email(sub) {
+                return Err(AuthError::InvalidArgument(format!(
+                    "invalid sub: '{}', expected an email",
+                    sub
+                )));
+            }
+        }
+        if !self.aud.starts_with("https://") && !self.aud.starts_with("http://") {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid aud: '{}', expected an url",
+                self.aud
+            )));
+        }
+        if let Some(scope) = self
+            .scopes
+            .iter()
+            .find(|scope| !scope.starts_with("https://") || scope.contains(' '))
+        {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid scope: '{}', expected an url",
+                scope
+            )));
         }
+        if self.lifetime <= Duration::zero() || self.lifetime.num_seconds() > MAX_LIFETIME_SECS {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid lifetime: {}s, expected: 1s - {}s",
+                self.lifetime.num_seconds(),
+                MAX_LIFETIME_SECS
+            )));
+        }
+        if self.clock_skew < Duration::zero() || self.clock_skew >= self.lifetime {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid clock skew: {}s, expected: 0s - {}s",
+                self.clock_skew.num_seconds(),
+                self.lifetime.num_seconds() - 1
+            )));
+        }
+
+        let scope = if self.scopes.is_empty() {
+            DEFAULT_SCOPES.join(" ")
+        } else {
+            self.scopes.join(" ")
+        };
+
+        // the claim is valid from (now - clock skew), but not longer than the lifetime
+        let iat = self.issued_at.unwrap_or_else(Utc::now) - self.clock_skew;
+        Ok(Claim {
+            iss: self.iss,
+            scope,
+            aud: self.aud,
+            sub: self.sub,
+            iat: iat.timestamp(),
+            exp: (iat + self.lifetime).timestamp(),
+        })
+    }
+}
+
+fn is_email(s: &str) -> bool {
+    match s.find('@') {
+        Some(i) => i > 0 && i < s.len() - 1 && !s.contains(char::is_whitespace),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ISS: &str = "bucket@goheros-207118.iam.gserviceaccount.com";
+
+    #[test]
+    fn test_build_default() {
+        let now = Utc.with_ymd_and_hms(2020, 4, 1, 10, 0, 0).unwrap();
+        let claim = ClaimBuilder::new(ISS).issued_at(now).build().unwrap();
+        assert_eq!(ISS, claim.iss);
+        assert_eq!(URL_AUTH, claim.aud);
+        assert_eq!(
+            "https://www.googleapis.com/auth/devstorage.read_only https://www.googleapis.com/auth/datastore",
+            claim.scope
+        );
+        assert_eq!(None, claim.sub);
+        assert_eq!(now.timestamp() - DEFAULT_CLOCK_SKEW_SECS, claim.iat);
+        assert_eq!(MAX_LIFETIME_SECS, claim.exp - claim.iat);
+    }
+
+    #[test]
+    fn test_build_scopes_and_subject() {
+        let claim = ClaimBuilder::new(ISS)
+            .scope(scope::DATASTORE)
+            .scopes(&[scope::PUBSUB, scope::DEVSTORAGE_READ_WRITE])
+            .subject("user@example.com")
+            .lifetime(Duration::minutes(5))
+            .clock_skew(Duration::zero())
+            .build()
+            .unwrap();
+        assert_eq!(
+            format!(
+                "{} {} {}",
+                scope::DATASTORE,
+                scope::PUBSUB,
+                scope::DEVSTORAGE_READ_WRITE
+            ),
+            claim.scope
+        );
+        assert_eq!(Some("user@example.com".to_string()), claim.sub);
+        assert_eq!(300, claim.exp - claim.iat);
+
+        let json = serde_json::to_value(&claim).unwrap();
+        assert_eq!("user@example.com", json["sub"]);
+    }
+
+    #[test]
+    fn test_sub_is_not_serialized_without_delegation() {
+        let json = serde_json::to_value(ClaimBuilder::new(ISS).build().unwrap()).unwrap();
+        assert!(json.get("sub").is_none());
+    }
+
+    #[test]
+    fn test_build_invalid() {
+        assert!(ClaimBuilder::new("bucket").build().is_err());
+        assert!(ClaimBuilder::new(ISS).subject("user").build().is_err());
+        assert!(ClaimBuilder::new(ISS).audience("oauth2").build().is_err());
+        assert!(ClaimBuilder::new(ISS).scope("datastore").build().is_err());
+        assert!(ClaimBuilder::new(ISS)
+            .lifetime(Duration::minutes(61))
+            .build()
+            .is_err());
+        assert!(ClaimBuilder::new(ISS)
+            .lifetime(Duration::zero())
+            .build()
+            .is_err());
+        assert!(ClaimBuilder::new(ISS)
+            .lifetime(Duration::seconds(10))
+            .clock_skew(Duration::seconds(10))
+            .build()
+            .is_err());
     }
 }
@@ -0,0 +1,721 @@
+use crate::dotenv::Dotenv;
+use crate::gcloud::REDACTED;
+use crate::secret::{self, Cipher, SecretError};
+
+use log::debug;
+use serde::de::value::SeqDeserializer;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize};
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The env to select the profile, if there is no `--profile` flag.
+pub const ENV_PROFILE: &str = "PORTFOLIO_PROFILE";
+const FLAG_PROFILE: &str = "--profile";
+
+/// The errors of the configuration, e.g. a dotenv file with a syntax error or a missing key.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// A dotenv file can not be read or parsed.
+    File(String),
+    /// The name of the profile is not a valid file suffix.
+    InvalidProfile(String),
+    /// A required key is not set in any layer.
+    MissingKey(String),
+    /// The value can not be converted into the type of the field, e.g. `PORT=abc`.
+    InvalidValue { key: String, message: String },
+    /// An encrypted value can not be decrypted, e.g. without a key file or passphrase.
+    Secret(SecretError),
+}
+
+impl ConfigError {
+    // the key is not known in the deserializer of the value, it is set by the map
+    fn with_key(self, key: &str) -> ConfigError {
+        match self {
+            ConfigError::InvalidValue { key: k, message } if k.is_empty() => {
+                ConfigError::InvalidValue {
+                    key: key.to_string(),
+                    message,
+                }
+            }
+            err => err,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File(msg) => write!(f, "{}", msg),
+            ConfigError::InvalidProfile(profile) => write!(
+                f,
+                "invalid profile: '{}', expected letters, digits, '-' or '_'",
+                profile
+            ),
+            ConfigError::MissingKey(key) => write!(
+                f,
+                "missing key '{}', set it in .env, .env.local, .env.{{profile}} or in the environment",
+                key
+            ),
+            ConfigError::InvalidValue { key, message } if key.is_empty() => write!(f, "{}", message),
+            ConfigError::InvalidValue { key, message } => {
+                write!(f, "invalid value of key '{}': {}", key, message)
+            }
+            ConfigError::Secret(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl de::Error for ConfigError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        ConfigError::InvalidValue {
+            key: String::new(),
+            message: msg.to_string(),
+        }
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        ConfigError::MissingKey(field.to_string())
+    }
+}
+
+/// Config merges the dotenv files and the environment, a later layer overrides an earlier one:
+///
+/// 1. `.env`: the defaults of the project
+/// 2. `.env.local`: the overrides of the developer (not in git)
+/// 3. `.env.{profile}`: e.g. `.env.dev` or `.env.prod`, only with a profile
+/// 4. the environment of the process
+///
+/// A missing file is skipped. Encrypted values (`ENC[...]`) are decrypted with the key file
+/// or the passphrase (see: `Cipher::from_env`), when they are read.
+#[derive(Debug)]
+pub struct Config {
+    profile: Option<String>,
+    values: HashMap<String, String>,
+    cipher: Option<Cipher>,
+}
+
+impl Config {
+    /// Load the layers from the working directory and the environment of the process.
+    pub fn load(profile: Option<&str>) -> Result<Config, ConfigError> {
+        let vars = env::vars_os()
+            .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)));
+        let config = Config::load_from(".", profile, vars)?;
+        let cipher = Cipher::from_env().map_err(ConfigError::Secret)?;
+        Ok(config.with_cipher(cipher))
+    }
+
+    /// Load the dotenv files from the directory, the vars are the last layer.
+    pub fn load_from<P, I>(dir: P, profile: Option<&str>, vars: I) -> Result<Config, ConfigError>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let dir = dir.as_ref();
+        let mut files = vec![dir.join(".env"), dir.join(".env.local")];
+        if let Some(profile) = profile {
+            if !is_valid_profile(profile) {
+                return Err(ConfigError::InvalidProfile(profile.to_string()));
+            }
+            files.push(dir.join(format!(".env.{}", profile)));
+        }
+
+        let mut values = HashMap::new();
+        for file in &files {
+            let dotenv = Dotenv::from_file(file).map_err(ConfigError::File)?;
+            for (key, value) in dotenv.entries() {
+                values.insert(key.clone(), value.clone());
+            }
+        }
+        values.extend(vars);
+
+        debug!(
+            "config loaded: {} (profile: {})",
+            files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            profile.unwrap_or("-")
+        );
+        Ok(Config {
+            profile: profile.map(str::to_string),
+            values,
+            cipher: None,
+        })
+    }
+
+    /// The cipher decrypts the encrypted values, without it an encrypted value is an error,
+    /// but only if it is read.
+    pub fn with_cipher(mut self, cipher: Option<Cipher>) -> Self {
+        self.cipher = cipher;
+        self
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The value of the key from the last layer, which sets it, an encrypted value is not decrypted.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    /// The value of the key, an encrypted value (`ENC[...]`) is decrypted with the cipher.
+    pub fn get_decrypted(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.values.get(key) {
+            Some(value) => decrypt(key, value, self.cipher.as_ref()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Deserialize the merged key-values into the struct, the names of the fields are the keys:
+    ///
+    /// ```ignore
+    /// #[derive(Deserialize)]
+    /// struct Server {
+    ///     #[serde(rename = "PORT")]
+    ///     port: u16,
+    /// }
+    /// let server: Server = config.deserialize()?;
+    /// ```
+    ///
+    /// The values are strings, they are parsed for numbers and bools, an `Option` is `None` for
+    /// a missing key or an empty value and a sequence is a comma separated list.
+    /// Only the encrypted values of the fields are decrypted.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, ConfigError> {
+        T::deserialize(MapDeserializer {
+            values: &self.values,
+            cipher: self.cipher.as_ref(),
+        })
+    }
+}
+
+// without a cipher an encrypted value is an error
+fn decrypt(key: &str, value: &str, cipher: Option<&Cipher>) -> Result<String, ConfigError> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(key, value).map_err(ConfigError::Secret),
+        None if secret::is_encrypted(value) => {
+            Err(ConfigError::Secret(SecretError::MissingKey(format!(
+                "'{}' is encrypted, set env {} or {}",
+                key,
+                secret::ENV_SECRET_KEY_FILE,
+                secret::ENV_PASSPHRASE
+            ))))
+        }
+        None => Ok(value.to_string()),
+    }
+}
+
+/// The profile from the flag `--profile dev` (or `--profile=dev`), otherwise from the env `PORTFOLIO_PROFILE`.
+pub fn select_profile<I: IntoIterator<Item = String>>(args: I) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == FLAG_PROFILE {
+            return args.next();
+        }
+        if let Some(profile) = arg.strip_prefix("--profile=") {
+            return Some(profile.to_string());
+        }
+    }
+    env::var(ENV_PROFILE)
+        .ok()
+        .filter(|profile| !profile.is_empty())
+}
+
+// the profile is a part of the file name, so no path (e.g. '../') is allowed
+fn is_valid_profile(profile: &str) -> bool {
+    !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The settings of the application.
+#[derive(Deserialize)]
+pub struct Settings {
+    #[serde(rename = "PROJECT_ID")]
+    pub project_id: String,
+    #[serde(rename = "NAMESPACE")]
+    pub namespace: String,
+    /// The path of the json key file, without it the application default credentials are used.
+    #[serde(rename = "GOOGLE_APPLICATION_CREDENTIALS")]
+    pub credentials: Option<PathBuf>,
+    /// The api key, it is sent instead of a token of the other credentials.
+    #[serde(rename = "GOOGLE_API_KEY")]
+    pub api_key: Option<String>,
+    /// The scopes of the access token of the service account key: `scope1,scope2`,
+    /// without them and without a subject the key signs the jwt-token self.
+    #[serde(rename = "GOOGLE_SCOPES", default)]
+    pub scopes: Vec<String>,
+    /// The user (email), as which the service account key acts (domain-wide delegation).
+    #[serde(rename = "GOOGLE_SUBJECT")]
+    pub subject: Option<String>,
+    /// The private key (PEM) for the jwt-token login, it should be encrypted in the dotenv file.
+    #[serde(rename = "PRIVATE_KEY")]
+    pub private_key: Option<String>,
+    /// The email of the service account of the private key, it is the issuer of the jwt-token.
+    #[serde(rename = "CLIENT_EMAIL")]
+    pub client_email: Option<String>,
+    /// Act as the last service account (email) of the list, the service accounts before are the
+    /// delegation chain: `delegate@...,target@...`.
+    #[serde(rename = "GOOGLE_IMPERSONATE_SERVICE_ACCOUNT", default)]
+    pub impersonate_service_account: Vec<String>,
+    /// The lifetime of the impersonated access token in seconds (default: 3600).
+    #[serde(rename = "GOOGLE_IMPERSONATE_LIFETIME")]
+    pub impersonate_lifetime: Option<u32>,
+    /// The host of the datastore emulator, e.g. `localhost:8081`.
+    #[serde(rename = "DATASTORE_EMULATOR_HOST")]
+    pub datastore_emulator_host: Option<String>,
+    /// The database of the datastore, without it the default database.
+    #[serde(rename = "DATASTORE_DATABASE")]
+    pub database: Option<String>,
+    /// The number of attempts of a datastore call including the first one (default: 5).
+    #[serde(rename = "DATASTORE_RETRY_MAX_ATTEMPTS")]
+    pub retry_max_attempts: Option<u32>,
+    /// The maximum delay before the first retry in milliseconds (default: 100).
+    #[serde(rename = "DATASTORE_RETRY_INITIAL_BACKOFF_MILLIS")]
+    pub retry_initial_backoff_millis: Option<u64>,
+    /// The total time of all attempts in seconds (default: 60).
+    #[serde(rename = "DATASTORE_RETRY_DEADLINE_SECS")]
+    pub retry_deadline_secs: Option<u64>,
+}
+
+impl fmt::Debug for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Settings")
+            .field("project_id", &self.project_id)
+            .field("namespace", &self.namespace)
+            .field("credentials", &self.credentials)
+            .field("api_key", &self.api_key.as_ref().map(|_| REDACTED))
+            .field("scopes", &self.scopes)
+            .field("subject", &self.subject)
+            .field("private_key", &self.private_key.as_ref().map(|_| REDACTED))
+            .field("client_email", &self.client_email)
+            .field(
+                "impersonate_service_account",
+                &self.impersonate_service_account,
+            )
+            .field("impersonate_lifetime", &self.impersonate_lifetime)
+            .field("datastore_emulator_host", &self.datastore_emulator_host)
+            .field("database", &self.database)
+            .field("retry_max_attempts", &self.retry_max_attempts)
+            .field(
+                "retry_initial_backoff_millis",
+                &self.retry_initial_backoff_millis,
+            )
+            .field("retry_deadline_secs", &self.retry_deadline_secs)
+            .finish()
+    }
+}
+
+// the merged map is a struct (or a map), the values are strings
+struct MapDeserializer<'a> {
+    values: &'a HashMap<String, String>,
+    cipher: Option<&'a Cipher>,
+}
+
+impl<'a> MapDeserializer<'a> {
+    fn entries(self, fields: Option<&'static [&'static str]>) -> Entries<'a> {
+        Entries {
+            iter: self.values.iter(),
+            value: None,
+            fields,
+            cipher: self.cipher,
+        }
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for MapDeserializer<'a> {
+    type Error = ConfigError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_map(self.entries(None))
+    }
+
+    // the other keys (e.g. of the env) are ignored, so they are not decrypted
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        visitor.visit_map(self.entries(Some(fields)))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+struct Entries<'a> {
+    iter: std::collections::hash_map::Iter<'a, String, String>,
+    value: Option<(&'a str, &'a str)>,
+    // the fields of the struct, None for a map
+    fields: Option<&'static [&'static str]>,
+    cipher: Option<&'a Cipher>,
+}
+
+impl<'de, 'a> MapAccess<'de> for Entries<'a> {
+    type Error = ConfigError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, ConfigError> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some((key, value));
+                seed.deserialize(key.as_str().into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, ConfigError> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value without a key"))?;
+        let is_field = self.fields.is_none_or(|fields| fields.contains(&key));
+        if is_field && secret::is_encrypted(value) {
+            let value = decrypt(key, value, self.cipher)?;
+            return seed
+                .deserialize(StringValue(&value))
+                .map_err(|err: ConfigError| err.with_key(key));
+        }
+        seed.deserialize(StringValue(value))
+            .map_err(|err: ConfigError| err.with_key(key))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+                match self.0.trim().parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(err) => Err(de::Error::custom(format!("'{}': {}", self.0, err))),
+                }
+            }
+        )*
+    };
+}
+
+// a value of the dotenv or the env, which is parsed into the type of the field
+struct StringValue<'a>(&'a str);
+
+impl<'de, 'a> IntoDeserializer<'de, ConfigError> for StringValue<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for StringValue<'a> {
+    type Error = ConfigError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        visitor.visit_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    }
+
+    // KEY= is the same as a missing key
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // SCOPES=a,b,c
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ConfigError> {
+        let items = self
+            .0
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(StringValue);
+        SeqDeserializer::new(items).deserialize_any(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, ConfigError> {
+        visitor.visit_enum(self.0.trim().into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 char str string bytes byte_buf unit unit_struct tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::temp_dir;
+    use std::fs;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_load_precedence() {
+        let dir = temp_dir("config_precedence");
+        fs::write(dir.join(".env"), "A=env\nB=env\nC=env\nD=env\n").unwrap();
+        fs::write(dir.join(".env.local"), "B=local\nC=local\nD=local\n").unwrap();
+        fs::write(dir.join(".env.dev"), "C=dev\nD=dev\n").unwrap();
+
+        let config = Config::load_from(&dir, Some("dev"), vars(&[("D", "process")])).unwrap();
+        assert_eq!(Some("dev"), config.profile());
+        assert_eq!(Some("env"), config.get("A"));
+        assert_eq!(Some("local"), config.get("B"));
+        assert_eq!(Some("dev"), config.get("C"));
+        assert_eq!(Some("process"), config.get("D"));
+
+        // without a profile the profile file is not read
+        let config = Config::load_from(&dir, None, vec![]).unwrap();
+        assert_eq!(Some("local"), config.get("C"));
+
+        // a missing profile file is skipped
+        let config = Config::load_from(&dir, Some("prod"), vec![]).unwrap();
+        assert_eq!(Some("local"), config.get("C"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        let dir = temp_dir("config_invalid");
+        assert_eq!(
+            ConfigError::InvalidProfile("../prod".to_string()),
+            Config::load_from(&dir, Some("../prod"), vec![]).unwrap_err()
+        );
+
+        fs::write(dir.join(".env.local"), "A=\"unterminated\n").unwrap();
+        let err = Config::load_from(&dir, None, vec![]).unwrap_err();
+        assert!(err.to_string().contains(".env.local"), "{}", err);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_decrypt() {
+        let cipher = Cipher::generate().unwrap();
+        let dir = temp_dir("config_decrypt");
+        fs::write(
+            dir.join(".env"),
+            format!(
+                "PRIVATE_KEY=\"{}\"\n",
+                cipher.encrypt("PRIVATE_KEY", "secret").unwrap()
+            ),
+        )
+        .unwrap();
+
+        // without a cipher only the read of the encrypted value is an error
+        let config = Config::load_from(&dir, None, vars(&[("NAMESPACE", "heroes")])).unwrap();
+        assert!(matches!(
+            config.get_decrypted("PRIVATE_KEY"),
+            Err(ConfigError::Secret(SecretError::MissingKey(_)))
+        ));
+        assert_eq!(
+            Some("heroes".to_string()),
+            config.get_decrypted("NAMESPACE").unwrap()
+        );
+
+        let config = config.with_cipher(Some(cipher));
+        assert_eq!(
+            Some("secret".to_string()),
+            config.get_decrypted("PRIVATE_KEY").unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_deserialize_decrypt_only_fields() {
+        #[derive(Debug, Deserialize)]
+        struct Namespace {
+            #[serde(rename = "NAMESPACE")]
+            namespace: String,
+        }
+        #[derive(Debug, Deserialize)]
+        struct Key {
+            #[serde(rename = "PRIVATE_KEY")]
+            private_key: String,
+        }
+
+        let cipher = Cipher::generate().unwrap();
+        let config = Config::load_from(
+            temp_dir("config_decrypt_fields"),
+            None,
+            vars(&[
+                ("NAMESPACE", "heroes"),
+                (
+                    "PRIVATE_KEY",
+                    &cipher.encrypt("PRIVATE_KEY", "secret").unwrap(),
+                ),
+            ]),
+        )
+        .unwrap();
+
+        // the encrypted value of another key is not read, so there is no cipher needed
+        let namespace: Namespace = config.deserialize().unwrap();
+        assert_eq!("heroes", namespace.namespace);
+        assert!(matches!(
+            config.deserialize::<Key>(),
+            Err(ConfigError::Secret(SecretError::MissingKey(_)))
+        ));
+
+        let key: Key = config.with_cipher(Some(cipher)).deserialize().unwrap();
+        assert_eq!("secret", key.private_key);
+    }
+
+    #[test]
+    fn test_select_profile() {
+        let args = |args: &[&str]| args.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            Some("dev".to_string()),
+            select_profile(args(&["portfolio", "--profile", "dev"]))
+        );
+        assert_eq!(
+            Some("prod".to_string()),
+            select_profile(args(&["portfolio", "--profile=prod"]))
+        );
+    }
+
+    #[test]
+    fn test_deserialize_settings() {
+        let config = Config::load_from(
+            temp_dir("config_settings"),
+            None,
+            vars(&[
+                ("PROJECT_ID", "goheros-207118"),
+                ("NAMESPACE", "heroes"),
+                ("GOOGLE_APPLICATION_CREDENTIALS", ""),
+                ("GOOGLE_SCOPES", "https://www.googleapis.com/auth/datastore"),
+                (
+                    "GOOGLE_IMPERSONATE_SERVICE_ACCOUNT",
+                    "delegate@goheros-207118.iam.gserviceaccount.com, bucket@goheros-207118.iam.gserviceaccount.com",
+                ),
+                ("DATASTORE_RETRY_MAX_ATTEMPTS", "3"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+
+        let settings: Settings = config.deserialize().unwrap();
+        assert_eq!("goheros-207118", settings.project_id);
+        assert_eq!("heroes", settings.namespace);
+        assert_eq!(None, settings.credentials);
+        assert_eq!(
+            vec!["https://www.googleapis.com/auth/datastore"],
+            settings.scopes
+        );
+        assert_eq!(None, settings.subject);
+        assert_eq!(
+            vec![
+                "delegate@goheros-207118.iam.gserviceaccount.com",
+                "bucket@goheros-207118.iam.gserviceaccount.com"
+            ],
+            settings.impersonate_service_account
+        );
+        assert_eq!(None, settings.impersonate_lifetime);
+        assert_eq!(Some(3), settings.retry_max_attempts);
+        assert_eq!(None, settings.retry_deadline_secs);
+    }
+
+    #[test]
+    fn test_deserialize_missing_key() {
+        let config = Config::load_from(
+            temp_dir("config_missing"),
+            None,
+            vars(&[("PROJECT_ID", "p")]),
+        )
+        .unwrap();
+        let err = config.deserialize::<Settings>().unwrap_err();
+        assert_eq!(ConfigError::MissingKey("NAMESPACE".to_string()), err);
+        assert_eq!(
+            "missing key 'NAMESPACE', set it in .env, .env.local, .env.{profile} or in the environment",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_deserialize_typed_values() {
+        #[derive(Debug, Deserialize)]
+        struct Server {
+            #[serde(rename = "PORT")]
+            port: u16,
+            #[serde(rename = "TLS")]
+            tls: bool,
+            #[serde(rename = "SCOPES", default)]
+            scopes: Vec<String>,
+            #[serde(rename = "TIMEOUT_SECS")]
+            timeout_secs: Option<u64>,
+        }
+
+        let config = Config::load_from(
+            temp_dir("config_typed"),
+            None,
+            vars(&[("PORT", "8080"), ("TLS", "true"), ("SCOPES", "a, b,c")]),
+        )
+        .unwrap();
+        let server: Server = config.deserialize().unwrap();
+        assert_eq!(8080, server.port);
+        assert!(server.tls);
+        assert_eq!(vec!["a", "b", "c"], server.scopes);
+        assert_eq!(None, server.timeout_secs);
+
+        let config = Config::load_from(
+            temp_dir("config_typed"),
+            None,
+            vars(&[("PORT", "http"), ("TLS", "true")]),
+        )
+        .unwrap();
+        assert_eq!(
+            "invalid value of key 'PORT': 'http': invalid digit found in string",
+            config.deserialize::<Server>().unwrap_err().to_string()
+        );
+    }
+}
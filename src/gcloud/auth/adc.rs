@@ -0,0 +1,264 @@
+use super::authorized_user::AuthorizedUser;
+use super::external_account::ExternalAccount;
+use super::metadata::MetadataServer;
+use super::service_account::{ServiceAccountKey, ENV_CREDENTIALS};
+use super::token::{CachedTokenProvider, TokenProvider};
+use super::AuthError;
+
+use log::{debug, info};
+use serde_json::Value;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ENV_CLOUDSDK_CONFIG: &str = "CLOUDSDK_CONFIG";
+const WELL_KNOWN_FILE: &str = "application_default_credentials.json";
+
+/// Application Default Credentials (ADC), the credentials are searched in this order:
+///
+/// 1. the json file from the env `GOOGLE_APPLICATION_CREDENTIALS`
+/// 2. the gcloud well-known file: `~/.config/gcloud/application_default_credentials.json`
+///    (created with: `gcloud auth application-default login`)
+/// 3. the GCE/Cloud Run metadata server
+///
+/// https://cloud.google.com/docs/authentication/production
+#[derive(Debug)]
+pub struct DefaultCredentials {
+    credentials_file: Option<PathBuf>,
+    well_known_file: Option<PathBuf>,
+    metadata: MetadataServer,
+}
+
+impl DefaultCredentials {
+    pub fn new() -> Self {
+        DefaultCredentials {
+            credentials_file: env::var(ENV_CREDENTIALS).ok().map(PathBuf::from),
+            well_known_file: well_known_file(),
+            metadata: MetadataServer::new(),
+        }
+    }
+
+    pub fn with_credentials_file<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.credentials_file = path.map(Into::into);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_well_known_file<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.well_known_file = path.map(Into::into);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_metadata_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.metadata = MetadataServer::with_host(host);
+        self
+    }
+
+    pub fn find(&self) -> Result<Box<dyn TokenProvider>, AuthError> {
+        // an invalid file in the env is an error and not skipped
+        if let Some(path) = &self.credentials_file {
+            info!(
+                "use credentials from env {}: {}",
+                ENV_CREDENTIALS,
+                path.display()
+            );
+            return from_file(path);
+        }
+
+        if let Some(path) = &self.well_known_file {
+            if path.exists() {
+                info!("use credentials from well-known file: {}", path.display());
+                return from_file(path);
+            }
+            debug!("well-known file: {} does not exist", path.display());
+        }
+
+        if self.metadata.is_available() {
+            info!("use credentials from the metadata server");
+            return Ok(Box::new(CachedTokenProvider::new(self.metadata.clone())));
+        }
+
+        Err(AuthError::MissingCredentials(
+            "could not find default credentials".to_string(),
+        ))
+    }
+}
+
+/// Create a token provider for a credentials file, the file type is read from the field `type`.
+pub fn from_file(path: &Path) -> Result<Box<dyn TokenProvider>, AuthError> {
+    let json = fs::read_to_string(path).map_err(|err| {
+        AuthError::MissingCredentials(format!("could not read file '{}': {}", path.display(), err))
+    })?;
+    from_json(&json)
+}
+
+fn from_json(json: &str) -> Result<Box<dyn TokenProvider>, AuthError> {
+    let v: Value = serde_json::from_str(json).map_err(|err| {
+        AuthError::InvalidCredentials(format!("could not parse credentials: {}", err))
+    })?;
+
+    match v.get("type").and_then(Value::as_str) {
+        Some("service_account") => Ok(Box::new(CachedTokenProvider::new(
+            ServiceAccountKey::from_json(json)?,
+        ))),
+        Some("authorized_user") => Ok(Box::new(CachedTokenProvider::new(
+            AuthorizedUser::from_json(json)?,
+        ))),
+        Some("external_account") => ExternalAccount::from_json(json)?.into_token_provider(),
+        Some(t) => Err(AuthError::InvalidCredentials(format!(
+            "unsupported credentials type: '{}'",
+            t
+        ))),
+        None => Err(AuthError::InvalidCredentials(
+            "credentials without field 'type'".to_string(),
+        )),
+    }
+}
+
+// the gcloud config dir: CLOUDSDK_CONFIG or %APPDATA%\gcloud (windows) or ~/.config/gcloud
+fn well_known_file() -> Option<PathBuf> {
+    let dir = if let Ok(dir) = env::var(ENV_CLOUDSDK_CONFIG) {
+        PathBuf::from(dir)
+    } else if cfg!(windows) {
+        PathBuf::from(env::var("APPDATA").ok()?).join("gcloud")
+    } else {
+        PathBuf::from(env::var("HOME").ok()?)
+            .join(".config")
+            .join("gcloud")
+    };
+    Some(dir.join(WELL_KNOWN_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::{self, MockResponse, MockServer};
+    use serde_json::json;
+
+    // nobody is listening on port 1
+    const NO_METADATA_HOST: &str = "127.0.0.1:1";
+
+    fn finder() -> DefaultCredentials {
+        DefaultCredentials::new()
+            .with_credentials_file::<PathBuf>(None)
+            .with_well_known_file::<PathBuf>(None)
+            .with_metadata_host(NO_METADATA_HOST)
+    }
+
+    #[test]
+    fn test_credentials_file_from_env() {
+        let path = testutil::write_temp_file("adc_sa.json", &testutil::service_account_json());
+        assert!(finder().with_credentials_file(Some(path)).find().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_credentials_file_is_not_skipped() {
+        let path = testutil::write_temp_file("adc_invalid.json", r#"{"type": "unknown"}"#);
+        let well_known =
+            testutil::write_temp_file("adc_sa_well_known.json", &testutil::service_account_json());
+
+        let err = finder()
+            .with_credentials_file(Some(path))
+            .with_well_known_file(Some(well_known))
+            .find()
+            .err()
+            .unwrap();
+        assert_eq!(
+            "invalid credentials: unsupported credentials type: 'unknown'",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_well_known_file_authorized_user() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"access_token": "user-token", "expires_in": 3599, "token_type": "Bearer"}),
+        )]);
+        let user = json!({
+            "type": "authorized_user",
+            "client_id": "my-client",
+            "client_secret": "my-secret",
+            "refresh_token": "my-refresh-token",
+            "token_uri": format!("{}/token", server.url()),
+        });
+        let path = testutil::write_temp_file("adc_user.json", &user.to_string());
+
+        let provider = finder().with_well_known_file(Some(path)).find().unwrap();
+        assert_eq!("user-token", provider.token().unwrap().token);
+
+        let req = &server.requests()[0];
+        assert_eq!("POST", req.method);
+        assert!(req.body.contains("grant_type=refresh_token"));
+        assert!(req.body.contains("refresh_token=my-refresh-token"));
+    }
+
+    #[test]
+    fn test_credentials_file_external_account() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"access_token": "sts-token", "expires_in": 3600, "token_type": "Bearer"}),
+        )]);
+        let subject_token = testutil::write_temp_file("adc_oidc_token.txt", "my-oidc-token");
+        let account = json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/ci",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/v1/token", server.url()),
+            "credential_source": { "file": subject_token },
+        });
+        let path = testutil::write_temp_file("adc_external.json", &account.to_string());
+
+        let provider = finder().with_credentials_file(Some(path)).find().unwrap();
+        assert_eq!("sts-token", provider.token().unwrap().token);
+    }
+
+    #[test]
+    fn test_metadata_server() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({})).with_header("Metadata-Flavor", "Google"),
+            MockResponse::json(
+                200,
+                json!({"access_token": "gce-token", "expires_in": 3599, "token_type": "Bearer"}),
+            ),
+        ]);
+
+        let provider = finder()
+            .with_well_known_file(Some("/not/exist/adc.json"))
+            .with_metadata_host(server.host())
+            .find()
+            .unwrap();
+        assert_eq!("gce-token", provider.token().unwrap().token);
+
+        let reqs = server.requests();
+        assert_eq!(
+            "/computeMetadata/v1/instance/service-accounts/default/token",
+            reqs[1].path
+        );
+        assert_eq!(Some("Google"), reqs[1].header("Metadata-Flavor"));
+    }
+
+    #[test]
+    fn test_metadata_server_error() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({})).with_header("Metadata-Flavor", "Google"),
+            MockResponse {
+                status: 404,
+                headers: vec![],
+                body: "no service account".to_string(),
+            },
+        ]);
+
+        let provider = finder().with_metadata_host(server.host()).find().unwrap();
+        let err = provider.token().err().unwrap();
+        assert_eq!(404, err.status_code());
+        assert_eq!("NOT_FOUND: no service account (404)", err.to_string());
+    }
+
+    #[test]
+    fn test_no_credentials() {
+        assert!(finder().find().is_err());
+    }
+}
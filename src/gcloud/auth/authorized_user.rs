@@ -0,0 +1,86 @@
+use super::error::oauth_response;
+use super::token::{AccessToken, TokenProvider};
+use super::{access_token_from_response, AuthError};
+use crate::gcloud::REDACTED;
+
+use serde::Deserialize;
+
+use std::fmt;
+
+const AUTHORIZED_USER_TYPE: &str = "authorized_user";
+const DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+
+// the credentials of a user, created with: gcloud auth application-default login
+//
+// {
+//   "type": "authorized_user",
+//   "client_id": "...apps.googleusercontent.com",
+//   "client_secret": "...",
+//   "refresh_token": "..."
+// }
+#[derive(Deserialize, Clone)]
+pub struct AuthorizedUser {
+    #[serde(rename = "type")]
+    pub credentials_type: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_string()
+}
+
+impl AuthorizedUser {
+    pub fn from_json(json: &str) -> Result<AuthorizedUser, AuthError> {
+        let user: AuthorizedUser = serde_json::from_str(json).map_err(|err| {
+            AuthError::InvalidCredentials(format!(
+                "could not parse authorized user credentials: {}",
+                err
+            ))
+        })?;
+
+        if user.credentials_type != AUTHORIZED_USER_TYPE {
+            return Err(AuthError::InvalidCredentials(format!(
+                "invalid credentials type: '{}', expected: '{}'",
+                user.credentials_type, AUTHORIZED_USER_TYPE
+            )));
+        }
+        Ok(user)
+    }
+
+    // exchange the refresh token for a new access token
+    fn refresh(&self) -> Result<AccessToken, AuthError> {
+        let resp = reqwest::blocking::Client::new()
+            .post(&self.token_uri)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("refresh_token", &self.refresh_token),
+            ])
+            .send()?;
+
+        let v = oauth_response(resp)?;
+        access_token_from_response(&v)
+    }
+}
+
+impl TokenProvider for AuthorizedUser {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        self.refresh()
+    }
+}
+
+impl fmt::Debug for AuthorizedUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorizedUser")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .field("token_uri", &self.token_uri)
+            .finish()
+    }
+}
@@ -0,0 +1,153 @@
+use crate::gcloud::Error;
+
+use http::StatusCode;
+use reqwest::blocking;
+use serde_json::Value;
+
+use std::fmt;
+
+/// The errors of the authentication, e.g. missing credentials or an oauth2 error response.
+#[derive(Debug)]
+pub enum AuthError {
+    /// No credentials are found, e.g. the env `PRIVATE_KEY` is not set or the file does not exist.
+    MissingCredentials(String),
+    /// The credentials (json key file, ...) can not be parsed or have the wrong type.
+    InvalidCredentials(String),
+    /// The private key is not a valid PEM (RSA, PKCS8).
+    MalformedKey(String),
+    /// The jwt-token could not be signed.
+    Signing(String),
+    /// A claim, a lifetime, an url, ... is invalid, this is checked before any request.
+    InvalidArgument(String),
+    /// The token (e.g. an ID token) is invalid, expired or has the wrong audience.
+    InvalidToken(String),
+    /// The request failed (connection, timeout, invalid response).
+    Transport(Error),
+    /// The error response of an oauth2 endpoint (token, sts, revoke, tokeninfo):
+    /// `{"error": "invalid_grant", "error_description": "Invalid JWT Signature."}`
+    OAuth {
+        status: u16,
+        error: String,
+        error_description: Option<String>,
+    },
+    /// The error response of a google api, e.g. the IAM Credentials API.
+    Api(Error),
+}
+
+impl AuthError {
+    /// The http status code, which fits best to the error.
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AuthError::MissingCredentials(_)
+            | AuthError::InvalidCredentials(_)
+            | AuthError::MalformedKey(_)
+            | AuthError::Signing(_)
+            | AuthError::InvalidToken(_) => StatusCode::UNAUTHORIZED.as_u16(),
+            AuthError::InvalidArgument(_) => StatusCode::BAD_REQUEST.as_u16(),
+            AuthError::Transport(err) | AuthError::Api(err) => err.code,
+            AuthError::OAuth { status, .. } => *status,
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingCredentials(msg) => write!(f, "missing credentials: {}", msg),
+            AuthError::InvalidCredentials(msg) => write!(f, "invalid credentials: {}", msg),
+            AuthError::MalformedKey(msg) => write!(f, "malformed private key: {}", msg),
+            AuthError::Signing(msg) => write!(f, "could not sign the jwt-token: {}", msg),
+            AuthError::InvalidArgument(msg) => write!(f, "{}", msg),
+            AuthError::InvalidToken(msg) => write!(f, "invalid token: {}", msg),
+            AuthError::Transport(err) => {
+                write!(f, "request failed: {} ({})", err.message, err.code)
+            }
+            AuthError::OAuth {
+                status,
+                error,
+                error_description: Some(description),
+            } => write!(f, "{}: {} ({})", error, description, status),
+            AuthError::OAuth {
+                status,
+                error,
+                error_description: None,
+            } => write!(f, "{} ({})", error, status),
+            AuthError::Api(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl From<reqwest::Error> for AuthError {
+    fn from(err: reqwest::Error) -> Self {
+        AuthError::Transport(err.into())
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Transport(err) | AuthError::Api(err) => err,
+            err => {
+                let status =
+                    StatusCode::from_u16(err.status_code()).unwrap_or(StatusCode::UNAUTHORIZED);
+                Error::new(status, err.to_string())
+            }
+        }
+    }
+}
+
+/// Read the json of an oauth2 response, an error status is an `AuthError::OAuth`.
+pub(crate) fn oauth_response(resp: blocking::Response) -> Result<Value, AuthError> {
+    let status = resp.status().as_u16();
+    if resp.status().is_success() {
+        return Ok(resp.json()?);
+    }
+
+    let v: Value = resp.json().unwrap_or(Value::Null);
+    let string = |name: &str| v.get(name).and_then(Value::as_str).map(str::to_string);
+    Err(AuthError::OAuth {
+        status,
+        error: string("error").unwrap_or_else(|| "unknown_error".to_string()),
+        error_description: string("error_description"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let err = AuthError::OAuth {
+            status: 400,
+            error: "invalid_grant".to_string(),
+            error_description: Some("Invalid JWT Signature.".to_string()),
+        };
+        assert_eq!(
+            "invalid_grant: Invalid JWT Signature. (400)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_into_gcloud_error() {
+        let err: Error =
+            AuthError::MissingCredentials("env PRIVATE_KEY is not set".to_string()).into();
+        assert_eq!(401, err.code);
+        assert_eq!(
+            "missing credentials: env PRIVATE_KEY is not set",
+            err.message
+        );
+
+        let err: Error = AuthError::OAuth {
+            status: 400,
+            error: "invalid_grant".to_string(),
+            error_description: None,
+        }
+        .into();
+        assert_eq!(400, err.code);
+        assert_eq!("invalid_grant (400)", err.message);
+    }
+}
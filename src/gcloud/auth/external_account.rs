@@ -0,0 +1,356 @@
+use super::error::oauth_response;
+use super::impersonate::Impersonated;
+use super::token::{AccessToken, CachedTokenProvider, TokenProvider};
+use super::{access_token_from_response, AuthError};
+use crate::authentication::scope;
+
+use log::debug;
+use reqwest::{blocking, Url};
+use serde::Deserialize;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::fs;
+
+const EXTERNAL_ACCOUNT_TYPE: &str = "external_account";
+const DEFAULT_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+const GRANT_TYPE_TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const TOKEN_TYPE_ACCESS_TOKEN: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+// workload identity federation, created with: gcloud iam workload-identity-pools create-cred-config
+//
+// {
+//   "type": "external_account",
+//   "audience": "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/ci",
+//   "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+//   "token_url": "https://sts.googleapis.com/v1/token",
+//   "service_account_impersonation_url": "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken",
+//   "credential_source": { "file": "/var/run/ci/token" }
+// }
+//
+// https://cloud.google.com/iam/docs/workload-identity-federation
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalAccount {
+    #[serde(rename = "type")]
+    pub credentials_type: String,
+    pub audience: String,
+    pub subject_token_type: String,
+    #[serde(default = "default_token_url")]
+    pub token_url: String,
+    pub service_account_impersonation_url: Option<String>,
+    pub credential_source: CredentialSource,
+}
+
+/// The subject token (e.g. the OIDC token of the CI) is read from a file or an url.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CredentialSource {
+    pub file: Option<String>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub format: Option<Format>,
+}
+
+/// The format of the file or the response of the url, default is text.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Format {
+    Text,
+    Json { subject_token_field_name: String },
+}
+
+fn default_token_url() -> String {
+    DEFAULT_TOKEN_URL.to_string()
+}
+
+impl ExternalAccount {
+    pub fn from_json(json: &str) -> Result<ExternalAccount, AuthError> {
+        let account: ExternalAccount = serde_json::from_str(json).map_err(|err| {
+            AuthError::InvalidCredentials(format!(
+                "could not parse external account credentials: {}",
+                err
+            ))
+        })?;
+
+        if account.credentials_type != EXTERNAL_ACCOUNT_TYPE {
+            return Err(AuthError::InvalidCredentials(format!(
+                "invalid credentials type: '{}', expected: '{}'",
+                account.credentials_type, EXTERNAL_ACCOUNT_TYPE
+            )));
+        }
+
+        let source = &account.credential_source;
+        if source.file.is_none() == source.url.is_none() {
+            return Err(AuthError::InvalidCredentials(
+                "credential_source needs exactly one of: 'file' or 'url'".to_string(),
+            ));
+        }
+        Ok(account)
+    }
+
+    /// The token provider exchanges the subject token at the STS endpoint and,
+    /// if configured, impersonates the service account. The tokens are refreshed automatically.
+    pub fn into_token_provider(self) -> Result<Box<dyn TokenProvider>, AuthError> {
+        match self.service_account_impersonation_url.clone() {
+            Some(url) => {
+                let (endpoint, target) = parse_impersonation_url(&url)?;
+                let source = Box::new(CachedTokenProvider::new(self));
+                Ok(Box::new(CachedTokenProvider::new(
+                    Impersonated::new(source, target).with_endpoint(endpoint),
+                )))
+            }
+            None => Ok(Box::new(CachedTokenProvider::new(self))),
+        }
+    }
+
+    fn subject_token(&self) -> Result<String, AuthError> {
+        let source = &self.credential_source;
+        let content = match (&source.file, &source.url) {
+            (Some(file), _) => fs::read_to_string(file).map_err(|err| {
+                AuthError::MissingCredentials(format!(
+                    "could not read subject token file '{}': {}",
+                    file, err
+                ))
+            })?,
+            (None, Some(url)) => {
+                let mut req = blocking::Client::new().get(url);
+                for (name, value) in &source.headers {
+                    req = req.header(name.as_str(), value.as_str());
+                }
+                req.send()?.error_for_status()?.text()?
+            }
+            (None, None) => {
+                return Err(AuthError::InvalidCredentials(
+                    "credential_source without 'file' or 'url'".to_string(),
+                ))
+            }
+        };
+
+        match &source.format {
+            Some(Format::Json {
+                subject_token_field_name,
+            }) => {
+                let v: Value = serde_json::from_str(&content).map_err(|err| {
+                    AuthError::InvalidToken(format!("subject token is not a json: {}", err))
+                })?;
+                match v.get(subject_token_field_name).and_then(Value::as_str) {
+                    Some(token) => Ok(token.to_string()),
+                    None => Err(AuthError::InvalidToken(format!(
+                        "subject token without field: '{}'",
+                        subject_token_field_name
+                    ))),
+                }
+            }
+            Some(Format::Text) | None => Ok(content.trim().to_string()),
+        }
+    }
+
+    // https://cloud.google.com/iam/docs/reference/sts/rest/v1/TopLevel/token
+    fn exchange(&self) -> Result<AccessToken, AuthError> {
+        debug!("exchange subject token at: {}", self.token_url);
+        let subject_token = self.subject_token()?;
+        let resp = blocking::Client::new()
+            .post(&self.token_url)
+            .form(&[
+                ("grant_type", GRANT_TYPE_TOKEN_EXCHANGE),
+                ("audience", &self.audience),
+                ("scope", scope::CLOUD_PLATFORM),
+                ("requested_token_type", TOKEN_TYPE_ACCESS_TOKEN),
+                ("subject_token", &subject_token),
+                ("subject_token_type", &self.subject_token_type),
+            ])
+            .send()?;
+
+        let v = oauth_response(resp)?;
+        access_token_from_response(&v)
+    }
+}
+
+impl TokenProvider for ExternalAccount {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        self.exchange()
+    }
+}
+
+// https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken
+// -> (https://iamcredentials.googleapis.com, sa@p.iam.gserviceaccount.com)
+fn parse_impersonation_url(url: &str) -> Result<(String, String), AuthError> {
+    let parsed = Url::parse(url).map_err(|err| {
+        AuthError::InvalidCredentials(format!("invalid impersonation url: {}", err))
+    })?;
+    let endpoint = parsed.origin().ascii_serialization();
+
+    let target = parsed
+        .path()
+        .rsplit('/')
+        .next()
+        .and_then(|last| last.strip_suffix(":generateAccessToken"))
+        .filter(|target| !target.is_empty());
+
+    match target {
+        Some(target) => Ok((endpoint, target.to_string())),
+        None => Err(AuthError::InvalidCredentials(format!(
+            "invalid impersonation url: {}",
+            url
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::{self, MockResponse, MockServer};
+    use serde_json::json;
+
+    const AUDIENCE: &str =
+        "//iam.googleapis.com/projects/123/locations/global/workloadIdentityPools/pool/providers/ci";
+
+    fn sts_response() -> MockResponse {
+        MockResponse::json(
+            200,
+            json!({
+                "access_token": "sts-token",
+                "issued_token_type": TOKEN_TYPE_ACCESS_TOKEN,
+                "token_type": "Bearer",
+                "expires_in": 3600
+            }),
+        )
+    }
+
+    #[test]
+    fn test_from_json_invalid_source() {
+        let json = json!({
+            "type": "external_account",
+            "audience": AUDIENCE,
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "credential_source": {}
+        });
+        assert!(ExternalAccount::from_json(&json.to_string()).is_err());
+    }
+
+    #[test]
+    fn test_file_source() {
+        let server = MockServer::start(vec![sts_response()]);
+        let file = testutil::write_temp_file("oidc_token.txt", "my-oidc-token\n");
+        let json = json!({
+            "type": "external_account",
+            "audience": AUDIENCE,
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/v1/token", server.url()),
+            "credential_source": { "file": file }
+        });
+
+        let provider = ExternalAccount::from_json(&json.to_string())
+            .unwrap()
+            .into_token_provider()
+            .unwrap();
+        assert_eq!("sts-token", provider.token().unwrap().token);
+
+        let req = &server.requests()[0];
+        assert_eq!("/v1/token", req.path);
+        assert!(req
+            .body
+            .contains("grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Atoken-exchange"));
+        assert!(req.body.contains("subject_token=my-oidc-token&"));
+        assert!(req
+            .body
+            .contains("subject_token_type=urn%3Aietf%3Aparams%3Aoauth%3Atoken-type%3Ajwt"));
+    }
+
+    #[test]
+    fn test_url_source_json_format() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({"id_token": "my-oidc-token"})),
+            sts_response(),
+        ]);
+        let json = json!({
+            "type": "external_account",
+            "audience": AUDIENCE,
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/v1/token", server.url()),
+            "credential_source": {
+                "url": format!("{}/oidc", server.url()),
+                "headers": {"Metadata": "True"},
+                "format": {"type": "json", "subject_token_field_name": "id_token"}
+            }
+        });
+
+        let account = ExternalAccount::from_json(&json.to_string()).unwrap();
+        assert_eq!("sts-token", account.token().unwrap().token);
+
+        let reqs = server.requests();
+        assert_eq!("/oidc", reqs[0].path);
+        assert_eq!(Some("True"), reqs[0].header("metadata"));
+        assert!(reqs[1].body.contains("subject_token=my-oidc-token&"));
+    }
+
+    #[test]
+    fn test_file_source_invalid_json() {
+        let file = testutil::write_temp_file("oidc_token.json", "my-oidc-token");
+        let json = json!({
+            "type": "external_account",
+            "audience": AUDIENCE,
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "credential_source": {
+                "file": file,
+                "format": {"type": "json", "subject_token_field_name": "id_token"}
+            }
+        });
+
+        let account = ExternalAccount::from_json(&json.to_string()).unwrap();
+        match account.token() {
+            Err(AuthError::InvalidToken(msg)) => {
+                assert!(msg.starts_with("subject token is not a json"))
+            }
+            other => panic!("expected InvalidToken, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_impersonation() {
+        let server = MockServer::start(vec![
+            sts_response(),
+            MockResponse::json(
+                200,
+                json!({"accessToken": "sa-token", "expireTime": "2030-01-01T10:00:00Z"}),
+            ),
+        ]);
+        let file = testutil::write_temp_file("oidc_token_impersonation.txt", "my-oidc-token");
+        let json = json!({
+            "type": "external_account",
+            "audience": AUDIENCE,
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "token_url": format!("{}/v1/token", server.url()),
+            "service_account_impersonation_url": format!(
+                "{}/v1/projects/-/serviceAccounts/sa@goheros-207118.iam.gserviceaccount.com:generateAccessToken",
+                server.url()
+            ),
+            "credential_source": { "file": file }
+        });
+
+        let provider = ExternalAccount::from_json(&json.to_string())
+            .unwrap()
+            .into_token_provider()
+            .unwrap();
+        assert_eq!("sa-token", provider.token().unwrap().token);
+
+        let reqs = server.requests();
+        assert_eq!(
+            "/v1/projects/-/serviceAccounts/sa@goheros-207118.iam.gserviceaccount.com:generateAccessToken",
+            reqs[1].path
+        );
+        assert_eq!(Some("Bearer sts-token"), reqs[1].header("authorization"));
+    }
+
+    #[test]
+    fn test_parse_impersonation_url() {
+        assert_eq!(
+            (
+                "https://iamcredentials.googleapis.com".to_string(),
+                "sa@p.iam.gserviceaccount.com".to_string()
+            ),
+            parse_impersonation_url("https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/sa@p.iam.gserviceaccount.com:generateAccessToken").unwrap()
+        );
+        assert!(parse_impersonation_url("https://iamcredentials.googleapis.com/v1/foo").is_err());
+    }
+}
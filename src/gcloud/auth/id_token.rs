@@ -0,0 +1,374 @@
+use super::service_account::ServiceAccountKey;
+use super::{create_jwt_token, jwt_bearer_grant, AuthError};
+
+use chrono::{DateTime, Duration, Utc};
+use http::header::CACHE_CONTROL;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::debug;
+use reqwest::blocking;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Mutex, MutexGuard};
+
+// the certs url can be replaced, e.g. by a local stub: GOOGLE_CERTS_URL=http://localhost:8080/certs
+pub const ENV_CERTS_URL: &str = "GOOGLE_CERTS_URL";
+const CERTS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const ISSUERS: &[&str] = &["accounts.google.com", "https://accounts.google.com"];
+// the certs are rotated, without a max-age in the response they are cached for 1 hour
+const DEFAULT_CACHE_SECS: i64 = 3600;
+// a bigger max-age is cut, so the rotated keys are fetched at least once a day
+const MAX_CACHE_SECS: i64 = 86400;
+// the clocks of google and of the service differ
+const LEEWAY_SECS: u64 = 30;
+// an unknown kid refreshes the certs at most once a minute, so tokens with an invented kid
+// can not trigger a request per token
+const MIN_REFRESH_SECS: i64 = 60;
+const ID_TOKEN_LIFETIME_SECS: i64 = 3600;
+
+/// The claims of a Google-signed ID token, e.g. of a Cloud Scheduler or Pub/Sub push request.
+///
+/// https://developers.google.com/identity/protocols/oauth2/openid-connect#an-id-tokens-payload
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub sub: String,
+    pub azp: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+struct CachedKeys {
+    // kid -> (modulus, exponent)
+    keys: HashMap<String, (String, String)>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct KeyCache {
+    keys: Option<CachedKeys>,
+    // the last refresh because of an unknown kid
+    refreshed_at: Option<DateTime<Utc>>,
+}
+
+/// IdTokenVerifier checks the signature (with the public keys of google), the issuer,
+/// the audience and the expiry of an ID token.
+///
+/// The public keys (JWKS) are cached until the max-age of the response.
+pub struct IdTokenVerifier {
+    audience: String,
+    certs_url: String,
+    client: blocking::Client,
+    cache: Mutex<KeyCache>,
+}
+
+impl IdTokenVerifier {
+    /// The audience is the url of the service, which is configured in the push subscription or the scheduler job.
+    ///
+    /// The certs url from the env `GOOGLE_CERTS_URL` or the default: `https://www.googleapis.com/oauth2/v3/certs`.
+    pub fn new<S: Into<String>>(audience: S) -> Self {
+        IdTokenVerifier {
+            audience: audience.into(),
+            certs_url: env::var(ENV_CERTS_URL).unwrap_or_else(|_| CERTS_URL.to_string()),
+            client: blocking::Client::new(),
+            cache: Mutex::new(KeyCache::default()),
+        }
+    }
+
+    pub fn with_certs_url<S: Into<String>>(mut self, certs_url: S) -> Self {
+        self.certs_url = certs_url.into();
+        self
+    }
+
+    pub fn verify(&self, id_token: &str) -> Result<IdTokenClaims, AuthError> {
+        let header = jsonwebtoken::decode_header(id_token)
+            .map_err(|err| AuthError::InvalidToken(err.to_string()))?;
+        if header.alg != Algorithm::RS256 {
+            return Err(AuthError::InvalidToken(format!(
+                "invalid algorithm: {:?}, expected: RS256",
+                header.alg
+            )));
+        }
+        let kid = header
+            .kid
+            .ok_or_else(|| AuthError::InvalidToken("id token without kid".to_string()))?;
+        let (n, e) = self.key(&kid)?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = LEEWAY_SECS;
+        validation.set_audience(&[&self.audience]);
+
+        let claims = jsonwebtoken::decode::<IdTokenClaims>(
+            id_token,
+            &DecodingKey::from_rsa_components(&n, &e),
+            &validation,
+        )
+        .map_err(|err| AuthError::InvalidToken(err.to_string()))?
+        .claims;
+
+        // google uses two issuers, the validation of jsonwebtoken accepts only one
+        if !ISSUERS.contains(&claims.iss.as_str()) {
+            return Err(AuthError::InvalidToken(format!(
+                "invalid issuer: '{}'",
+                claims.iss
+            )));
+        }
+        Ok(claims)
+    }
+
+    // the key for the kid, an unknown kid refreshes the cache (the keys are rotated),
+    // the certs are fetched without the lock, so other verifications are not blocked
+    fn key(&self, kid: &str) -> Result<(String, String), AuthError> {
+        {
+            let mut cache = self.lock_cache();
+            let now = Utc::now();
+            if let Some(cached) = cache.keys.as_ref().filter(|c| c.expires_at > now) {
+                if let Some(key) = cached.keys.get(kid) {
+                    return Ok(key.clone());
+                }
+                let refreshed_recently = cache
+                    .refreshed_at
+                    .is_some_and(|at| at + Duration::seconds(MIN_REFRESH_SECS) > now);
+                if refreshed_recently {
+                    return Err(unknown_kid(kid));
+                }
+                cache.refreshed_at = Some(now);
+            }
+        }
+
+        let fetched = self.fetch_keys()?;
+        let key = fetched.keys.get(kid).cloned();
+        self.lock_cache().keys = Some(fetched);
+        key.ok_or_else(|| unknown_kid(kid))
+    }
+
+    fn lock_cache(&self) -> MutexGuard<'_, KeyCache> {
+        self.cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn fetch_keys(&self) -> Result<CachedKeys, AuthError> {
+        debug!("fetch certs from: {}", self.certs_url);
+        let resp = self
+            .client
+            .get(&self.certs_url)
+            .send()?
+            .error_for_status()?;
+        let max_age = resp
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .and_then(max_age)
+            .unwrap_or(DEFAULT_CACHE_SECS);
+
+        let jwks: Jwks = resp.json()?;
+        Ok(CachedKeys {
+            keys: jwks.keys.into_iter().map(|k| (k.kid, (k.n, k.e))).collect(),
+            expires_at: Utc::now() + Duration::seconds(max_age),
+        })
+    }
+}
+
+fn unknown_kid(kid: &str) -> AuthError {
+    AuthError::InvalidToken(format!("unknown kid: '{}'", kid))
+}
+
+// Cache-Control: public, max-age=19845, must-revalidate, no-transform
+fn max_age(cache_control: &str) -> Option<i64> {
+    cache_control
+        .split(',')
+        .filter_map(|directive| directive.trim().strip_prefix("max-age="))
+        .next()
+        .and_then(|secs| secs.parse::<i64>().ok())
+        .map(|secs| secs.clamp(0, MAX_CACHE_SECS))
+}
+
+// https://developers.google.com/identity/protocols/oauth2/service-account#authorizingrequests
+// the claim with target_audience returns an id_token instead of an access_token
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct IdTokenRequestClaim {
+    iss: String,
+    sub: String,
+    aud: String,
+    target_audience: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// Mint a Google-signed ID token for the target audience (e.g. the url of a Cloud Run service)
+/// with the service account key.
+pub fn mint_id_token(key: &ServiceAccountKey, target_audience: &str) -> Result<String, AuthError> {
+    let now = Utc::now().timestamp();
+    let claim = IdTokenRequestClaim {
+        iss: key.client_email.clone(),
+        sub: key.client_email.clone(),
+        aud: key.token_uri.clone(),
+        target_audience: target_audience.to_string(),
+        iat: now,
+        exp: now + ID_TOKEN_LIFETIME_SECS,
+    };
+    let jwt_token = create_jwt_token(claim, &key.private_key, Some(&key.private_key_id))?;
+
+    let v = jwt_bearer_grant(&key.token_uri, jwt_token)?;
+    match v.get("id_token").and_then(Value::as_str) {
+        Some(id_token) => Ok(id_token.to_string()),
+        None => Err(AuthError::InvalidToken(
+            "no id_token in the token response".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::{self, MockResponse, MockServer};
+    use serde_json::json;
+
+    const AUDIENCE: &str = "https://portfolio-abc123-ew.a.run.app";
+
+    fn claims(iss: &str, aud: &str, exp_in_secs: i64) -> IdTokenClaims {
+        let now = Utc::now().timestamp();
+        IdTokenClaims {
+            iss: iss.to_string(),
+            aud: aud.to_string(),
+            sub: "1234567890".to_string(),
+            azp: None,
+            email: Some("scheduler@goheros-207118.iam.gserviceaccount.com".to_string()),
+            email_verified: Some(true),
+            iat: now - 60,
+            exp: now + exp_in_secs,
+        }
+    }
+
+    fn sign(claims: &IdTokenClaims, kid: &str) -> String {
+        create_jwt_token(claims, testutil::PRIVATE_KEY, Some(kid)).unwrap()
+    }
+
+    fn verifier(server: &MockServer) -> IdTokenVerifier {
+        IdTokenVerifier::new(AUDIENCE).with_certs_url(format!("{}/oauth2/v3/certs", server.url()))
+    }
+
+    #[test]
+    fn test_verify() {
+        let server = MockServer::start(vec![MockResponse::json(200, testutil::jwks_json())
+            .with_header("Cache-Control", "public, max-age=19845, must-revalidate")]);
+        let v = verifier(&server);
+
+        let expected = claims("https://accounts.google.com", AUDIENCE, 3600);
+        assert_eq!(
+            expected,
+            v.verify(&sign(&expected, testutil::KEY_ID)).unwrap()
+        );
+
+        let expected = claims("accounts.google.com", AUDIENCE, 3600);
+        assert_eq!(
+            expected,
+            v.verify(&sign(&expected, testutil::KEY_ID)).unwrap()
+        );
+
+        // the certs are cached
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn test_verify_huge_max_age() {
+        let server = MockServer::start(vec![MockResponse::json(200, testutil::jwks_json())
+            .with_header("Cache-Control", "public, max-age=9223372036854775807")]);
+
+        let c = claims("https://accounts.google.com", AUDIENCE, 3600);
+        assert!(verifier(&server)
+            .verify(&sign(&c, testutil::KEY_ID))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_verify_invalid_claims() {
+        let server = MockServer::start(vec![MockResponse::json(200, testutil::jwks_json())]);
+        let v = verifier(&server);
+
+        let wrong_aud = claims("https://accounts.google.com", "https://other.app", 3600);
+        assert!(v.verify(&sign(&wrong_aud, testutil::KEY_ID)).is_err());
+
+        let wrong_iss = claims("https://evil.example.com", AUDIENCE, 3600);
+        assert_eq!(
+            "invalid token: invalid issuer: 'https://evil.example.com'",
+            v.verify(&sign(&wrong_iss, testutil::KEY_ID))
+                .unwrap_err()
+                .to_string()
+        );
+
+        let expired = claims("https://accounts.google.com", AUDIENCE, -3600);
+        assert!(v.verify(&sign(&expired, testutil::KEY_ID)).is_err());
+    }
+
+    #[test]
+    fn test_verify_unknown_kid_refreshes_certs() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, testutil::jwks_json()),
+            MockResponse::json(200, testutil::jwks_json()),
+        ]);
+        let v = verifier(&server);
+
+        let c = claims("https://accounts.google.com", AUDIENCE, 3600);
+        assert!(v.verify(&sign(&c, testutil::KEY_ID)).is_ok());
+        assert_eq!(
+            "invalid token: unknown kid: 'rotated-key'",
+            v.verify(&sign(&c, "rotated-key")).unwrap_err().to_string()
+        );
+        assert_eq!(2, server.requests().len());
+
+        // the next unknown kid within a minute does not fetch the certs
+        assert!(v.verify(&sign(&c, "invented-key")).is_err());
+        assert!(v.verify(&sign(&c, testutil::KEY_ID)).is_ok());
+        assert_eq!(2, server.requests().len());
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(
+            Some(19845),
+            max_age("public, max-age=19845, must-revalidate, no-transform")
+        );
+        assert_eq!(None, max_age("no-cache"));
+        assert_eq!(Some(MAX_CACHE_SECS), max_age("max-age=9223372036854775807"));
+        assert_eq!(Some(0), max_age("max-age=-1"));
+    }
+
+    #[test]
+    fn test_mint_id_token() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"id_token": "my-id-token"}),
+        )]);
+        let mut key = ServiceAccountKey::from_json(&testutil::service_account_json()).unwrap();
+        key.token_uri = format!("{}/token", server.url());
+
+        assert_eq!("my-id-token", mint_id_token(&key, AUDIENCE).unwrap());
+
+        let body = &server.requests()[0].body;
+        let assertion = body.split("assertion=").nth(1).unwrap();
+        let claim = jsonwebtoken::dangerous_insecure_decode::<IdTokenRequestClaim>(assertion)
+            .unwrap()
+            .claims;
+        assert_eq!(AUDIENCE, claim.target_audience);
+        assert_eq!(key.token_uri, claim.aud);
+    }
+}
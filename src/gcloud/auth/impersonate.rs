@@ -0,0 +1,300 @@
+use super::token::{AccessToken, TokenProvider};
+use super::{Auth, AuthError};
+use crate::authentication::scope;
+use crate::gcloud::Error;
+
+use chrono::{DateTime, Utc};
+use http::StatusCode;
+use log::debug;
+use reqwest::blocking;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// the endpoint can be replaced, e.g. by a local stand-in
+const IAM_CREDENTIALS_ENDPOINT: &str = "https://iamcredentials.googleapis.com";
+const DEFAULT_LIFETIME_SECS: u32 = 3600;
+// 12 hours, greater than 1 hour needs the org policy: constraints/iam.allowServiceAccountCredentialLifetimeExtension
+const MAX_LIFETIME_SECS: u32 = 43200;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedJwt {
+    pub key_id: String,
+    pub signed_jwt: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignedBlob {
+    pub key_id: String,
+    pub signed_blob: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenerateAccessTokenResponse {
+    access_token: String,
+    expire_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SignBlobResponse {
+    key_id: String,
+    signed_blob: String,
+}
+
+/// Impersonated acts as the target service account, the requests to the IAM Credentials API
+/// are authorized with the source credentials (e.g. the credentials of a developer).
+/// The source needs the role: `roles/iam.serviceAccountTokenCreator` for the target
+/// (or for the first service account in the delegation chain).
+///
+/// https://cloud.google.com/iam/docs/reference/credentials/rest
+pub struct Impersonated {
+    source: Box<dyn Auth>,
+    target_principal: String,
+    delegates: Vec<String>,
+    scopes: Vec<String>,
+    lifetime_secs: u32,
+    endpoint: String,
+    client: blocking::Client,
+}
+
+impl Impersonated {
+    /// The target principal is the email of the service account, e.g.: `bucket@goheros-207118.iam.gserviceaccount.com`.
+    pub fn new<S: Into<String>>(source: Box<dyn Auth>, target_principal: S) -> Self {
+        Impersonated {
+            source,
+            target_principal: target_principal.into(),
+            delegates: vec![],
+            scopes: vec![scope::CLOUD_PLATFORM.to_string()],
+            lifetime_secs: DEFAULT_LIFETIME_SECS,
+            endpoint: IAM_CREDENTIALS_ENDPOINT.to_string(),
+            client: blocking::Client::new(),
+        }
+    }
+
+    /// The delegation chain (emails of service accounts), every service account
+    /// must have the role `roles/iam.serviceAccountTokenCreator` for the next one.
+    pub fn with_delegates(mut self, delegates: Vec<String>) -> Self {
+        self.delegates = delegates;
+        self
+    }
+
+    pub fn with_scopes(mut self, scopes: Vec<String>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime_secs: u32) -> Result<Self, AuthError> {
+        if lifetime_secs == 0 || lifetime_secs > MAX_LIFETIME_SECS {
+            return Err(AuthError::InvalidArgument(format!(
+                "invalid lifetime: {}s, expected: 1s - {}s",
+                lifetime_secs, MAX_LIFETIME_SECS
+            )));
+        }
+        self.lifetime_secs = lifetime_secs;
+        Ok(self)
+    }
+
+    pub fn with_endpoint<S: Into<String>>(mut self, endpoint: S) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    pub fn generate_access_token(&self) -> Result<AccessToken, AuthError> {
+        debug!("generate access token for: {}", self.target_principal);
+        let body = json!({
+            "delegates": self.delegate_names(),
+            "scope": self.scopes,
+            "lifetime": format!("{}s", self.lifetime_secs),
+        });
+        let resp: GenerateAccessTokenResponse = self.call("generateAccessToken", &body)?;
+        Ok(AccessToken {
+            token: resp.access_token,
+            expires_at: Some(resp.expire_time),
+        })
+    }
+
+    /// Sign the claim (payload) with a system-managed private key of the target service account.
+    pub fn sign_jwt<T: Serialize>(&self, claim: &T) -> Result<SignedJwt, AuthError> {
+        let body = json!({
+            "delegates": self.delegate_names(),
+            "payload": serde_json::to_string(claim).map_err(|err| {
+                AuthError::Signing(format!("could not serialize the claim: {}", err))
+            })?,
+        });
+        self.call("signJwt", &body)
+    }
+
+    /// Sign the bytes with a system-managed private key of the target service account.
+    pub fn sign_blob(&self, blob: &[u8]) -> Result<SignedBlob, AuthError> {
+        let body = json!({
+            "delegates": self.delegate_names(),
+            "payload": base64::encode(blob),
+        });
+        let resp: SignBlobResponse = self.call("signBlob", &body)?;
+        let signed_blob = base64::decode(&resp.signed_blob).map_err(|err| {
+            AuthError::Transport(Error::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("invalid base64 signed blob: {}", err),
+            ))
+        })?;
+        Ok(SignedBlob {
+            key_id: resp.key_id,
+            signed_blob,
+        })
+    }
+
+    fn delegate_names(&self) -> Vec<String> {
+        self.delegates.iter().map(|d| resource_name(d)).collect()
+    }
+
+    fn call<D: DeserializeOwned>(
+        &self,
+        method: &str,
+        body: &serde_json::Value,
+    ) -> Result<D, AuthError> {
+        let url = format!(
+            "{}/v1/{}:{}",
+            self.endpoint,
+            resource_name(&self.target_principal),
+            method
+        );
+        // the error of the source credentials
+        let (name, value) = self.source.header(&url).map_err(AuthError::Transport)?;
+        let resp = self
+            .client
+            .post(&url)
+            .header(name, value)
+            .json(body)
+            .send()?;
+
+        if resp.status().as_u16() == StatusCode::OK.as_u16() {
+            Ok(resp.json::<D>()?)
+        } else {
+            Err(AuthError::Api(Error::from_response(resp)))
+        }
+    }
+}
+
+impl TokenProvider for Impersonated {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        self.generate_access_token()
+    }
+}
+
+// the wildcard '-' for the project is required, the project is inferred from the email
+fn resource_name(email: &str) -> String {
+    format!("projects/-/serviceAccounts/{}", email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::auth::JwtToken;
+    use crate::gcloud::testutil::{MockResponse, MockServer};
+    use serde_json::Value;
+
+    const TARGET: &str = "bucket@goheros-207118.iam.gserviceaccount.com";
+
+    fn impersonated(server: &MockServer) -> Impersonated {
+        Impersonated::new(Box::new(JwtToken::new("base-token")), TARGET).with_endpoint(server.url())
+    }
+
+    #[test]
+    fn test_generate_access_token() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"accessToken": "impersonated-token", "expireTime": "2030-01-01T10:00:00Z"}),
+        )]);
+
+        let token = impersonated(&server)
+            .with_delegates(vec![
+                "delegate@goheros-207118.iam.gserviceaccount.com".to_string()
+            ])
+            .with_lifetime(600)
+            .unwrap()
+            .token()
+            .unwrap();
+        assert_eq!("impersonated-token", token.token);
+        assert_eq!(
+            "2030-01-01T10:00:00Z".parse::<DateTime<Utc>>().ok(),
+            token.expires_at
+        );
+
+        let req = &server.requests()[0];
+        assert_eq!(
+            format!(
+                "/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+                TARGET
+            ),
+            req.path
+        );
+        assert_eq!(Some("Bearer base-token"), req.header("authorization"));
+        let body: Value = serde_json::from_str(&req.body).unwrap();
+        assert_eq!(
+            json!({
+                "delegates": ["projects/-/serviceAccounts/delegate@goheros-207118.iam.gserviceaccount.com"],
+                "scope": [scope::CLOUD_PLATFORM],
+                "lifetime": "600s",
+            }),
+            body
+        );
+    }
+
+    #[test]
+    fn test_sign_jwt() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"keyId": "key-1", "signedJwt": "a.b.c"}),
+        )]);
+
+        let signed = impersonated(&server)
+            .sign_jwt(&json!({"iss": TARGET}))
+            .unwrap();
+        assert_eq!("key-1", signed.key_id);
+        assert_eq!("a.b.c", signed.signed_jwt);
+
+        let body: Value = serde_json::from_str(&server.requests()[0].body).unwrap();
+        assert_eq!(json!(format!(r#"{{"iss":"{}"}}"#, TARGET)), body["payload"]);
+    }
+
+    #[test]
+    fn test_sign_blob() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({"keyId": "key-1", "signedBlob": base64::encode(b"signature")}),
+        )]);
+
+        let signed = impersonated(&server).sign_blob(b"my blob").unwrap();
+        assert_eq!(b"signature".to_vec(), signed.signed_blob);
+
+        let req = &server.requests()[0];
+        assert!(req.path.ends_with(":signBlob"));
+        let body: Value = serde_json::from_str(&req.body).unwrap();
+        assert_eq!(json!(base64::encode(b"my blob")), body["payload"]);
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let server = MockServer::start(vec![MockResponse::json(
+            403,
+            json!({"error": {"code": 403, "message": "Permission 'iam.serviceAccounts.getAccessToken' denied", "status": "PERMISSION_DENIED"}}),
+        )]);
+
+        match impersonated(&server).generate_access_token() {
+            Err(AuthError::Api(err)) => {
+                assert_eq!(403, err.code);
+                assert_eq!("PERMISSION_DENIED", err.status);
+            }
+            other => panic!("expected Api error, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_lifetime() {
+        let i = Impersonated::new(Box::new(JwtToken::new("base-token")), TARGET);
+        assert!(i.with_lifetime(MAX_LIFETIME_SECS + 1).is_err());
+    }
+}
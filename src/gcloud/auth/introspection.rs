@@ -0,0 +1,263 @@
+use super::error::oauth_response;
+use super::AuthError;
+use crate::authentication::scope;
+
+use chrono::{DateTime, TimeZone, Utc};
+use log::debug;
+use reqwest::blocking;
+use serde_json::Value;
+
+const REVOKE_URL: &str = "https://oauth2.googleapis.com/revoke";
+const TOKENINFO_URL: &str = "https://oauth2.googleapis.com/tokeninfo";
+
+/// The information about an access token from the oauth2 tokeninfo endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    /// The client id, which requested the token.
+    pub audience: Option<String>,
+    pub authorized_party: Option<String>,
+    pub subject: Option<String>,
+    pub email: Option<String>,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenInfo {
+    // the tokeninfo response, the numbers are strings:
+    // {"azp": "...", "aud": "...", "sub": "...", "scope": "https://... https://...",
+    //  "exp": "1588000000", "expires_in": "3599", "email": "...", "email_verified": "true"}
+    fn from_response(v: &Value) -> TokenInfo {
+        let string = |name: &str| v.get(name).and_then(Value::as_str).map(str::to_string);
+        let exp = v.get("exp").and_then(|exp| match exp {
+            Value::String(s) => s.parse::<i64>().ok(),
+            other => other.as_i64(),
+        });
+
+        TokenInfo {
+            audience: string("aud"),
+            authorized_party: string("azp"),
+            subject: string("sub"),
+            email: string("email"),
+            scopes: string("scope")
+                .map(|s| s.split_whitespace().map(str::to_string).collect())
+                .unwrap_or_default(),
+            // an exp out of the range of a date is no expiry
+            expires_at: exp.and_then(|exp| Utc.timestamp_opt(exp, 0).single()),
+        }
+    }
+
+    /// Does the token carry the scope, e.g. the scope `cloud-platform` includes `datastore`.
+    pub fn has_scope(&self, required: &str) -> bool {
+        self.scopes.iter().any(|granted| covers(granted, required))
+    }
+
+    /// Does the token carry all the scopes.
+    pub fn has_scopes(&self, required: &[&str]) -> bool {
+        self.missing_scopes(required).is_empty()
+    }
+
+    pub fn missing_scopes<'a>(&self, required: &[&'a str]) -> Vec<&'a str> {
+        required
+            .iter()
+            .filter(|scope| !self.has_scope(scope))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at <= Utc::now(),
+            None => false,
+        }
+    }
+}
+
+// a broader scope includes the narrower scopes
+fn covers(granted: &str, required: &str) -> bool {
+    if granted == required || granted == scope::CLOUD_PLATFORM {
+        return true;
+    }
+    match granted {
+        scope::CLOUD_PLATFORM_READ_ONLY => required == scope::DEVSTORAGE_READ_ONLY,
+        scope::DEVSTORAGE_FULL_CONTROL => {
+            required == scope::DEVSTORAGE_READ_WRITE || required == scope::DEVSTORAGE_READ_ONLY
+        }
+        scope::DEVSTORAGE_READ_WRITE => required == scope::DEVSTORAGE_READ_ONLY,
+        _ => false,
+    }
+}
+
+/// Introspection calls the oauth2 endpoints to revoke a token and to read the tokeninfo.
+///
+/// https://developers.google.com/identity/protocols/oauth2/web-server#tokenrevoke
+#[derive(Debug)]
+pub struct Introspection {
+    revoke_url: String,
+    tokeninfo_url: String,
+    client: blocking::Client,
+}
+
+impl Introspection {
+    pub fn new() -> Self {
+        Introspection {
+            revoke_url: REVOKE_URL.to_string(),
+            tokeninfo_url: TOKENINFO_URL.to_string(),
+            client: blocking::Client::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_revoke_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.revoke_url = url.into();
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_tokeninfo_url<S: Into<String>>(mut self, url: S) -> Self {
+        self.tokeninfo_url = url.into();
+        self
+    }
+
+    /// Revoke the access token (or the refresh token), afterwards it is invalid.
+    pub fn revoke(&self, token: &str) -> Result<(), AuthError> {
+        debug!("revoke token at: {}", self.revoke_url);
+        // the token is sent in the body and not in the url
+        let resp = self
+            .client
+            .post(&self.revoke_url)
+            .form(&[("token", token)])
+            .send()?;
+
+        // the body of a successful revoke is empty
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            oauth_response(resp).map(|_| ())
+        }
+    }
+
+    pub fn token_info(&self, access_token: &str) -> Result<TokenInfo, AuthError> {
+        let resp = self
+            .client
+            .post(&self.tokeninfo_url)
+            .form(&[("access_token", access_token)])
+            .send()?;
+
+        let v = oauth_response(resp)?;
+        Ok(TokenInfo::from_response(&v))
+    }
+}
+
+/// Revoke the token at the google oauth2 endpoint.
+pub fn revoke(token: &str) -> Result<(), AuthError> {
+    Introspection::new().revoke(token)
+}
+
+/// Read the information (scopes, expiry, audience) of the access token.
+pub fn token_info(access_token: &str) -> Result<TokenInfo, AuthError> {
+    Introspection::new().token_info(access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::{MockResponse, MockServer};
+    use serde_json::json;
+
+    fn introspection(server: &MockServer) -> Introspection {
+        Introspection::new()
+            .with_revoke_url(format!("{}/revoke", server.url()))
+            .with_tokeninfo_url(format!("{}/tokeninfo", server.url()))
+    }
+
+    #[test]
+    fn test_token_info() {
+        let server = MockServer::start(vec![MockResponse::json(
+            200,
+            json!({
+                "azp": "123.apps.googleusercontent.com",
+                "aud": "123.apps.googleusercontent.com",
+                "sub": "1234567890",
+                "scope": format!("{} {}", scope::DATASTORE, scope::DEVSTORAGE_READ_WRITE),
+                "exp": "1893492000",
+                "expires_in": "3599",
+                "email": "bucket@goheros-207118.iam.gserviceaccount.com",
+                "email_verified": "true"
+            }),
+        )]);
+
+        let info = introspection(&server).token_info("my-token").unwrap();
+        assert_eq!(
+            Some("123.apps.googleusercontent.com".to_string()),
+            info.audience
+        );
+        assert_eq!(Utc.timestamp_opt(1893492000, 0).single(), info.expires_at);
+        assert!(!info.is_expired());
+        assert!(info.has_scopes(&[scope::DATASTORE, scope::DEVSTORAGE_READ_ONLY]));
+        assert_eq!(
+            vec![scope::PUBSUB],
+            info.missing_scopes(&[scope::DATASTORE, scope::PUBSUB])
+        );
+
+        let req = &server.requests()[0];
+        assert_eq!("/tokeninfo", req.path);
+        assert_eq!("access_token=my-token", req.body);
+    }
+
+    #[test]
+    fn test_token_info_exp_out_of_range() {
+        let info = TokenInfo::from_response(&json!({"exp": "99999999999999"}));
+        assert_eq!(None, info.expires_at);
+        let info = TokenInfo::from_response(&json!({"exp": i64::MIN}));
+        assert_eq!(None, info.expires_at);
+    }
+
+    #[test]
+    fn test_token_info_invalid_token() {
+        let server = MockServer::start(vec![MockResponse::json(
+            400,
+            json!({"error": "invalid_token", "error_description": "Invalid Value"}),
+        )]);
+
+        let err = introspection(&server).token_info("my-token").unwrap_err();
+        assert_eq!(400, err.status_code());
+        assert_eq!("invalid_token: Invalid Value (400)", err.to_string());
+    }
+
+    #[test]
+    fn test_revoke() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({})),
+            MockResponse::json(
+                400,
+                json!({"error": "invalid_token", "error_description": "Token expired or revoked"}),
+            ),
+        ]);
+
+        let i = introspection(&server);
+        assert!(i.revoke("my-token").is_ok());
+        let err = i.revoke("my-token").unwrap_err();
+        assert_eq!(
+            "invalid_token: Token expired or revoked (400)",
+            err.to_string()
+        );
+
+        let req = &server.requests()[0];
+        assert_eq!("POST", req.method);
+        assert_eq!("/revoke", req.path);
+        assert_eq!("token=my-token", req.body);
+    }
+
+    #[test]
+    fn test_cloud_platform_covers_all() {
+        let info = TokenInfo {
+            audience: None,
+            authorized_party: None,
+            subject: None,
+            email: None,
+            scopes: vec![scope::CLOUD_PLATFORM.to_string()],
+            expires_at: None,
+        };
+        assert!(info.has_scopes(&[scope::DATASTORE, scope::DEVSTORAGE_FULL_CONTROL]));
+    }
+}
@@ -0,0 +1,89 @@
+use super::access_token_from_response;
+use super::token::{AccessToken, TokenProvider};
+use super::AuthError;
+use crate::gcloud::Error;
+
+use http::header::HeaderValue;
+use log::debug;
+use serde_json::Value;
+
+use std::env;
+use std::time::Duration;
+
+// the host can be replaced, e.g. by a local stub: GCE_METADATA_HOST=localhost:8080
+pub const ENV_METADATA_HOST: &str = "GCE_METADATA_HOST";
+const METADATA_HOST: &str = "metadata.google.internal";
+const METADATA_FLAVOR: &str = "Metadata-Flavor";
+const METADATA_FLAVOR_VALUE: &str = "Google";
+// the metadata server is only available on GCE, Cloud Run, ..., so do not wait long
+const PING_TIMEOUT_MILLIS: u64 = 500;
+
+/// The metadata server of GCE/Cloud Run returns the access token
+/// for the service account, which is attached to the instance.
+#[derive(Debug, Clone)]
+pub struct MetadataServer {
+    host: String,
+}
+
+impl MetadataServer {
+    /// The host from the env `GCE_METADATA_HOST` or the default: `metadata.google.internal`.
+    pub fn new() -> Self {
+        match env::var(ENV_METADATA_HOST) {
+            Ok(host) => MetadataServer::with_host(host),
+            Err(_) => MetadataServer::with_host(METADATA_HOST),
+        }
+    }
+
+    pub fn with_host<S: Into<String>>(host: S) -> Self {
+        MetadataServer { host: host.into() }
+    }
+
+    /// Is the metadata server available (the response has the header: `Metadata-Flavor: Google`).
+    pub fn is_available(&self) -> bool {
+        let client = match reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(PING_TIMEOUT_MILLIS))
+            .build()
+        {
+            Ok(client) => client,
+            Err(_) => return false,
+        };
+
+        match client
+            .get(&format!("http://{}/", self.host))
+            .header(METADATA_FLAVOR, METADATA_FLAVOR_VALUE)
+            .send()
+        {
+            Ok(resp) => {
+                resp.headers().get(METADATA_FLAVOR)
+                    == Some(&HeaderValue::from_static(METADATA_FLAVOR_VALUE))
+            }
+            Err(err) => {
+                debug!("metadata server {} is not available: {}", self.host, err);
+                false
+            }
+        }
+    }
+
+    fn fetch_token(&self) -> Result<AccessToken, AuthError> {
+        let url = format!(
+            "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+            self.host
+        );
+        let resp = reqwest::blocking::Client::new()
+            .get(&url)
+            .header(METADATA_FLAVOR, METADATA_FLAVOR_VALUE)
+            .send()?;
+
+        if !resp.status().is_success() {
+            return Err(AuthError::Api(Error::from_response(resp)));
+        }
+        let v: Value = resp.json()?;
+        access_token_from_response(&v)
+    }
+}
+
+impl TokenProvider for MetadataServer {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        self.fetch_token()
+    }
+}
@@ -0,0 +1,341 @@
+pub mod adc;
+pub mod authorized_user;
+pub mod error;
+pub mod external_account;
+pub mod id_token;
+pub mod impersonate;
+pub mod introspection;
+pub mod metadata;
+pub mod self_signed;
+pub mod service_account;
+pub mod token;
+
+pub use error::AuthError;
+
+use crate::authentication::ClaimBuilder;
+use crate::gcloud::{Error, REDACTED};
+use error::oauth_response;
+use token::{AccessToken, TokenProvider};
+
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use http::StatusCode;
+use serde::Serialize;
+use serde_json::Value;
+
+use std::fmt;
+
+const URL_TOKEN: &str = "https://oauth2.googleapis.com/token";
+
+const HEADER_API_KEY: &str = "x-goog-api-key";
+
+/// Auth returns the http header, which carries the credentials for a request to the given url.
+///
+/// The credentials are never part of the url, so they can not leak into an error message or a log.
+pub trait Auth: Send + Sync {
+    fn header(&self, url: &str) -> Result<(HeaderName, HeaderValue), Error>;
+}
+
+// every token provider can be used as oauth2 bearer token
+impl<P> Auth for P
+where
+    P: TokenProvider + ?Sized,
+{
+    fn header(&self, _url: &str) -> Result<(HeaderName, HeaderValue), Error> {
+        match self.token() {
+            Ok(token) => Ok((AUTHORIZATION, bearer(&token.token)?)),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn bearer(token: &str) -> Result<HeaderValue, Error> {
+    sensitive(format!("Bearer {}", token))
+}
+
+// a sensitive header value is not printed by reqwest (Debug)
+fn sensitive(value: String) -> Result<HeaderValue, Error> {
+    match HeaderValue::from_str(&value) {
+        Ok(mut v) => {
+            v.set_sensitive(true);
+            Ok(v)
+        }
+        Err(_) => Err(Error::new(
+            StatusCode::UNAUTHORIZED,
+            "credentials contain invalid header characters".to_string(),
+        )),
+    }
+}
+
+/// ApiKey sends the key as header `x-goog-api-key`, so there is no token request.
+pub struct ApiKey<T: AsRef<str>> {
+    pub key: T,
+}
+
+impl<T: AsRef<str>> ApiKey<T> {
+    pub fn new(key: T) -> Self {
+        Self { key }
+    }
+}
+
+impl<T> Auth for ApiKey<T>
+where
+    T: AsRef<str> + Send + Sync,
+{
+    fn header(&self, _url: &str) -> Result<(HeaderName, HeaderValue), Error> {
+        Ok((
+            HeaderName::from_static(HEADER_API_KEY),
+            sensitive(self.key.as_ref().to_string())?,
+        ))
+    }
+}
+
+impl<T: AsRef<str>> fmt::Debug for ApiKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiKey").field("key", &REDACTED).finish()
+    }
+}
+
+/// JwtToken is a fixed access token (e.g. of `gcloud auth print-access-token`),
+/// which can not be refreshed.
+pub struct JwtToken<T: AsRef<str>> {
+    access_token: T,
+}
+
+impl<T: AsRef<str>> JwtToken<T> {
+    pub fn new(access_token: T) -> Self {
+        Self { access_token }
+    }
+}
+
+impl<T: AsRef<str>> fmt::Debug for JwtToken<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtToken")
+            .field("access_token", &REDACTED)
+            .finish()
+    }
+}
+
+impl<T> TokenProvider for JwtToken<T>
+where
+    T: AsRef<str> + Send + Sync,
+{
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        Ok(AccessToken {
+            token: self.access_token.as_ref().to_string(),
+            expires_at: None,
+        })
+    }
+}
+
+/// PrivateKeyGrant signs a new claim with the private key (PEM) for every token and exchanges it
+/// at the token endpoint, use a CachedTokenProvider to reuse the access token until it expires.
+pub struct PrivateKeyGrant {
+    private_key: String,
+    client_email: String,
+    token_uri: String,
+}
+
+impl PrivateKeyGrant {
+    /// The client email of the service account is the issuer of the claim.
+    pub fn new(private_key: &str, client_email: &str) -> Self {
+        PrivateKeyGrant {
+            private_key: private_key.to_string(),
+            client_email: client_email.to_string(),
+            token_uri: URL_TOKEN.to_string(),
+        }
+    }
+
+    /// A new jwt-token, signed with the private key.
+    pub fn jwt_token(&self) -> Result<String, AuthError> {
+        let claim = ClaimBuilder::new(self.client_email.as_str()).build()?;
+        create_jwt_token(claim, &self.private_key, None)
+    }
+}
+
+impl TokenProvider for PrivateKeyGrant {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        get_access_token(&self.token_uri, self.jwt_token()?)
+    }
+}
+
+impl fmt::Debug for PrivateKeyGrant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivateKeyGrant")
+            .field("private_key", &REDACTED)
+            .field("client_email", &self.client_email)
+            .field("token_uri", &self.token_uri)
+            .finish()
+    }
+}
+
+fn get_access_token(token_uri: &str, jwt_token: impl AsRef<str>) -> Result<AccessToken, AuthError> {
+    let v = jwt_bearer_grant(token_uri, jwt_token)?;
+    access_token_from_response(&v)
+}
+
+// exchange the signed jwt-token at the token endpoint,
+// the response contains the access_token or the id_token (claim with target_audience)
+fn jwt_bearer_grant(token_uri: &str, jwt_token: impl AsRef<str>) -> Result<Value, AuthError> {
+    let client = reqwest::blocking::Client::new();
+    let json_resp = client
+        .post(token_uri)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(format!(
+            "grant_type=urn%3Aietf%3Aparams%3Aoauth%3Agrant-type%3Ajwt-bearer&assertion={}",
+            jwt_token.as_ref()
+        ))
+        .send()?;
+
+    oauth_response(json_resp)
+}
+
+// the token response of oauth2 and the metadata server:
+// {"access_token": "...", "expires_in": 3599, "token_type": "Bearer"}
+fn access_token_from_response(v: &Value) -> Result<AccessToken, AuthError> {
+    let token = match v.get("access_token").and_then(Value::as_str) {
+        Some(token) => token.to_string(),
+        None => {
+            return Err(AuthError::InvalidToken(
+                "no access_token in the token response".to_string(),
+            ))
+        }
+    };
+
+    match v.get("expires_in").and_then(Value::as_i64) {
+        Some(expires_in) => Ok(AccessToken::new(token, expires_in)),
+        None => Ok(AccessToken {
+            token,
+            expires_at: None,
+        }),
+    }
+}
+
+/// Create a signed (RS256) jwt-token. The `key_id` is set as `kid` in the header,
+/// so google can find the public key to the private key.
+pub fn create_jwt_token<T: Serialize>(
+    claim: T,
+    private_key: &str,
+    key_id: Option<&str>,
+) -> Result<String, AuthError> {
+    match jsonwebtoken::EncodingKey::from_rsa_pem(private_key.as_bytes()) {
+        Ok(pk) => {
+            let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+            header.kid = key_id.map(|kid| kid.to_string());
+            match jsonwebtoken::encode(&header, &claim, &pk) {
+                Ok(token) => Ok(token),
+                Err(msg) => Err(AuthError::Signing(msg.to_string())),
+            }
+        }
+        Err(msg) => Err(AuthError::MalformedKey(msg.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::Claim;
+    use crate::gcloud::testutil::{self, MockResponse, MockServer};
+    use serde_json::json;
+    use token::CachedTokenProvider;
+
+    const CLIENT_EMAIL: &str = "bucket@goheros-207118.iam.gserviceaccount.com";
+
+    #[test]
+    fn test_api_key_header() {
+        let (name, value) = ApiKey::new("my-key").header("https://any").unwrap();
+        assert_eq!("x-goog-api-key", name.as_str());
+        assert_eq!("my-key", value.to_str().unwrap());
+        assert!(value.is_sensitive());
+    }
+
+    #[test]
+    fn test_bearer_header() {
+        let (name, value) = JwtToken::new("my-token").header("https://any").unwrap();
+        assert_eq!(AUTHORIZATION, name);
+        assert_eq!("Bearer my-token", value.to_str().unwrap());
+        assert!(value.is_sensitive());
+    }
+
+    #[test]
+    fn test_debug_without_credentials() {
+        let out = format!(
+            "{:?} {:?} {:?}",
+            ApiKey::new("my-key"),
+            JwtToken::new("my-token"),
+            JwtToken::new("my-token").token().unwrap()
+        );
+        assert!(!out.contains("my-key"), "{}", out);
+        assert!(!out.contains("my-token"), "{}", out);
+    }
+
+    #[test]
+    fn test_private_key_grant_refresh() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({"access_token": "token-1", "expires_in": 30})),
+            MockResponse::json(200, json!({"access_token": "token-2", "expires_in": 3599})),
+        ]);
+        let grant = PrivateKeyGrant {
+            private_key: testutil::PRIVATE_KEY.to_string(),
+            client_email: CLIENT_EMAIL.to_string(),
+            token_uri: format!("{}/token", server.url()),
+        };
+        let p = CachedTokenProvider::new(grant);
+
+        // the first token expires within the refresh time, so a new grant is minted
+        assert_eq!("token-1", p.token().unwrap().token);
+        assert_eq!("token-2", p.token().unwrap().token);
+        assert_eq!("token-2", p.token().unwrap().token);
+
+        assert_eq!(2, server.requests().len());
+        let out = format!(
+            "{:?}",
+            PrivateKeyGrant::new(testutil::PRIVATE_KEY, CLIENT_EMAIL)
+        );
+        assert!(!out.contains("PRIVATE KEY"), "{}", out);
+    }
+
+    #[test]
+    fn test_private_key_grant_issuer() {
+        let grant = PrivateKeyGrant::new(
+            testutil::PRIVATE_KEY,
+            "other@goheros-207118.iam.gserviceaccount.com",
+        );
+        let jwt_token = grant.jwt_token().unwrap();
+        let claim = jsonwebtoken::dangerous_insecure_decode::<Claim>(&jwt_token)
+            .unwrap()
+            .claims;
+        assert_eq!("other@goheros-207118.iam.gserviceaccount.com", claim.iss);
+    }
+
+    #[test]
+    fn test_create_jwt_token_malformed_key() {
+        match create_jwt_token("claim", "no pem", None) {
+            Err(AuthError::MalformedKey(_)) => {}
+            other => panic!("expected MalformedKey, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_get_access_token_oauth_error() {
+        let server = MockServer::start(vec![MockResponse::json(
+            400,
+            json!({"error": "invalid_grant", "error_description": "Invalid JWT Signature."}),
+        )]);
+
+        match get_access_token(&format!("{}/token", server.url()), "a.b.c") {
+            Err(AuthError::OAuth {
+                status,
+                error,
+                error_description,
+            }) => {
+                assert_eq!(400, status);
+                assert_eq!("invalid_grant", error);
+                assert_eq!(
+                    Some("Invalid JWT Signature.".to_string()),
+                    error_description
+                );
+            }
+            other => panic!("expected OAuth error, got: {:?}", other),
+        }
+    }
+}
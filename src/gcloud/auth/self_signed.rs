@@ -0,0 +1,160 @@
+use super::service_account::ServiceAccountKey;
+use super::token::AccessToken;
+use super::{bearer, create_jwt_token, Auth, AuthError};
+use crate::gcloud::Error;
+
+use chrono::{Duration, Utc};
+use http::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use log::debug;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// the maximum lifetime of a self-signed jwt
+const LIFETIME_SECS: i64 = 3600;
+const REFRESH_BEFORE_SECS: i64 = 60;
+
+// https://developers.google.com/identity/protocols/oauth2/service-account#jwt-auth
+//
+// iss and sub: the email address of the service account
+// aud: the api endpoint, e.g. https://datastore.googleapis.com/
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+struct SelfSignedClaim {
+    iss: String,
+    sub: String,
+    aud: String,
+    iat: i64,
+    exp: i64,
+}
+
+/// SelfSignedJwt signs a jwt-token for the audience (the api endpoint) with the private key
+/// of the service account and uses the jwt-token direct as access token.
+/// So there is no request to the oauth2 token endpoint.
+///
+/// The tokens are cached per audience, until they expire.
+pub struct SelfSignedJwt {
+    key: ServiceAccountKey,
+    cache: Mutex<HashMap<String, AccessToken>>,
+}
+
+impl SelfSignedJwt {
+    pub fn new(key: ServiceAccountKey) -> Self {
+        SelfSignedJwt {
+            key,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The token for the audience, e.g. `https://datastore.googleapis.com/`.
+    pub fn token_for(&self, audience: &str) -> Result<AccessToken, AuthError> {
+        // a panic of another thread does not corrupt the cache, so the lock is recovered
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(token) = cache.get(audience) {
+            if !token.expires_within(Duration::seconds(REFRESH_BEFORE_SECS)) {
+                return Ok(token.clone());
+            }
+        }
+
+        debug!("create self-signed jwt for: {}", audience);
+        let now = Utc::now().timestamp();
+        let claim = SelfSignedClaim {
+            iss: self.key.client_email.clone(),
+            sub: self.key.client_email.clone(),
+            aud: audience.to_string(),
+            iat: now,
+            exp: now + LIFETIME_SECS,
+        };
+        let jwt_token =
+            create_jwt_token(claim, &self.key.private_key, Some(&self.key.private_key_id))?;
+
+        let token = AccessToken::new(jwt_token, LIFETIME_SECS);
+        cache.insert(audience.to_string(), token.clone());
+        Ok(token)
+    }
+}
+
+impl Auth for SelfSignedJwt {
+    fn header(&self, url: &str) -> Result<(HeaderName, HeaderValue), Error> {
+        let token = self.token_for(&audience(url)?)?;
+        Ok((AUTHORIZATION, bearer(&token.token)?))
+    }
+}
+
+// the audience is the service endpoint:
+// https://datastore.googleapis.com/v1/projects/p:lookup -> https://datastore.googleapis.com/
+fn audience(url: &str) -> Result<String, AuthError> {
+    let url = Url::parse(url)
+        .map_err(|err| AuthError::InvalidArgument(format!("invalid url for audience: {}", err)))?;
+
+    match url.host_str() {
+        Some(host) => Ok(format!("{}://{}/", url.scheme(), host)),
+        None => Err(AuthError::InvalidArgument(
+            "url without host for audience".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil;
+
+    fn self_signed_jwt() -> SelfSignedJwt {
+        SelfSignedJwt::new(ServiceAccountKey::from_json(&testutil::service_account_json()).unwrap())
+    }
+
+    #[test]
+    fn test_audience() {
+        assert_eq!(
+            "https://datastore.googleapis.com/",
+            audience("https://datastore.googleapis.com/v1/projects/p:lookup").unwrap()
+        );
+        assert!(audience("no url").is_err());
+    }
+
+    #[test]
+    fn test_token_for() {
+        let jwt = self_signed_jwt();
+        let token = jwt.token_for("https://datastore.googleapis.com/").unwrap();
+
+        let header = jsonwebtoken::decode_header(&token.token).unwrap();
+        assert_eq!(Some(testutil::KEY_ID.to_string()), header.kid);
+
+        let claim = jsonwebtoken::dangerous_insecure_decode::<SelfSignedClaim>(&token.token)
+            .unwrap()
+            .claims;
+        assert_eq!("https://datastore.googleapis.com/", claim.aud);
+        assert_eq!("bucket@goheros-207118.iam.gserviceaccount.com", claim.iss);
+        assert_eq!(claim.iss, claim.sub);
+        assert_eq!(LIFETIME_SECS, claim.exp - claim.iat);
+    }
+
+    #[test]
+    fn test_cached_per_audience() {
+        let jwt = self_signed_jwt();
+        let datastore = jwt.token_for("https://datastore.googleapis.com/").unwrap();
+        let storage = jwt.token_for("https://storage.googleapis.com/").unwrap();
+        assert_ne!(datastore.token, storage.token);
+
+        let again = jwt.token_for("https://datastore.googleapis.com/").unwrap();
+        assert_eq!(datastore.token, again.token);
+    }
+
+    #[test]
+    fn test_header() {
+        let jwt = self_signed_jwt();
+        let (name, value) = jwt
+            .header("https://datastore.googleapis.com/v1/projects/p:lookup")
+            .unwrap();
+        assert_eq!(AUTHORIZATION, name);
+
+        let token = jwt.token_for("https://datastore.googleapis.com/").unwrap();
+        assert_eq!(format!("Bearer {}", token.token), value.to_str().unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+use super::AuthError;
+use crate::gcloud::REDACTED;
+
+use chrono::{DateTime, Duration, Utc};
+use log::debug;
+
+use std::fmt;
+use std::sync::Mutex;
+
+// refresh the token one minute before it expires
+const DEFAULT_REFRESH_BEFORE_SECS: i64 = 60;
+
+#[derive(Clone, PartialEq)]
+pub struct AccessToken {
+    pub token: String,
+    // None: the expiry is unknown, the token is used as long as it is valid
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl AccessToken {
+    pub fn new(token: String, expires_in_secs: i64) -> Self {
+        AccessToken {
+            token,
+            expires_at: Some(Utc::now() + Duration::seconds(expires_in_secs)),
+        }
+    }
+
+    /// Is the token expired or does the token expire within the given duration.
+    pub fn expires_within(&self, duration: Duration) -> bool {
+        match self.expires_at {
+            Some(expires_at) => expires_at - duration <= Utc::now(),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("token", &REDACTED)
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// A TokenProvider is asked for an access token before every request.
+pub trait TokenProvider: Send + Sync {
+    fn token(&self) -> Result<AccessToken, AuthError>;
+}
+
+impl<P: TokenProvider + ?Sized> TokenProvider for Box<P> {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        (**self).token()
+    }
+}
+
+/// CachedTokenProvider remembers the token of the underlying provider
+/// and asks the provider for a new one shortly before the token expires.
+///
+/// The refresh is done under the lock, so concurrent callers wait
+/// for one refresh, instead of all fetching a new token (single-flight).
+pub struct CachedTokenProvider<P: TokenProvider> {
+    provider: P,
+    refresh_before: Duration,
+    cache: Mutex<Option<AccessToken>>,
+}
+
+impl<P: TokenProvider> CachedTokenProvider<P> {
+    pub fn new(provider: P) -> Self {
+        CachedTokenProvider::with_refresh_before(
+            provider,
+            Duration::seconds(DEFAULT_REFRESH_BEFORE_SECS),
+        )
+    }
+
+    pub fn with_refresh_before(provider: P, refresh_before: Duration) -> Self {
+        CachedTokenProvider {
+            provider,
+            refresh_before,
+            cache: Mutex::new(None),
+        }
+    }
+}
+
+impl<P: TokenProvider> TokenProvider for CachedTokenProvider<P> {
+    fn token(&self) -> Result<AccessToken, AuthError> {
+        // a panic of another thread does not corrupt the cache, so the lock is recovered
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(token) = cache.as_ref() {
+            if !token.expires_within(self.refresh_before) {
+                return Ok(token.clone());
+            }
+        }
+
+        debug!("refresh access token");
+        let token = self.provider.token()?;
+        *cache = Some(token.clone());
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    struct CountingProvider {
+        calls: AtomicUsize,
+        expires_in_secs: i64,
+    }
+
+    impl CountingProvider {
+        fn new(expires_in_secs: i64) -> Self {
+            CountingProvider {
+                calls: AtomicUsize::new(0),
+                expires_in_secs,
+            }
+        }
+    }
+
+    impl TokenProvider for CountingProvider {
+        fn token(&self) -> Result<AccessToken, AuthError> {
+            let n = self.calls.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(20));
+            Ok(AccessToken::new(
+                format!("token-{}", n),
+                self.expires_in_secs,
+            ))
+        }
+    }
+
+    #[test]
+    fn test_expires_within() {
+        let t = AccessToken::new("t".to_string(), 30);
+        assert!(t.expires_within(Duration::seconds(60)));
+        assert!(!t.expires_within(Duration::seconds(10)));
+
+        let t = AccessToken {
+            token: "t".to_string(),
+            expires_at: None,
+        };
+        assert!(!t.expires_within(Duration::days(1)));
+    }
+
+    #[test]
+    fn test_cached_token() {
+        let p = CachedTokenProvider::new(CountingProvider::new(3600));
+        assert_eq!("token-0", p.token().unwrap().token);
+        assert_eq!("token-0", p.token().unwrap().token);
+        assert_eq!(1, p.provider.calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_refresh_before_expiry() {
+        let p = CachedTokenProvider::new(CountingProvider::new(30));
+        assert_eq!("token-0", p.token().unwrap().token);
+        assert_eq!("token-1", p.token().unwrap().token);
+        assert_eq!(2, p.provider.calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_single_flight() {
+        let p = Arc::new(CachedTokenProvider::new(CountingProvider::new(3600)));
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let p = Arc::clone(&p);
+                thread::spawn(move || p.token().unwrap().token)
+            })
+            .collect();
+
+        for h in handles {
+            assert_eq!("token-0", h.join().unwrap());
+        }
+        assert_eq!(1, p.provider.calls.load(Ordering::SeqCst));
+    }
+}
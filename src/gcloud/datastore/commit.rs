@@ -1,33 +1,394 @@
-use crate::gcloud::{Error, ResponseError};
+use crate::gcloud::auth::Auth;
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::http::Client;
+use crate::gcloud::transport::Request;
+use crate::gcloud::Error;
 
-use http::StatusCode;
-use reqwest::blocking;
-use serde_json::Value;
+use super::serializer::to_properties;
+use super::Key;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 
+/// The mode of a commit, a transactional commit is atomic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommitMode {
+    Transactional,
+    NonTransactional,
+}
+
+impl CommitMode {
+    fn to_string(self) -> &'static str {
+        match self {
+            CommitMode::Transactional => "TRANSACTIONAL",
+            CommitMode::NonTransactional => "NON_TRANSACTIONAL",
+        }
+    }
+}
+
+/// A write of a commit, the properties are in the Datastore format:
+/// `{"Name": {"stringValue": "Yeh"}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mutation {
+    /// The entity must not exist, an incomplete key (without id) is allocated.
+    Insert {
+        key: Key,
+        properties: Value,
+    },
+    /// The entity must exist.
+    Update {
+        key: Key,
+        properties: Value,
+    },
+    Upsert {
+        key: Key,
+        properties: Value,
+    },
+    Delete(Key),
+}
+
+impl Mutation {
+    pub fn insert<T: Serialize>(key: Key, entity: &T) -> Result<Self, Error> {
+        let properties = to_properties(entity)?;
+        Ok(Mutation::Insert { key, properties })
+    }
+
+    pub fn update<T: Serialize>(key: Key, entity: &T) -> Result<Self, Error> {
+        let properties = to_properties(entity)?;
+        Ok(Mutation::Update { key, properties })
+    }
+
+    pub fn upsert<T: Serialize>(key: Key, entity: &T) -> Result<Self, Error> {
+        let properties = to_properties(entity)?;
+        Ok(Mutation::Upsert { key, properties })
+    }
+
+    pub fn delete(key: Key) -> Self {
+        Mutation::Delete(key)
+    }
+
+    // the same mutation can be written twice: an insert fails the second time
+    // and an upsert with an incomplete key allocates a second entity
+    fn is_idempotent(&self) -> bool {
+        match self {
+            Mutation::Upsert { key, .. } => key.is_complete(),
+            Mutation::Delete(_) => true,
+            Mutation::Insert { .. } | Mutation::Update { .. } => false,
+        }
+    }
+
+    pub fn key(&self) -> &Key {
+        match self {
+            Mutation::Insert { key, .. }
+            | Mutation::Update { key, .. }
+            | Mutation::Upsert { key, .. }
+            | Mutation::Delete(key) => key,
+        }
+    }
+
+    // https://cloud.google.com/datastore/docs/reference/data/rest/v1/projects/commit#Mutation
+    fn to_json(&self) -> Value {
+        let entity = |key: &Key, properties: &Value| json!({"key": key, "properties": properties});
+        match self {
+            Mutation::Insert { key, properties } => json!({ "insert": entity(key, properties) }),
+            Mutation::Update { key, properties } => json!({ "update": entity(key, properties) }),
+            Mutation::Upsert { key, properties } => json!({ "upsert": entity(key, properties) }),
+            Mutation::Delete(key) => json!({ "delete": key }),
+        }
+    }
+}
+
+/// The result of a mutation, in the order of the mutations.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationResult {
+    /// The allocated key of an insert with an incomplete key.
+    #[serde(default)]
+    pub key: Option<Key>,
+    /// The version of the entity after the mutation, a deleted entity has the version of the deletion.
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub conflict_detected: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitResult {
+    #[serde(default)]
+    pub mutation_results: Vec<MutationResult>,
+    /// The number of the updated index entries.
+    #[serde(default)]
+    pub index_updates: i64,
+    #[serde(default)]
+    pub commit_time: Option<String>,
+}
+
+/// The options of a new transaction, a read-write transaction after an aborted one
+/// has the previous transaction (for a higher priority).
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionMode<'t> {
+    ReadOnly,
+    ReadWrite {
+        previous_transaction: Option<&'t str>,
+    },
+}
+
+impl TransactionMode<'_> {
+    // https://cloud.google.com/datastore/docs/reference/data/rest/v1/TransactionOptions
+    fn to_json(&self) -> Value {
+        match self {
+            TransactionMode::ReadOnly => json!({"transactionOptions": {"readOnly": {}}}),
+            TransactionMode::ReadWrite {
+                previous_transaction: Some(previous),
+            } => json!({"transactionOptions": {"readWrite": {"previousTransaction": previous}}}),
+            TransactionMode::ReadWrite {
+                previous_transaction: None,
+            } => json!({"transactionOptions": {"readWrite": {}}}),
+        }
+    }
+}
+
+// the request of the database, the default database is empty and not sent
+fn with_database(mut request: Value, database: &str) -> Value {
+    if !database.is_empty() {
+        request["databaseId"] = json!(database);
+    }
+    request
+}
+
+/// Begin a transaction in the database and return its id.
 pub fn transaction(
-    client: &blocking::Client,
-    auth_query_str: &str,
+    client: &Client,
+    auth: &dyn Auth,
+    endpoint: &str,
     project: &str,
+    database: &str,
+    mode: &TransactionMode,
 ) -> Result<String, Error> {
-    let url = format!(
-        "https://datastore.googleapis.com/v1/projects/{}:beginTransaction?{}",
-        project, auth_query_str
-    );
-    let resp = client.post(&url).body("").send()?;
-
-    if resp.status().as_u16() == StatusCode::OK.as_u16() {
-        let v = resp.json::<Value>().unwrap();
-        let trans = v.get("transaction").unwrap();
-        return Ok(trans.as_str().unwrap().to_string());
-    } else {
-        Err(resp.json::<ResponseError>()?.error)
+    let url = format!("{}/v1/projects/{}:beginTransaction", endpoint, project);
+    let request = with_database(mode.to_json(), database);
+    let resp = client.send(|| {
+        let (name, value) = auth.header(&url)?;
+        Request::post(&url)
+            .with_header(name, value)
+            .with_json(&request)
+    })?;
+
+    let v = resp.deserialize::<Value>()?;
+    match v.get("transaction").and_then(Value::as_str) {
+        Some(transaction) => Ok(transaction.to_string()),
+        None => Err(Error::with_kind(
+            ErrorKind::Decode,
+            format!("the response has no transaction: {}", v),
+        )),
+    }
+}
+
+/// Roll back the transaction, e.g. after an error in the transaction.
+pub fn rollback(
+    client: &Client,
+    auth: &dyn Auth,
+    endpoint: &str,
+    project: &str,
+    database: &str,
+    transaction: &str,
+) -> Result<(), Error> {
+    let url = format!("{}/v1/projects/{}:rollback", endpoint, project);
+    let request = with_database(json!({ "transaction": transaction }), database);
+    client.send(|| {
+        let (name, value) = auth.header(&url)?;
+        Request::post(&url)
+            .with_header(name, value)
+            .with_json(&request)
+    })?;
+    Ok(())
+}
+
+fn create_commit_json(database: &str, transaction: Option<&str>, mutations: &[Mutation]) -> Value {
+    let mode = match transaction {
+        Some(_) => CommitMode::Transactional,
+        None => CommitMode::NonTransactional,
+    };
+    let mut commit = json!({
+        "mode": mode.to_string(),
+        "mutations": mutations.iter().map(Mutation::to_json).collect::<Vec<_>>()
+    });
+    if let Some(transaction) = transaction {
+        commit["transaction"] = json!(transaction);
     }
+    with_database(commit, database)
 }
 
+/// Commit the mutations, with a transaction the commit is `TRANSACTIONAL`.
+/// Only a `NON_TRANSACTIONAL` commit of upserts (with complete keys) and deletes is retried,
+/// an insert or an update is not idempotent and a transaction is retried as a whole.
 pub fn commit(
-    client: &blocking::Client,
-    auth_query_str: &str,
+    client: &Client,
+    auth: &dyn Auth,
+    endpoint: &str,
     project: &str,
-) -> Result<String, Error> {
-    transaction(&client, &auth_query_str, &project)
+    database: &str,
+    transaction: Option<&str>,
+    mutations: &[Mutation],
+) -> Result<CommitResult, Error> {
+    let url = format!("{}/v1/projects/{}:commit", endpoint, project);
+    let commit = create_commit_json(database, transaction, mutations);
+    let request = || {
+        let (name, value) = auth.header(&url)?;
+        Request::post(&url)
+            .with_header(name, value)
+            .with_json(&commit)
+    };
+    let resp = if transaction.is_none() && mutations.iter().all(Mutation::is_idempotent) {
+        client.send(request)?
+    } else {
+        client.send_once(request()?)?
+    };
+
+    resp.deserialize::<CommitResult>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::auth::ApiKey;
+    use crate::gcloud::http::RetryPolicy;
+    use crate::gcloud::testutil::{MockResponse, MockServer};
+    use std::time::Duration;
+
+    #[test]
+    fn test_create_commit_json() {
+        let key = Key::new("goheros-207118", "heroes", "Rust-Test", None);
+        let insert = Mutation::insert(key, &json!({"IsTrue": true, "Name": "Yeh"})).unwrap();
+        let delete = Mutation::delete(Key::new("goheros-207118", "heroes", "Rust-Test", Some(42)));
+
+        assert_eq!(
+            json!({
+                "mode": "TRANSACTIONAL",
+                "mutations": [
+                    {"insert": {
+                        "key": {
+                            "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+                            "path": [{"kind": "Rust-Test"}]
+                        },
+                        "properties": {
+                            "IsTrue": {"booleanValue": true},
+                            "Name": {"stringValue": "Yeh"}
+                        }
+                    }},
+                    {"delete": {
+                        "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+                        "path": [{"kind": "Rust-Test", "id": "42"}]
+                    }}
+                ],
+                "transaction": "tx-1"
+            }),
+            create_commit_json("", Some("tx-1"), &[insert, delete.clone()])
+        );
+
+        let commit = create_commit_json("db-1", None, &[delete]);
+        assert_eq!("NON_TRANSACTIONAL", commit["mode"]);
+        assert_eq!(None, commit.get("transaction"));
+        assert_eq!("db-1", commit["databaseId"]);
+    }
+
+    #[test]
+    fn test_transaction_mode() {
+        assert_eq!(
+            json!({"transactionOptions": {"readOnly": {}}}),
+            TransactionMode::ReadOnly.to_json()
+        );
+        assert_eq!(
+            json!({"transactionOptions": {"readWrite": {"previousTransaction": "tx-1"}}}),
+            TransactionMode::ReadWrite {
+                previous_transaction: Some("tx-1")
+            }
+            .to_json()
+        );
+    }
+
+    #[test]
+    fn test_commit_result() {
+        let result: CommitResult = serde_json::from_value(json!({
+            "mutationResults": [
+                {
+                    "key": {
+                        "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+                        "path": [{"kind": "Rust-Test", "id": "5629499534213120"}]
+                    },
+                    "version": "1586011287013000"
+                },
+                {"version": "1586011287013000", "conflictDetected": true}
+            ],
+            "indexUpdates": 4,
+            "commitTime": "2020-04-04T14:41:27.013Z"
+        }))
+        .unwrap();
+
+        assert_eq!(4, result.index_updates);
+        assert_eq!(
+            Some(5629499534213120),
+            result.mutation_results[0].key.as_ref().unwrap().id()
+        );
+        assert_eq!(None, result.mutation_results[1].key);
+        assert!(result.mutation_results[1].conflict_detected);
+    }
+
+    fn unavailable() -> MockResponse {
+        MockResponse::json(
+            503,
+            json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}}),
+        )
+    }
+
+    #[test]
+    fn test_commit_retry_only_idempotent() {
+        let server = MockServer::start(vec![
+            unavailable(),
+            MockResponse::json(
+                200,
+                json!({"mutationResults": [{"version": "2"}, {"version": "2"}]}),
+            ),
+            unavailable(),
+            unavailable(),
+        ]);
+        let client = Client::default().with_retry_policy(
+            RetryPolicy::default().with_initial_backoff(Duration::from_millis(1)),
+        );
+        let auth = ApiKey::new("my-key");
+        let send = |transaction: Option<&str>, mutations: &[Mutation]| {
+            commit(
+                &client,
+                &auth,
+                &server.url(),
+                "goheros-207118",
+                "",
+                transaction,
+                mutations,
+            )
+        };
+        let key = |id: Option<i64>| Key::new("goheros-207118", "heroes", "Rust-Test", id);
+
+        // upsert and delete can be written twice
+        let upsert = Mutation::upsert(key(Some(7)), &json!({"Name": "Yeh"})).unwrap();
+        let result = send(None, &[upsert, Mutation::delete(key(Some(42)))]).unwrap();
+        assert_eq!(2, result.mutation_results.len());
+        assert_eq!(2, server.requests().len());
+
+        // an upsert with an incomplete key allocates a new key
+        let upsert = Mutation::upsert(key(None), &json!({"Name": "Yeh"})).unwrap();
+        assert_eq!(
+            ErrorKind::Unavailable,
+            send(None, &[upsert]).unwrap_err().kind()
+        );
+        assert_eq!(3, server.requests().len());
+
+        // a transaction is retried as a whole
+        let delete = Mutation::delete(key(Some(42)));
+        assert_eq!(
+            ErrorKind::Unavailable,
+            send(Some("tx-1"), &[delete]).unwrap_err().kind()
+        );
+        assert_eq!(4, server.requests().len());
+    }
 }
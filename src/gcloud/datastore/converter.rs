@@ -1,39 +1,34 @@
-use super::{Entity, Error};
+use super::{query, Entity, Error};
+use crate::gcloud::error::ErrorKind;
 use http::StatusCode;
 use serde::de::DeserializeOwned;
 use serde_json::map::Map;
-use serde_json::{Number, Value};
+use serde_json::Value;
 
 pub fn deserialize_lookup_result<D>(v: &Value) -> Result<D, Error>
 where
     D: DeserializeOwned,
 {
-    if let Some(found) = v.get("found") {
-        let prop_map = found
-            .get(0)
-            .unwrap()
-            .get("entity")
-            .unwrap()
-            .get("properties")
-            .unwrap();
-
-        let v = to_object(prop_map);
+    if let Some(result) = first(v, "found") {
+        let v = entity_object(result)?;
         return Ok(serde_json::from_value(v)?);
     };
 
-    if let Some(missing) = v.get("missing") {
-        let e: Entity =
-            serde_json::from_value(missing.get(0).unwrap().get("entity").unwrap().clone())?;
+    if let Some(result) = first(v, "missing") {
+        let entity = result
+            .get("entity")
+            .ok_or_else(|| decode("the missing result has no entity", result))?;
+        let e: Entity = serde_json::from_value(entity.clone())?;
         return Err(Error::new(
             StatusCode::NOT_FOUND,
-            format!("result is missing: {}", e.to_string()),
+            format!("result is missing: {}", e),
         ));
     };
 
     // this must be: deferred
-    Err(Error::new(
-        StatusCode::INTERNAL_SERVER_ERROR,
-        format!("could not deserialize lookup result, invalid result: {}", v).to_string(),
+    Err(decode(
+        "could not deserialize lookup result, invalid result",
+        v,
     ))
 }
 
@@ -41,85 +36,109 @@ pub fn deserialize_query_result<D>(v: &Value) -> Result<Vec<D>, Error>
 where
     D: DeserializeOwned,
 {
-    if let Some(batch) = v.get("batch") {
-        let results = batch.get("entityResults").unwrap().as_array().unwrap();
-        let mut return_vec = Vec::<Value>::with_capacity(results.len());
-        for r in results {
-            let prop_map = r.get("entity").unwrap().get("properties").unwrap();
-            return_vec.push(to_object(prop_map));
-        }
-        let return_arr = Value::Array(return_vec);
-        return Ok(serde_json::from_value(return_arr)?);
+    let batch = v
+        .get("batch")
+        .ok_or_else(|| decode("could not deserialize query result, expect 'batch'", v))?;
+
+    // a batch without a match has no entityResults
+    let results = match batch.get("entityResults") {
+        Some(results) => results
+            .as_array()
+            .ok_or_else(|| decode("the entityResults must be an array", results))?,
+        None => return Ok(vec![]),
     };
+    let mut return_vec = Vec::<Value>::with_capacity(results.len());
+    for r in results {
+        return_vec.push(entity_object(r)?);
+    }
+    Ok(serde_json::from_value(Value::Array(return_vec))?)
+}
 
-    Err(Error::new(
-        StatusCode::INTERNAL_SERVER_ERROR,
-        format!(
-            "could not deserialize qurey result, expect 'batch' and not: {:?}",
-            v.get(0)
-        ),
-    ))
+// the first result of the lookup, e.g. of "found": [{"entity": {...}}]
+fn first<'v>(v: &'v Value, name: &str) -> Option<&'v Value> {
+    v.get(name)
+        .and_then(Value::as_array)
+        .and_then(|a| a.first())
+}
+
+// the object of the entity of a result, an entity without properties has none in the json
+fn entity_object(result: &Value) -> Result<Value, Error> {
+    let entity = result
+        .get("entity")
+        .ok_or_else(|| decode("the result has no entity", result))?;
+    match entity.get("properties") {
+        Some(properties) => to_object(properties),
+        None => Ok(Value::Object(Map::new())),
+    }
+}
+
+fn decode(message: &str, v: &Value) -> Error {
+    Error::with_kind(ErrorKind::Decode, format!("{}: {}", message, v))
 }
 
 // example:
 // "Name": {"stringValue": "its me"}
-// attr_name (attr): { datatype (dt) : value (v) }
-pub fn to_object(map: &Value) -> Value {
+// attr_name (attr): { datatype : value }
+pub fn to_object(map: &Value) -> Result<Value, Error> {
+    let properties = map.as_object().ok_or_else(|| {
+        Error::with_kind(
+            ErrorKind::Decode,
+            format!("the properties must be an object: {}", map),
+        )
+    })?;
     let mut result_map = Map::new();
-    for (attr, dt_v) in map.as_object().unwrap() {
-        for (dt, v) in dt_v.as_object().unwrap() {
-            let to_val = to_value(dt, v.as_str().unwrap());
-            result_map.insert(attr.to_string(), to_val);
-        }
+    for (attr, dt_v) in properties {
+        result_map.insert(attr.to_string(), to_value(dt_v)?);
     }
-    Value::Object(result_map)
+    Ok(Value::Object(result_map))
 }
 
-// still missing datatypes:
+// all datatypes of:
 // https://cloud.google.com/datastore/docs/reference/data/rest/v1/projects/runQuery#Value
 //
-// convert: "integerValue": "42" (datatype = "integerValue", val = "42") -> Value::Number(42)
-pub fn to_value(datatype: &str, val: &str) -> Value {
-    match datatype {
-        "nullValue" => Value::Null,
-        "doubleValue" => {
-            let v = val.parse().unwrap();
-            let n = Number::from_f64(v).unwrap();
-            Value::Number(n)
-        }
-        "integerValue" => {
-            let v: isize = val.parse().unwrap();
-            Value::Number(Number::from(v))
-        }
-        "booleanValue" => {
-            let v = val.parse().unwrap();
-            Value::Bool(v)
-        }
-        // timestampValue | stringValue
-        _ => {
-            let v = val.to_string();
-            Value::String(v)
-        }
-    }
+// convert: {"integerValue": "42"} -> Value::Number(42)
+pub fn to_value(dt_v: &Value) -> Result<Value, Error> {
+    Ok(query::Value::from_datastore(dt_v)?.to_plain_json())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use serde::{Deserialize, Serialize};
+    use serde_json::{json, Number};
 
     #[test]
     fn test_to_value() {
         assert_eq!(
             Value::Number(Number::from(42)),
-            to_value("integerValue", "42")
+            to_value(&json!({"integerValue": "42"})).unwrap()
         );
 
-        assert_eq!(Value::Null, to_value("nullValue", "null"));
+        assert_eq!(Value::Null, to_value(&json!({"nullValue": null})).unwrap());
 
         assert_eq!(
             Value::String("foo".to_string()),
-            to_value("stringValue", "foo")
+            to_value(&json!({"stringValue": "foo"})).unwrap()
+        );
+
+        assert_eq!(
+            Value::Bool(true),
+            to_value(&json!({"booleanValue": true})).unwrap()
+        );
+        assert_eq!(
+            json!(4.2),
+            to_value(&json!({"doubleValue": 4.2, "meaning": 1})).unwrap()
+        );
+        assert_eq!(
+            json!([{"Name": "Fly"}]),
+            to_value(&json!({"arrayValue": {"values": [
+                {"entityValue": {"properties": {"Name": {"stringValue": "Fly"}}}}
+            ]}}))
+            .unwrap()
+        );
+        assert_eq!(
+            ErrorKind::Decode,
+            to_value(&json!({"fooValue": "foo"})).unwrap_err().kind()
         );
     }
 
@@ -130,7 +149,7 @@ mod tests {
             "Action": {"stringValue": "List"}
           }"#;
         let value_map: Value = serde_json::from_str(json).unwrap();
-        let result = to_object(&value_map);
+        let result = to_object(&value_map).unwrap();
 
         let mut map = Map::new();
         map.insert(String::from("HeroID"), Value::Number(Number::from(42)));
@@ -207,4 +226,31 @@ mod tests {
         assert_eq!("2018-09-02T18:51:06Z", heros.get(0).unwrap().time);
         assert_eq!("Delete", heros.get(1).unwrap().action);
     }
+
+    #[test]
+    fn test_deserialize_empty_results() {
+        let empty_batch = json!({"batch": {
+            "entityResultType": "FULL",
+            "endCursor": "CgA=",
+            "moreResults": "NO_MORE_RESULTS"
+        }});
+        let heros: Vec<Hero> = deserialize_query_result(&empty_batch).unwrap();
+        assert!(heros.is_empty());
+
+        let err = deserialize_query_result::<Hero>(&json!({"batch": {"entityResults": [{}]}}))
+            .unwrap_err();
+        assert_eq!(ErrorKind::Decode, err.kind());
+
+        let missing = json!({"found": [], "missing": [{"entity": {
+            "key": {"partitionId": {"projectId": "p"}, "path": [{"kind": "Hero", "id": "1"}]}
+        }}]});
+        assert_eq!(
+            404,
+            deserialize_lookup_result::<Hero>(&missing)
+                .unwrap_err()
+                .code
+        );
+        let err = deserialize_lookup_result::<Hero>(&json!({"found": [{}]})).unwrap_err();
+        assert_eq!(ErrorKind::Decode, err.kind());
+    }
 }
@@ -0,0 +1,488 @@
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::Error;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The key of an entity: the partition and the path from the root ancestor to the entity.
+/// The last element of the path without id and name is incomplete, the id is allocated with an insert.
+///
+/// `Key::new("p", "heroes", "Power", Some(7)).with_parent(&Key::named("p", "heroes", "Hero", "batman"))`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Key {
+    #[serde(rename = "partitionId")]
+    partition_id: PartitionId,
+    #[serde(default)]
+    path: Vec<PathElement>,
+}
+
+/// The partition of a key, the namespace and the database are empty for the default.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PartitionId {
+    #[serde(rename = "projectId")]
+    project_id: String,
+    #[serde(rename = "namespaceId", default)]
+    namespace_id: String,
+    #[serde(
+        rename = "databaseId",
+        default,
+        skip_serializing_if = "String::is_empty"
+    )]
+    database_id: String,
+}
+
+/// An element of the path, with a numeric id or a name. The id is an int64 string in the api.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PathElement {
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+}
+
+impl PartitionId {
+    pub fn new(project: &str, namespace: &str) -> Self {
+        PartitionId {
+            project_id: project.to_string(),
+            namespace_id: namespace.to_string(),
+            database_id: String::new(),
+        }
+    }
+
+    /// The database of the partition, the default database is empty.
+    pub fn with_database(mut self, database: &str) -> Self {
+        self.database_id = database.to_string();
+        self
+    }
+
+    pub fn project(&self) -> &str {
+        &self.project_id
+    }
+
+    pub fn database(&self) -> &str {
+        &self.database_id
+    }
+}
+
+impl PathElement {
+    pub fn with_id(kind: &str, id: i64) -> Self {
+        PathElement {
+            kind: kind.to_string(),
+            id: Some(id.to_string()),
+            name: None,
+        }
+    }
+
+    pub fn with_name(kind: &str, name: &str) -> Self {
+        PathElement {
+            kind: kind.to_string(),
+            id: None,
+            name: Some(name.to_string()),
+        }
+    }
+
+    /// An element without id and name, only the last element of a key can be incomplete.
+    pub fn incomplete(kind: &str) -> Self {
+        PathElement {
+            kind: kind.to_string(),
+            id: None,
+            name: None,
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    pub fn id(&self) -> Option<i64> {
+        self.id.as_ref().and_then(|id| id.parse().ok())
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.id.is_some() || self.name.is_some()
+    }
+}
+
+impl fmt::Display for PathElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.id, &self.name) {
+            (Some(id), _) => write!(f, "{}({})", self.kind, id),
+            (None, Some(name)) => write!(f, "{}({:?})", self.kind, name),
+            (None, None) => write!(f, "{}(incomplete)", self.kind),
+        }
+    }
+}
+
+impl Key {
+    /// The key of the kind with the id, a key without id is incomplete,
+    /// the id is allocated with an insert.
+    pub fn new(project: &str, namespace: &str, kind: &str, id: Option<i64>) -> Self {
+        let element = match id {
+            Some(id) => PathElement::with_id(kind, id),
+            None => PathElement::incomplete(kind),
+        };
+        Key::from_path(project, namespace, vec![element])
+    }
+
+    /// The key of the kind with the name, e.g. a name from the application.
+    pub fn named(project: &str, namespace: &str, kind: &str, name: &str) -> Self {
+        Key::from_path(project, namespace, vec![PathElement::with_name(kind, name)])
+    }
+
+    /// The key with the full path, the first element is the root ancestor.
+    pub fn from_path(project: &str, namespace: &str, path: Vec<PathElement>) -> Self {
+        Key {
+            partition_id: PartitionId::new(project, namespace),
+            path,
+        }
+    }
+
+    /// The key as child of the parent, the ancestors are replaced by the path of the parent.
+    pub fn with_parent(mut self, parent: &Key) -> Self {
+        let element = self.path.pop();
+        self.path = parent.path.clone();
+        self.path.extend(element);
+        self
+    }
+
+    /// The database of the key, the default database is empty.
+    pub fn with_database(mut self, database: &str) -> Self {
+        self.partition_id.database_id = database.to_string();
+        self
+    }
+
+    pub fn project(&self) -> &str {
+        &self.partition_id.project_id
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.partition_id.namespace_id
+    }
+
+    pub fn database(&self) -> &str {
+        &self.partition_id.database_id
+    }
+
+    pub fn path(&self) -> &[PathElement] {
+        &self.path
+    }
+
+    pub fn kind(&self) -> &str {
+        self.path.last().map(|p| p.kind()).unwrap_or_default()
+    }
+
+    /// The id of the entity, it is None for an incomplete key or a key with a name.
+    pub fn id(&self) -> Option<i64> {
+        self.path.last().and_then(PathElement::id)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.path.last().and_then(PathElement::name)
+    }
+
+    /// A complete key has an id or a name in all elements.
+    pub fn is_complete(&self) -> bool {
+        !self.path.is_empty() && self.path.iter().all(PathElement::is_complete)
+    }
+
+    /// The key of the parent, it is None for a root entity.
+    pub fn parent(&self) -> Option<Key> {
+        if self.path.len() < 2 {
+            return None;
+        }
+        Some(Key {
+            partition_id: self.partition_id.clone(),
+            path: self.path[..self.path.len() - 1].to_vec(),
+        })
+    }
+
+    /// The legacy urlsafe key of App Engine (e.g. of `ndb` or the Go `datastore.Key.Encode`),
+    /// the base64 (url-safe, without padding) of the `Reference` protocol buffer.
+    pub fn encode(&self) -> String {
+        let mut path = vec![];
+        for element in &self.path {
+            path.push(0x0b); // start group: element = 1
+            write_bytes(&mut path, 2, element.kind.as_bytes());
+            if let Some(id) = element.id() {
+                write_tag(&mut path, 3, WIRE_VARINT);
+                write_varint(&mut path, id as u64);
+            }
+            if let Some(name) = element.name() {
+                write_bytes(&mut path, 4, name.as_bytes());
+            }
+            path.push(0x0c); // end group
+        }
+
+        let mut buf = vec![];
+        write_bytes(&mut buf, 13, self.project().as_bytes());
+        write_bytes(&mut buf, 14, &path);
+        if !self.namespace().is_empty() {
+            write_bytes(&mut buf, 20, self.namespace().as_bytes());
+        }
+        if !self.database().is_empty() {
+            write_bytes(&mut buf, 23, self.database().as_bytes());
+        }
+        base64::encode_config(&buf, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Read a legacy urlsafe key, see: `encode`. The partition of the app (e.g. `s~`) is removed.
+    pub fn decode(encoded: &str) -> Result<Key, Error> {
+        let buf = base64::decode_config(encoded.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+            .map_err(|err| invalid_key(encoded, &err.to_string()))?;
+        Key::decode_reference(&buf).map_err(|msg| invalid_key(encoded, msg))
+    }
+
+    fn decode_reference(buf: &[u8]) -> Result<Key, &'static str> {
+        let mut key = Key::from_path("", "", vec![]);
+        let mut reader = Reader { buf, pos: 0 };
+        while !reader.is_empty() {
+            match reader.tag()? {
+                (13, WIRE_BYTES) => {
+                    let app = reader.string()?;
+                    key.partition_id.project_id = match app.find('~') {
+                        Some(i) => app[i + 1..].to_string(),
+                        None => app,
+                    };
+                }
+                (14, WIRE_BYTES) => {
+                    let mut path = Reader {
+                        buf: reader.bytes()?,
+                        pos: 0,
+                    };
+                    while !path.is_empty() {
+                        match path.tag()? {
+                            (1, WIRE_START_GROUP) => key.path.push(path.element()?),
+                            (_, wire) => path.skip(wire)?,
+                        }
+                    }
+                }
+                (20, WIRE_BYTES) => key.partition_id.namespace_id = reader.string()?,
+                (23, WIRE_BYTES) => key.partition_id.database_id = reader.string()?,
+                (_, wire) => reader.skip(wire)?,
+            }
+        }
+        if key.project().is_empty() || key.path.is_empty() {
+            return Err("the key has no app or no path");
+        }
+        Ok(key)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path: Vec<String> = self.path.iter().map(PathElement::to_string).collect();
+        write!(
+            f,
+            "project: {}, namespace: {}, path: {}",
+            self.project(),
+            self.namespace(),
+            path.join("/")
+        )?;
+        if !self.database().is_empty() {
+            write!(f, ", database: {}", self.database())?;
+        }
+        Ok(())
+    }
+}
+
+fn invalid_key(encoded: &str, msg: &str) -> Error {
+    Error::with_kind(
+        ErrorKind::InvalidArgument,
+        format!("invalid urlsafe key {:?}: {}", encoded, msg),
+    )
+}
+
+// the wire types of the protocol buffer
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_BYTES: u8 = 2;
+const WIRE_START_GROUP: u8 = 3;
+const WIRE_END_GROUP: u8 = 4;
+const WIRE_FIXED32: u8 = 5;
+
+fn write_tag(buf: &mut Vec<u8>, field: u64, wire: u8) {
+    write_varint(buf, field << 3 | u64::from(wire));
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    write_tag(buf, field, WIRE_BYTES);
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn varint(&mut self) -> Result<u64, &'static str> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self.buf.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            v |= u64::from(b & 0x7f) << shift;
+            if b < 0x80 {
+                return Ok(v);
+            }
+        }
+        Err("invalid varint")
+    }
+
+    fn tag(&mut self) -> Result<(u64, u8), &'static str> {
+        let tag = self.varint()?;
+        Ok((tag >> 3, (tag & 0x07) as u8))
+    }
+
+    fn bytes(&mut self) -> Result<&'b [u8], &'static str> {
+        let len = self.varint()? as usize;
+        let end = self.pos.checked_add(len).ok_or("invalid length")?;
+        let bytes = self.buf.get(self.pos..end).ok_or("truncated bytes")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| "invalid utf-8")
+    }
+
+    fn skip(&mut self, wire: u8) -> Result<(), &'static str> {
+        let len = match wire {
+            WIRE_VARINT => return self.varint().map(|_| ()),
+            WIRE_FIXED64 => 8,
+            WIRE_BYTES => return self.bytes().map(|_| ()),
+            WIRE_FIXED32 => 4,
+            _ => return Err("unsupported wire type"),
+        };
+        self.pos += len;
+        if self.pos > self.buf.len() {
+            return Err("truncated field");
+        }
+        Ok(())
+    }
+
+    // the fields of the group until the end of the group: type = 2, id = 3, name = 4
+    fn element(&mut self) -> Result<PathElement, &'static str> {
+        let mut element = PathElement::incomplete("");
+        loop {
+            match self.tag()? {
+                (1, WIRE_END_GROUP) => break,
+                (2, WIRE_BYTES) => element.kind = self.string()?,
+                (3, WIRE_VARINT) => element.id = Some((self.varint()? as i64).to_string()),
+                (4, WIRE_BYTES) => element.name = Some(self.string()?),
+                (_, wire) => self.skip(wire)?,
+            }
+        }
+        if element.kind.is_empty() {
+            return Err("the path element has no kind");
+        }
+        Ok(element)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_key_builder() {
+        let parent = Key::named("goheros-207118", "heroes", "Hero", "batman");
+        let key = Key::new("goheros-207118", "heroes", "Power", Some(7))
+            .with_parent(&parent)
+            .with_database("db-1");
+
+        assert_eq!("Power", key.kind());
+        assert_eq!(Some(7), key.id());
+        assert_eq!(None, key.name());
+        assert!(key.is_complete());
+        assert_eq!("db-1", key.database());
+        assert_eq!(Some("batman"), key.parent().unwrap().name());
+        assert_eq!(None, parent.parent());
+        assert_eq!(
+            json!({
+                "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes", "databaseId": "db-1"},
+                "path": [{"kind": "Hero", "name": "batman"}, {"kind": "Power", "id": "7"}]
+            }),
+            json!(key)
+        );
+        assert_eq!(
+            r#"project: goheros-207118, namespace: heroes, path: Hero("batman")/Power(7), database: db-1"#,
+            key.to_string()
+        );
+
+        let incomplete = Key::new("goheros-207118", "", "Power", None).with_parent(&parent);
+        assert!(!incomplete.is_complete());
+        assert_eq!(
+            json!([{"kind": "Hero", "name": "batman"}, {"kind": "Power"}]),
+            json!(incomplete.path())
+        );
+    }
+
+    #[test]
+    fn test_deserialize_name_key() {
+        let key: Key = serde_json::from_value(json!({
+            "partitionId": {"projectId": "goheros-207118"},
+            "path": [{"kind": "Hero", "name": "batman"}, {"kind": "Power", "id": "5629499534213120"}]
+        }))
+        .unwrap();
+        assert_eq!(Some(5629499534213120), key.id());
+        assert_eq!("", key.namespace());
+        assert_eq!(Some("batman"), key.path()[0].name());
+    }
+
+    #[test]
+    fn test_urlsafe() {
+        // app "example", path: Kind(1337)
+        let key = Key::decode("agdleGFtcGxlcgsLEgRLaW5kGLkKDA").unwrap();
+        assert_eq!(Key::new("example", "", "Kind", Some(1337)), key);
+        assert_eq!("agdleGFtcGxlcgsLEgRLaW5kGLkKDA", key.encode());
+
+        let key = Key::new("goheros-207118", "heroes", "Power", Some(5629499534213120))
+            .with_parent(&Key::named("goheros-207118", "heroes", "Hero", "batman"))
+            .with_database("db-1");
+        assert_eq!(key, Key::decode(&key.encode()).unwrap());
+        assert_eq!(key, Key::decode(&format!("{}==", key.encode())).unwrap());
+
+        // the partition of the app is removed
+        let mut buf = vec![];
+        write_bytes(&mut buf, 13, b"s~goheros-207118");
+        write_bytes(
+            &mut buf,
+            14,
+            &[0x0b, 0x12, 0x04, b'H', b'e', b'r', b'o', 0x18, 0x2a, 0x0c],
+        );
+        let key = Key::decode(&base64::encode_config(&buf, base64::URL_SAFE_NO_PAD)).unwrap();
+        assert_eq!(Key::new("goheros-207118", "", "Hero", Some(42)), key);
+    }
+
+    #[test]
+    fn test_urlsafe_invalid() {
+        for encoded in &[
+            "not a key!",
+            "",
+            "agdleGFtcGxl",
+            "agdleGFtcGxlcgsLEgRLaW5kGLkK",
+        ] {
+            let err = Key::decode(encoded).unwrap_err();
+            assert_eq!(ErrorKind::InvalidArgument, err.kind(), "{}", encoded);
+        }
+    }
+}
@@ -1,55 +1,68 @@
-use crate::gcloud::{Error, ResponseError};
+use crate::gcloud::auth::Auth;
+use crate::gcloud::http::Client;
+use crate::gcloud::transport::Request;
+use crate::gcloud::Error;
 
 use super::converter::deserialize_lookup_result;
-use super::ReadConsistency;
-use http::StatusCode;
-use reqwest::blocking;
+use super::{Key, ReadOptions};
 use serde::de::DeserializeOwned;
-use serde_json::Value;
+use serde_json::{json, Value};
 
-const LOOKUP_JSON: &'static str = r#"{
-    "readOptions": { "readConsistency": "{readConsistency}" },
-    "keys": [
-      {
-        "partitionId": { "namespaceId": "{namespace}" },
-        "path": [
-          {
-            "kind": "{kind}",
-            "id": "{id}"
-          }
-        ]
-      }
-    ]
-}"#;
-
-fn create_lookup_json(namespace: &str, kind: &str, id: &str) -> String {
-  LOOKUP_JSON
-    .replace("{readConsistency}", ReadConsistency::Eventual.to_string())
-    .replace("{namespace}", namespace)
-    .replace("{kind}", kind)
-    .replace("{id}", id)
-    .replace("\n", "")
+fn create_lookup_json(read_options: &ReadOptions, key: &Key) -> Value {
+  let mut lookup = json!({
+    "readOptions": read_options.to_json(),
+    "keys": [key]
+  });
+  // the database of the request, the key has the same database
+  if !key.database().is_empty() {
+    lookup["databaseId"] = json!(key.database());
+  }
+  lookup
 }
 
 pub fn lookup<D: DeserializeOwned>(
-  client: &blocking::Client,
-  auth_query_str: &str,
+  client: &Client,
+  auth: &dyn Auth,
+  endpoint: &str,
   project: &str,
-  namespace: &str,
-  kind: &str,
-  id: i128,
+  read_options: &ReadOptions,
+  key: &Key,
 ) -> Result<D, Error> {
-  let url = format!(
-    "https://datastore.googleapis.com/v1/projects/{}:lookup?{}",
-    project, auth_query_str
-  );
-  let lookup_json = create_lookup_json(namespace, kind, &id.to_string());
-  let resp = client.post(&url).body(lookup_json).send()?;
+  let url = format!("{}/v1/projects/{}:lookup", endpoint, project);
+  let lookup_json = create_lookup_json(read_options, key);
+  let resp = read_options.send(client, || {
+    let (name, value) = auth.header(&url)?;
+    Request::post(&url).with_header(name, value).with_json(&lookup_json)
+  })?;
+
+  let v = resp.deserialize::<Value>()?;
+  deserialize_lookup_result(&v)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gcloud::datastore::ReadConsistency;
 
-  if resp.status().as_u16() == StatusCode::OK.as_u16() {
-    let v = resp.json::<Value>().unwrap();
-    return Ok(deserialize_lookup_result(&v)?);
-  } else {
-    Err(resp.json::<ResponseError>()?.error)
+  #[test]
+  fn test_create_lookup_json() {
+    let key = Key::new("goheros-207118", "heroes", "Power", Some(7))
+      .with_parent(&Key::named("goheros-207118", "heroes", "Hero", "batman"))
+      .with_database("db-1");
+    assert_eq!(
+      json!({
+        "readOptions": {"readConsistency": "EVENTUAL"},
+        "keys": [{
+          "partitionId": {
+            "projectId": "goheros-207118",
+            "namespaceId": "heroes",
+            "databaseId": "db-1"
+          },
+          "path": [{"kind": "Hero", "name": "batman"}, {"kind": "Power", "id": "7"}]
+        }],
+        "databaseId": "db-1"
+      }),
+      create_lookup_json(&ReadOptions::Consistency(ReadConsistency::Eventual), &key)
+    );
   }
 }
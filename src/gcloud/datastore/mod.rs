@@ -1,66 +1,238 @@
+use crate::gcloud::auth::Auth;
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::http::Client;
+use crate::gcloud::http::RetryPolicy;
+#[cfg(test)]
+use crate::gcloud::transport::Transport;
+use crate::gcloud::transport::{Request, Response};
 use crate::gcloud::Error;
+use crate::logging::{self, trace::latency};
 
 pub mod commit;
 pub mod converter;
+pub mod key;
 pub mod lookup;
 pub mod query;
+pub mod serializer;
+pub mod transaction;
 
+use commit::TransactionMode;
+pub use commit::{CommitMode, CommitResult, Mutation, MutationResult};
+pub use key::{Key, PartitionId};
 use query::Filter;
 
-use reqwest::blocking;
-use reqwest::{self};
+use log::{debug, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fmt;
+use std::time::Instant;
+
+/// The endpoint of the Datastore api.
+pub const DEFAULT_ENDPOINT: &str = "https://datastore.googleapis.com";
 
 pub struct Datastore<'a> {
     project: &'a str,
-    auth_query_str: &'a str,
-    client: blocking::Client,
+    auth: &'a dyn Auth,
+    client: Client,
+    endpoint: String,
+    database: String,
 }
 
 impl<'a> Datastore<'a> {
-    pub fn new(project: &'a str, auth_query_str: &'a str) -> Self {
+    pub fn new(project: &'a str, auth: &'a dyn Auth) -> Self {
         Datastore {
-            project: project,
-            auth_query_str: auth_query_str,
-            client: blocking::Client::new(),
+            project,
+            auth,
+            client: Client::default(),
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            database: String::new(),
         }
     }
 
-    pub fn lookup<D>(&self, namespace: &str, kind: &str, id: i128) -> Result<D, Error>
+    /// The retries of the calls, the default is: `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.client = self.client.with_retry_policy(retry);
+        self
+    }
+
+    /// The transport of the requests, e.g. a `ScriptedTransport` in the tests.
+    #[cfg(test)]
+    pub fn with_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.client = self.client.with_transport(transport);
+        self
+    }
+
+    /// The endpoint without a trailing slash, e.g. of the emulator: `http://localhost:8081`.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// The database of the keys, partitions and transactions, the default database is empty.
+    pub fn with_database(mut self, database: &str) -> Self {
+        self.database = database.to_string();
+        self
+    }
+
+    /// Read the entity of the key, a missing entity is a `NotFound` error.
+    pub fn lookup<D>(&self, key: &Key) -> Result<D, Error>
     where
         D: DeserializeOwned,
     {
-        lookup::lookup(
-            &self.client,
-            &self.auth_query_str,
-            &self.project,
-            namespace,
-            kind,
-            id,
-        )
+        observe("lookup", key.kind(), || {
+            lookup::lookup(
+                &self.client,
+                self.auth,
+                &self.endpoint,
+                self.project,
+                &ReadOptions::Consistency(ReadConsistency::Eventual),
+                key,
+            )
+        })
     }
 
-    pub fn query<D>(&self, namespace: &str, kind: &str, filter: &Filter) -> Result<Vec<D>, Error>
+    /// The entities of the kind in the partition, which match the filter.
+    pub fn query<D>(
+        &self,
+        partition: &PartitionId,
+        kind: &str,
+        filter: &Filter,
+    ) -> Result<Vec<D>, Error>
     where
         D: DeserializeOwned,
     {
-        query::query(
+        observe("query", kind, || {
+            query::query(
+                &self.client,
+                self.auth,
+                &self.endpoint,
+                &ReadOptions::Consistency(ReadConsistency::Eventual),
+                partition,
+                kind,
+                filter,
+            )
+        })
+    }
+
+    /// The partition of the namespace in the project of the datastore.
+    pub fn partition(&self, namespace: &str) -> PartitionId {
+        PartitionId::new(self.project, namespace).with_database(&self.database)
+    }
+
+    /// The key of the kind in the project of the datastore, see: `Key::new`.
+    pub fn key(&self, namespace: &str, kind: &str, id: Option<i64>) -> Key {
+        Key::new(self.project, namespace, kind, id).with_database(&self.database)
+    }
+
+    /// Commit the mutations in one call, a transactional commit begins a transaction.
+    pub fn commit(&self, mode: CommitMode, mutations: &[Mutation]) -> Result<CommitResult, Error> {
+        let kind = mutations
+            .first()
+            .map(|m| m.key().kind())
+            .unwrap_or_default();
+        observe("commit", kind, || {
+            let transaction = match mode {
+                CommitMode::Transactional => Some(commit::transaction(
+                    &self.client,
+                    self.auth,
+                    &self.endpoint,
+                    self.project,
+                    &self.database,
+                    &TransactionMode::ReadWrite {
+                        previous_transaction: None,
+                    },
+                )?),
+                CommitMode::NonTransactional => None,
+            };
+            let committed = commit::commit(
+                &self.client,
+                self.auth,
+                &self.endpoint,
+                self.project,
+                &self.database,
+                transaction.as_deref(),
+                mutations,
+            );
+            if let (Err(err), Some(transaction)) = (&committed, &transaction) {
+                if err.kind() != ErrorKind::Aborted {
+                    self.rollback(transaction);
+                }
+            }
+            committed
+        })
+    }
+
+    // an aborted commit has no transaction to roll back,
+    // after any other error the transaction can still be open
+    fn rollback(&self, transaction: &str) {
+        if let Err(err) = commit::rollback(
             &self.client,
-            &self.auth_query_str,
-            &self.project,
-            namespace,
-            kind,
-            filter,
-        )
+            self.auth,
+            &self.endpoint,
+            self.project,
+            &self.database,
+            transaction,
+        ) {
+            warn!("rollback of the transaction failed: {}", err);
+        }
     }
 
-    pub fn commit(&self, _namespace: &str, _kind: &str) -> Result<String, Error> {
-        commit::commit(&self.client, &self.auth_query_str, &self.project)
+    /// Insert the entity, the result contains the allocated key of an incomplete key.
+    pub fn insert<T: Serialize>(&self, key: Key, entity: &T) -> Result<MutationResult, Error> {
+        self.commit_one(Mutation::insert(key, entity)?)
+    }
+
+    pub fn update<T: Serialize>(&self, key: Key, entity: &T) -> Result<MutationResult, Error> {
+        self.commit_one(Mutation::update(key, entity)?)
+    }
+
+    pub fn upsert<T: Serialize>(&self, key: Key, entity: &T) -> Result<MutationResult, Error> {
+        self.commit_one(Mutation::upsert(key, entity)?)
+    }
+
+    pub fn delete(&self, key: Key) -> Result<MutationResult, Error> {
+        self.commit_one(Mutation::delete(key))
+    }
+
+    fn commit_one(&self, mutation: Mutation) -> Result<MutationResult, Error> {
+        let result = self.commit(CommitMode::NonTransactional, &[mutation])?;
+        result.mutation_results.into_iter().next().ok_or_else(|| {
+            Error::with_kind(
+                ErrorKind::Decode,
+                "the commit has no mutation result".to_string(),
+            )
+        })
     }
 }
 
-enum ReadConsistency {
+// log the operation with the kind and the latency, in the json format they are fields of the entry
+fn observe<T, F>(operation: &str, kind: &str, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    let start = Instant::now();
+    let result = f();
+    let latency = latency(start.elapsed());
+
+    logging::with_fields(
+        vec![
+            ("operation", json!(operation)),
+            ("kind", json!(kind)),
+            ("latency", json!(latency)),
+        ],
+        || match &result {
+            Ok(_) => debug!("datastore {} {}: ok ({})", operation, kind, latency),
+            Err(err) => warn!(
+                "datastore {} {}: {} ({}, {})",
+                operation, kind, err.message, err.code, latency
+            ),
+        },
+    );
+    result
+}
+
+pub enum ReadConsistency {
     ReadConsistencyUnspecidied,
     Strong,
     Eventual,
@@ -85,65 +257,90 @@ impl ReadConsistency {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct Entity {
-    key: Key,
+/// The read options of a lookup or a query: the consistency or the transaction of the read.
+pub enum ReadOptions<'t> {
+    Consistency(ReadConsistency),
+    Transaction(&'t str),
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct Key {
-    #[serde(rename(deserialize = "partitionId"))]
-    partition_id: PartitionId,
-    path: Vec<Path>,
-}
+impl ReadOptions<'_> {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            ReadOptions::Consistency(consistency) => {
+                json!({ "readConsistency": consistency.to_string() })
+            }
+            ReadOptions::Transaction(transaction) => json!({ "transaction": transaction }),
+        }
+    }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PartitionId {
-    #[serde(rename(deserialize = "projectId"))]
-    project_id: String,
-    #[serde(rename(deserialize = "namespaceId"))]
-    namespace_id: String,
+    // an aborted read in a transaction is not retried with the same transaction,
+    // `run_in_transaction` retries the transaction as a whole
+    fn send<F>(&self, client: &Client, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Result<Request, Error>,
+    {
+        match self {
+            ReadOptions::Consistency(_) => client.send(request),
+            ReadOptions::Transaction(_) => client.send_without_aborted(request),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
-pub struct Path {
-    kind: String,
-    id: String,
+pub struct Entity {
+    key: Key,
 }
 
-impl Entity {
-    fn to_string(self) -> String {
-        format!(
-            "project: {} namespace {} kind: {}, id: {}",
-            self.key.partition_id.project_id,
-            self.key.partition_id.namespace_id,
-            self.key.path.get(0).unwrap().kind,
-            self.key.path.get(0).unwrap().id
-        )
+impl fmt::Display for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.key)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::authentication::Claim;
-    use crate::gcloud::auth::{ApiKey, Auth, JwtToken};
+    use crate::gcloud::auth::ApiKey;
+    use crate::gcloud::testutil::ScriptedTransport;
+    use crate::gcloud::transport::Response;
     use http::StatusCode;
     use serde::{Deserialize, Serialize};
+    use serde_json::Value;
+    use std::sync::Arc;
 
     #[derive(Deserialize, Serialize, Debug)]
     struct NotUsed {}
 
+    // the datastore with the scripted responses, the transport records the requests
+    fn datastore<'a>(
+        auth: &'a dyn Auth,
+        responses: Vec<Response>,
+    ) -> (Datastore<'a>, Arc<ScriptedTransport>) {
+        let transport = Arc::new(ScriptedTransport::new(responses));
+        let datastore = Datastore::new("goheros-207118", auth)
+            .with_transport(Box::new(Arc::clone(&transport)))
+            .with_retry_policy(RetryPolicy::none());
+        (datastore, transport)
+    }
+
     #[test]
     fn datastore_lookup_error_unauthorized_401() {
         let a = ApiKey::new("invalid-auth-key");
-        let q = a.to_url_query();
-        let s = Datastore::new("project-not-exist", &q);
-        let r: Result<NotUsed, Error> = s.lookup("ns", "kind", 42);
-        match r {
-            Err(e) => assert_eq!(StatusCode::UNAUTHORIZED.as_u16(), e.code),
-            Ok(_) => (),
-        }
+        let (s, _) = datastore(
+            &a,
+            vec![Response::json(
+                StatusCode::UNAUTHORIZED,
+                &json!({"error": {
+                    "code": 401,
+                    "message": "Request had invalid authentication credentials.",
+                    "status": "UNAUTHENTICATED"
+                }}),
+            )],
+        );
+        let r: Result<NotUsed, Error> = s.lookup(&s.key("ns", "kind", Some(42)));
+        let err = r.unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED.as_u16(), err.code);
+        assert_eq!("UNAUTHENTICATED", err.status);
     }
 
     #[derive(Deserialize, Serialize, Debug)]
@@ -158,27 +355,247 @@ mod tests {
         time: String,
     }
 
+    fn key(id: &str) -> Value {
+        json!({
+            "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+            "path": [{"kind": "Protocol", "id": id}]
+        })
+    }
+
     #[test]
     fn datastore_lookup_found() {
-        let a = JwtToken::from_env_private_key(Claim::new()).unwrap();
-        let q = a.to_url_query();
-        let s = Datastore::new("goheros-207118", &q);
-        let r: Result<Hero, Error> = s.lookup("heroes", "Protocol", 5066702320566272);
+        let a = ApiKey::new("my-key");
+        let (s, transport) = datastore(
+            &a,
+            vec![Response::json(
+                StatusCode::OK,
+                &json!({
+                    "found": [{
+                        "entity": {
+                            "key": key("5066702320566272"),
+                            "properties": {
+                                "HeroID": {"integerValue": "2"},
+                                "Note": {"stringValue": "hero found"},
+                                "Action": {"stringValue": "GetByID"},
+                                "Time": {"timestampValue": "2019-01-05T10:51:35.771Z"}
+                            }
+                        },
+                        "version": "1"
+                    }],
+                    "readTime": "2020-04-01T10:00:00Z"
+                }),
+            )],
+        );
+        let r: Result<Hero, Error> = s.lookup(&s.key("heroes", "Protocol", Some(5066702320566272)));
         assert!(r.is_ok());
         let hero: Hero = r.unwrap();
         assert_eq!(2, hero.hero_id);
         assert_eq!("GetByID", hero.action);
+
+        let requests = transport.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            "https://datastore.googleapis.com/v1/projects/goheros-207118:lookup",
+            requests[0].url
+        );
+        assert_eq!(Some("my-key"), requests[0].header("x-goog-api-key"));
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(key("5066702320566272")["path"], body["keys"][0]["path"]);
+        assert_eq!("heroes", body["keys"][0]["partitionId"]["namespaceId"]);
     }
 
     #[test]
     fn datastore_lookup_missing() {
-        let a = JwtToken::from_env_private_key(Claim::new()).unwrap();
-        let q = a.to_url_query();
-        let s = Datastore::new("goheros-207118", &q);
-        let r: Result<Hero, Error> = s.lookup("heroes", "Protocol", 42);
+        let a = ApiKey::new("my-key");
+        let (s, _) = datastore(
+            &a,
+            vec![Response::json(
+                StatusCode::OK,
+                &json!({"missing": [{"entity": {"key": key("42")}, "version": "7"}]}),
+            )],
+        );
+        let r: Result<Hero, Error> = s.lookup(&s.key("heroes", "Protocol", Some(42)));
         assert!(r.is_err());
         let err: Error = r.unwrap_err();
         assert_eq!(404, err.code);
-        assert_eq!("Not Found", err.status);
+        assert_eq!("NOT_FOUND", err.status);
+    }
+
+    #[test]
+    fn datastore_query_with_endpoint() {
+        let a = ApiKey::new("my-key");
+        let (s, transport) = datastore(
+            &a,
+            vec![Response::json(
+                StatusCode::OK,
+                &json!({"batch": {
+                    "entityResultType": "FULL",
+                    "entityResults": [{"entity": {
+                        "key": key("1"),
+                        "properties": {
+                            "HeroID": {"integerValue": "1"},
+                            "Note": {"stringValue": "first"},
+                            "Action": {"stringValue": "List"},
+                            "Time": {"timestampValue": "2019-01-05T10:51:35.771Z"}
+                        }
+                    }}],
+                    "moreResults": "NO_MORE_RESULTS"
+                }}),
+            )],
+        );
+        let s = s.with_endpoint("http://localhost:8081/");
+        let filter = Filter {
+            property: "Action",
+            op: query::Operator::Equal,
+            value: query::Value::String(String::from("List")),
+        };
+        let heroes: Vec<Hero> = s
+            .query(&s.partition("heroes"), "Protocol", &filter)
+            .unwrap();
+        assert_eq!(1, heroes.len());
+        assert_eq!("first", heroes[0].note);
+
+        let requests = transport.requests();
+        assert_eq!(
+            "http://localhost:8081/v1/projects/goheros-207118:runQuery",
+            requests[0].url
+        );
+        let body: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(
+            json!({"projectId": "goheros-207118", "namespaceId": "heroes"}),
+            body["partitionId"]
+        );
+        assert_eq!(json!([{"name": "Protocol"}]), body["query"]["kind"]);
+    }
+
+    #[test]
+    fn datastore_commit_transactional() {
+        let a = ApiKey::new("my-key");
+        let transport = Arc::new(ScriptedTransport::new(vec![
+            Response::json(
+                StatusCode::SERVICE_UNAVAILABLE,
+                &json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}}),
+            ),
+            Response::json(StatusCode::OK, &json!({"transaction": "tx-1"})),
+            Response::json(
+                StatusCode::OK,
+                &json!({"mutationResults": [{"version": "2"}], "indexUpdates": 3}),
+            ),
+        ]));
+        let s = Datastore::new("goheros-207118", &a)
+            .with_transport(Box::new(Arc::clone(&transport)))
+            .with_retry_policy(
+                RetryPolicy::default().with_initial_backoff(std::time::Duration::from_millis(1)),
+            );
+
+        let delete = Mutation::delete(s.key("heroes", "Protocol", Some(42)));
+        let result = s.commit(CommitMode::Transactional, &[delete]).unwrap();
+        assert_eq!(3, result.index_updates);
+        assert_eq!("2", result.mutation_results[0].version);
+
+        // the beginTransaction is retried
+        let requests = transport.requests();
+        assert_eq!(3, requests.len());
+        assert!(requests[2].url.ends_with("goheros-207118:commit"));
+        let body: Value = serde_json::from_str(&requests[2].body).unwrap();
+        assert_eq!("TRANSACTIONAL", body["mode"]);
+        assert_eq!("tx-1", body["transaction"]);
+    }
+
+    #[test]
+    fn datastore_commit_transactional_rollback() {
+        let a = ApiKey::new("my-key");
+        let invalid = Response::json(
+            StatusCode::BAD_REQUEST,
+            &json!({"error": {"code": 400, "message": "invalid key", "status": "INVALID_ARGUMENT"}}),
+        );
+        let aborted = Response::json(
+            StatusCode::CONFLICT,
+            &json!({"error": {"code": 409, "message": "too much contention", "status": "ABORTED"}}),
+        );
+        let (s, transport) = datastore(
+            &a,
+            vec![
+                Response::json(StatusCode::OK, &json!({"transaction": "tx-1"})),
+                invalid,
+                Response::json(StatusCode::OK, &json!({})),
+                Response::json(StatusCode::OK, &json!({"transaction": "tx-2"})),
+                aborted,
+            ],
+        );
+        let delete = || Mutation::delete(s.key("heroes", "Protocol", Some(42)));
+
+        // the transaction of a failed commit is rolled back
+        let err = s
+            .commit(CommitMode::Transactional, &[delete()])
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidArgument, err.kind());
+        let requests = transport.requests();
+        assert_eq!(3, requests.len());
+        assert!(requests[2].url.ends_with("goheros-207118:rollback"));
+        let body: Value = serde_json::from_str(&requests[2].body).unwrap();
+        assert_eq!("tx-1", body["transaction"]);
+
+        // an aborted commit has no transaction to roll back
+        let err = s
+            .commit(CommitMode::Transactional, &[delete()])
+            .unwrap_err();
+        assert_eq!(ErrorKind::Aborted, err.kind());
+        assert_eq!(5, transport.requests().len());
+    }
+
+    #[test]
+    fn datastore_insert() {
+        let a = ApiKey::new("my-key");
+        let (s, transport) = datastore(
+            &a,
+            vec![Response::json(
+                StatusCode::OK,
+                &json!({"mutationResults": [{"key": key("5629499534213120"), "version": "1"}]}),
+            )],
+        );
+
+        let hero = json!({"HeroID": 2, "Note": "new", "Action": "Insert", "Time": "now"});
+        let result = s.insert(s.key("heroes", "Protocol", None), &hero).unwrap();
+        assert_eq!(Some(5629499534213120), result.key.unwrap().id());
+
+        let body: Value = serde_json::from_str(&transport.requests()[0].body).unwrap();
+        assert_eq!("NON_TRANSACTIONAL", body["mode"]);
+        assert_eq!(
+            json!({"integerValue": "2"}),
+            body["mutations"][0]["insert"]["properties"]["HeroID"]
+        );
+    }
+
+    #[test]
+    fn datastore_insert_without_retry() {
+        let a = ApiKey::new("my-key");
+        let (s, transport) = datastore(
+            &a,
+            vec![
+                Response::json(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    &json!({"error": {"code": 503, "message": "unavailable", "status": "UNAVAILABLE"}}),
+                ),
+                Response::json(
+                    StatusCode::OK,
+                    &json!({"mutationResults": [{"version": "1"}]}),
+                ),
+            ],
+        );
+        let s = s.with_retry_policy(
+            RetryPolicy::default().with_initial_backoff(std::time::Duration::from_millis(1)),
+        );
+
+        // the insert of a retry could write the entity twice
+        let hero = json!({"HeroID": 2, "Note": "new", "Action": "Insert", "Time": "now"});
+        let err = s
+            .insert(s.key("heroes", "Protocol", None), &hero)
+            .unwrap_err();
+        assert_eq!(ErrorKind::Unavailable, err.kind());
+
+        let requests = transport.requests();
+        assert_eq!(1, requests.len());
+        assert!(requests[0].url.ends_with("goheros-207118:commit"));
     }
 }
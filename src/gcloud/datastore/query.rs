@@ -1,19 +1,16 @@
-use crate::gcloud::{Error, ResponseError};
+use crate::gcloud::auth::Auth;
+use crate::gcloud::http::Client;
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::transport::Request;
+use crate::gcloud::Error;
 
 use super::converter::deserialize_query_result;
-use super::ReadConsistency;
-use http::StatusCode;
-use reqwest::blocking;
+use super::key::PartitionId;
+use super::{Key, ReadOptions};
 use serde::de::DeserializeOwned;
-use serde_json::Value as JsonValue;
+use serde_json::{json, Map, Number, Value as JsonValue};
 
-const QUERY_JSON: &'static str = r#"{
-    "partitionId": { "namespaceId": "{namespace}" },
-    "readOptions": { "readConsistency": "{readConsistency}" },
-    "query": { "kind": { "name": "{kind}"},
-    {filter}
-    }
-}"#;
+use std::collections::BTreeMap;
 
 #[allow(dead_code)]
 pub enum Operator {
@@ -27,98 +24,306 @@ pub enum Operator {
 }
 
 impl Operator {
-  #[allow(dead_code)]
-  fn to_json(&self) -> String {
+  fn to_json(&self) -> JsonValue {
     match self {
-      Operator::OperatorUnspecified => r#""op":"OPERATOR_UNSPECIFIED""#.to_string(),
-      Operator::LessThan => r#""op":"LESS_THAN""#.to_string(),
-      Operator::LessThanOrEqual => r#""op":"LESS_THAN_OR_EQUAL""#.to_string(),
-      Operator::GreaterThan => r#""op":"GREATER_THAN""#.to_string(),
-      Operator::GreaterThanOrEqual => r#""op":"GREATER_THAN_OR_EQUAL""#.to_string(),
-      Operator::Equal => r#""op":"EQUAL""#.to_string(),
-      Operator::HasAncestor => r#""op":"HAS_ANCESTOR""#.to_string(),
+      Operator::OperatorUnspecified => json!("OPERATOR_UNSPECIFIED"),
+      Operator::LessThan => json!("LESS_THAN"),
+      Operator::LessThanOrEqual => json!("LESS_THAN_OR_EQUAL"),
+      Operator::GreaterThan => json!("GREATER_THAN"),
+      Operator::GreaterThanOrEqual => json!("GREATER_THAN_OR_EQUAL"),
+      Operator::Equal => json!("EQUAL"),
+      Operator::HasAncestor => json!("HAS_ANCESTOR"),
     }
   }
 }
 
-#[allow(dead_code)]
+// https://cloud.google.com/datastore/docs/reference/data/rest/v1/projects/runQuery#Value
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
   Null,
   Bool(bool),
   String(String),
-  Integer(isize),
+  Integer(i64),
   Double(f64),
+  /// RFC 3339, e.g. `2018-07-27T20:13:20Z`.
+  Timestamp(String),
+  Key(Key),
+  Blob(Vec<u8>),
+  GeoPoint { latitude: f64, longitude: f64 },
+  Array(Vec<Value>),
+  /// An embedded entity, the key is optional.
+  Entity {
+    key: Option<Key>,
+    properties: BTreeMap<String, Value>,
+  },
+  /// A value with the options of a property, e.g. a long string without index.
+  WithOptions {
+    value: Box<Value>,
+    exclude_from_indexes: bool,
+    meaning: Option<i32>,
+  },
 }
 
 impl Value {
-  #[allow(dead_code)]
-  fn to_json(&self) -> String {
+  /// The value is not indexed, e.g. a string with more than 1500 bytes.
+  pub fn exclude_from_indexes(self) -> Value {
+    match self {
+      Value::WithOptions { value, meaning, .. } => {
+        Value::WithOptions { value, exclude_from_indexes: true, meaning }
+      }
+      value => Value::WithOptions {
+        value: Box::new(value),
+        exclude_from_indexes: true,
+        meaning: None,
+      },
+    }
+  }
+
+  /// The meaning of the value, it is kept for the legacy clients.
+  pub fn with_meaning(self, meaning: i32) -> Value {
+    match self {
+      Value::WithOptions { value, exclude_from_indexes, .. } => {
+        Value::WithOptions { value, exclude_from_indexes, meaning: Some(meaning) }
+      }
+      value => Value::WithOptions {
+        value: Box::new(value),
+        exclude_from_indexes: false,
+        meaning: Some(meaning),
+      },
+    }
+  }
+
+  /// The Datastore format, e.g. `{"integerValue": "42"}`.
+  pub fn to_datastore(&self) -> JsonValue {
+    match self {
+      Value::Null => json!({ "nullValue": null }),
+      Value::Bool(v) => json!({ "booleanValue": v }),
+      Value::String(v) => json!({ "stringValue": v }),
+      // int64 is a string in the json of the api
+      Value::Integer(v) => json!({ "integerValue": v.to_string() }),
+      Value::Double(v) => json!({ "doubleValue": double_to_json(*v) }),
+      Value::Timestamp(v) => json!({ "timestampValue": v }),
+      Value::Key(v) => json!({ "keyValue": v }),
+      Value::Blob(v) => json!({ "blobValue": base64::encode(v) }),
+      Value::GeoPoint { latitude, longitude } => {
+        json!({ "geoPointValue": { "latitude": latitude, "longitude": longitude } })
+      }
+      Value::Array(values) => {
+        let values: Vec<JsonValue> = values.iter().map(Value::to_datastore).collect();
+        json!({ "arrayValue": { "values": values } })
+      }
+      Value::Entity { key, properties } => {
+        let properties: Map<String, JsonValue> =
+          properties.iter().map(|(name, v)| (name.clone(), v.to_datastore())).collect();
+        let mut entity = json!({ "properties": properties });
+        if let Some(key) = key {
+          entity["key"] = json!(key);
+        }
+        json!({ "entityValue": entity })
+      }
+      Value::WithOptions { value, exclude_from_indexes, meaning } => {
+        let mut v = value.to_datastore();
+        if *exclude_from_indexes {
+          v["excludeFromIndexes"] = json!(true);
+        }
+        if let Some(meaning) = meaning {
+          v["meaning"] = json!(meaning);
+        }
+        v
+      }
+    }
+  }
+
+  /// Read the Datastore format, see: `to_datastore`.
+  pub fn from_datastore(v: &JsonValue) -> Result<Value, Error> {
+    let map = v.as_object().ok_or_else(|| decode("a value must be an object", v))?;
+    let exclude_from_indexes = map
+      .get("excludeFromIndexes")
+      .and_then(JsonValue::as_bool)
+      .unwrap_or(false);
+    let meaning = map.get("meaning").and_then(JsonValue::as_i64).map(|m| m as i32);
+    let (datatype, val) = map
+      .iter()
+      .find(|(datatype, _)| *datatype != "excludeFromIndexes" && *datatype != "meaning")
+      .ok_or_else(|| decode("the value has no datatype", v))?;
+
+    let value = match datatype.as_str() {
+      "nullValue" => Value::Null,
+      "booleanValue" => Value::Bool(val.as_bool().ok_or_else(|| decode("invalid boolean", val))?),
+      "stringValue" => Value::String(as_str(val)?.to_string()),
+      "integerValue" => {
+        let v = match val {
+          JsonValue::String(s) => s.parse().ok(),
+          _ => val.as_i64(),
+        };
+        Value::Integer(v.ok_or_else(|| decode("invalid integer", val))?)
+      }
+      "doubleValue" => Value::Double(double_from_json(val)?),
+      "timestampValue" => Value::Timestamp(as_str(val)?.to_string()),
+      "keyValue" => Value::Key(serde_json::from_value(val.clone())?),
+      "blobValue" => {
+        let blob = base64::decode(as_str(val)?)
+          .or_else(|_| base64::decode_config(as_str(val).unwrap_or_default(), base64::URL_SAFE))
+          .map_err(|_| decode("invalid base64 blob", val))?;
+        Value::Blob(blob)
+      }
+      "geoPointValue" => Value::GeoPoint {
+        latitude: val.get("latitude").map_or(Ok(0.0), double_from_json)?,
+        longitude: val.get("longitude").map_or(Ok(0.0), double_from_json)?,
+      },
+      // an empty array has no values
+      "arrayValue" => match val.get("values").and_then(JsonValue::as_array) {
+        Some(values) => {
+          Value::Array(values.iter().map(Value::from_datastore).collect::<Result<_, _>>()?)
+        }
+        None => Value::Array(vec![]),
+      },
+      "entityValue" => {
+        let key = match val.get("key") {
+          Some(key) => Some(serde_json::from_value(key.clone())?),
+          None => None,
+        };
+        let mut properties = BTreeMap::new();
+        if let Some(props) = val.get("properties").and_then(JsonValue::as_object) {
+          for (name, v) in props {
+            properties.insert(name.clone(), Value::from_datastore(v)?);
+          }
+        }
+        Value::Entity { key, properties }
+      }
+      _ => return Err(decode("unknown datatype", v)),
+    };
+
+    if exclude_from_indexes || meaning.is_some() {
+      return Ok(Value::WithOptions { value: Box::new(value), exclude_from_indexes, meaning });
+    }
+    Ok(value)
+  }
+
+  /// The plain json of the value, e.g. to deserialize a struct: `{"integerValue": "42"}` -> `42`.
+  /// A blob is a base64 string, a geo point an object with `latitude` and `longitude`.
+  pub fn to_plain_json(&self) -> JsonValue {
     match self {
-      Value::Null => r#""value":{"nullValue":null}"#.to_string(),
-      Value::Bool(v) => r#""value":{"booleanValue":"{value}"}"#.replace("{value}", &v.to_string()),
-      Value::String(v) => r#""value":{"stringValue":"{value}"}"#.replace("{value}", &v),
-      Value::Integer(v) => r#""value":{"integerValue":{value}}"#.replace("{value}", &v.to_string()),
-      Value::Double(v) => r#""value":{"doubleValue":{value}}"#.replace("{value}", &v.to_string()),
+      Value::Null => JsonValue::Null,
+      Value::Bool(v) => json!(v),
+      Value::String(v) | Value::Timestamp(v) => json!(v),
+      Value::Integer(v) => json!(v),
+      Value::Double(v) => double_to_json(*v),
+      Value::Key(v) => json!(v),
+      Value::Blob(v) => json!(base64::encode(v)),
+      Value::GeoPoint { latitude, longitude } => {
+        json!({ "latitude": latitude, "longitude": longitude })
+      }
+      Value::Array(values) => JsonValue::Array(values.iter().map(Value::to_plain_json).collect()),
+      Value::Entity { properties, .. } => JsonValue::Object(
+        properties.iter().map(|(name, v)| (name.clone(), v.to_plain_json())).collect(),
+      ),
+      Value::WithOptions { value, .. } => value.to_plain_json(),
     }
   }
 }
 
+// NaN and Infinity are strings in the json of the api
+fn double_to_json(v: f64) -> JsonValue {
+  match Number::from_f64(v) {
+    Some(n) => JsonValue::Number(n),
+    None if v.is_nan() => json!("NaN"),
+    None if v > 0.0 => json!("Infinity"),
+    None => json!("-Infinity"),
+  }
+}
+
+fn double_from_json(v: &JsonValue) -> Result<f64, Error> {
+  match v {
+    JsonValue::String(s) => match s.as_str() {
+      "NaN" => Ok(f64::NAN),
+      "Infinity" => Ok(f64::INFINITY),
+      "-Infinity" => Ok(f64::NEG_INFINITY),
+      _ => s.parse().map_err(|_| decode("invalid double", v)),
+    },
+    _ => v.as_f64().ok_or_else(|| decode("invalid double", v)),
+  }
+}
+
+fn as_str(v: &JsonValue) -> Result<&str, Error> {
+  v.as_str().ok_or_else(|| decode("expect a string", v))
+}
+
+fn decode(message: &str, v: &JsonValue) -> Error {
+  Error::with_kind(ErrorKind::Decode, format!("{}: {}", message, v))
+}
+
 pub struct Filter<'a> {
   pub property: &'a str,
   pub op: Operator,
   pub value: Value,
 }
 
-#[allow(dead_code)]
 impl<'a> Filter<'a> {
-  pub fn to_json(&self) -> String {
-    format!(
-      "{} {}, {} {}",
-      r#""filter":{"propertyFilter":{"property":{"name":"{property}"},"#
-        .replace("{property}", self.property),
-      self.op.to_json(),
-      self.value.to_json(),
-      "} }",
-    )
+  /// The entities with the ancestor, e.g. the powers of a hero.
+  pub fn has_ancestor(key: Key) -> Self {
+    Filter {
+      property: "__key__",
+      op: Operator::HasAncestor,
+      value: Value::Key(key),
+    }
+  }
+
+  pub fn to_json(&self) -> JsonValue {
+    json!({
+      "propertyFilter": {
+        "property": { "name": self.property },
+        "op": self.op.to_json(),
+        "value": self.value.to_datastore()
+      }
+    })
   }
 }
 
-fn create_query_json(namespace: &str, kind: &str, filter: &str) -> String {
-  QUERY_JSON
-    .replace("{readConsistency}", ReadConsistency::Eventual.to_string())
-    .replace("{namespace}", namespace)
-    .replace("{kind}", kind)
-    .replace("{filter}", filter)
-    .replace("\n", "")
+fn create_query_json(
+  read_options: &ReadOptions,
+  partition: &PartitionId,
+  kind: &str,
+  filter: &Filter,
+) -> JsonValue {
+  let mut query = json!({
+    "partitionId": partition,
+    "readOptions": read_options.to_json(),
+    "query": {
+      "kind": [{ "name": kind }],
+      "filter": filter.to_json()
+    }
+  });
+  // the database of the request, the partition has the same database
+  if !partition.database().is_empty() {
+    query["databaseId"] = json!(partition.database());
+  }
+  query
 }
 
 pub fn query<D: DeserializeOwned>(
-  client: &blocking::Client,
-  auth_query_str: &str,
-  project: &str,
-  namespace: &str,
+  client: &Client,
+  auth: &dyn Auth,
+  endpoint: &str,
+  read_options: &ReadOptions,
+  partition: &PartitionId,
   kind: &str,
   filter: &Filter,
 ) -> Result<Vec<D>, Error> {
-  let url = format!(
-    "https://datastore.googleapis.com/v1/projects/{}:runQuery?{}",
-    project, auth_query_str
-  );
-  let lookup_json = create_query_json(namespace, kind, &filter.to_json());
-  let resp = client.post(&url).body(lookup_json).send()?;
-
-  if resp.status().as_u16() == StatusCode::OK.as_u16() {
-    let v = resp.json::<JsonValue>().unwrap();
-    return Ok(deserialize_query_result(&v)?);
-  } else {
-    Err(resp.json::<ResponseError>()?.error)
-  }
+  let url = format!("{}/v1/projects/{}:runQuery", endpoint, partition.project());
+  let query_json = create_query_json(read_options, partition, kind, filter);
+  let resp = read_options.send(client, || {
+    let (name, value) = auth.header(&url)?;
+    Request::post(&url).with_header(name, value).with_json(&query_json)
+  })?;
+
+  let v = resp.deserialize::<JsonValue>()?;
+  deserialize_query_result(&v)
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use serde_json::Value as JsonValue;
 
   #[test]
   fn test_filter() {
@@ -127,61 +332,175 @@ mod tests {
       op: Operator::Equal,
       value: Value::String(String::from("List")),
     };
-    let json_str = format!("{} {} {}", "{", f.to_json(), "}");
-    let r: JsonValue = serde_json::from_str(&json_str).unwrap();
-    r.get("Filter");
+    assert_eq!(
+      json!({"propertyFilter": {
+        "property": {"name": "Action"},
+        "op": "EQUAL",
+        "value": {"stringValue": "List"}
+      }}),
+      f.to_json()
+    );
   }
 
   #[test]
-  fn test_operator() {
-    assert_eq!(
-      r#""op":"OPERATOR_UNSPECIFIED""#.to_string(),
-      Operator::OperatorUnspecified.to_json()
-    );
+  fn test_filter_has_ancestor() {
+    let f = Filter::has_ancestor(Key::named("goheros-207118", "heroes", "Hero", "batman"));
     assert_eq!(
-      r#""op":"LESS_THAN""#.to_string(),
-      Operator::LessThan.to_json()
-    );
-    assert_eq!(
-      r#""op":"LESS_THAN_OR_EQUAL""#.to_string(),
-      Operator::LessThanOrEqual.to_json()
+      json!({"propertyFilter": {
+        "property": {"name": "__key__"},
+        "op": "HAS_ANCESTOR",
+        "value": {"keyValue": {
+          "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+          "path": [{"kind": "Hero", "name": "batman"}]
+        }}
+      }}),
+      f.to_json()
     );
+  }
+
+  #[test]
+  fn test_create_query_json() {
+    let partition = PartitionId::new("goheros-207118", "he\"roes").with_database("db-1");
+    let f = Filter {
+      property: "Action",
+      op: Operator::Equal,
+      value: Value::String(String::from("say \"hi\"")),
+    };
     assert_eq!(
-      r#""op":"GREATER_THAN""#.to_string(),
-      Operator::GreaterThan.to_json()
+      json!({
+        "partitionId": {
+          "projectId": "goheros-207118",
+          "namespaceId": "he\"roes",
+          "databaseId": "db-1"
+        },
+        "readOptions": {"transaction": "tx-1"},
+        "query": {
+          "kind": [{"name": "Protocol"}],
+          "filter": {"propertyFilter": {
+            "property": {"name": "Action"},
+            "op": "EQUAL",
+            "value": {"stringValue": "say \"hi\""}
+          }}
+        },
+        "databaseId": "db-1"
+      }),
+      create_query_json(&ReadOptions::Transaction("tx-1"), &partition, "Protocol", &f)
     );
+  }
+
+  #[test]
+  fn test_operator() {
+    assert_eq!(json!("OPERATOR_UNSPECIFIED"), Operator::OperatorUnspecified.to_json());
+    assert_eq!(json!("LESS_THAN"), Operator::LessThan.to_json());
+    assert_eq!(json!("LESS_THAN_OR_EQUAL"), Operator::LessThanOrEqual.to_json());
+    assert_eq!(json!("GREATER_THAN"), Operator::GreaterThan.to_json());
+    assert_eq!(json!("GREATER_THAN_OR_EQUAL"), Operator::GreaterThanOrEqual.to_json());
+    assert_eq!(json!("EQUAL"), Operator::Equal.to_json());
+    assert_eq!(json!("HAS_ANCESTOR"), Operator::HasAncestor.to_json());
+  }
+
+  #[test]
+  fn test_value() {
+    assert_eq!(json!({"nullValue": null}), Value::Null.to_datastore());
+    assert_eq!(json!({"booleanValue": true}), Value::Bool(true).to_datastore());
     assert_eq!(
-      r#""op":"GREATER_THAN_OR_EQUAL""#.to_string(),
-      Operator::GreaterThanOrEqual.to_json()
+      json!({"stringValue": "Foo"}),
+      Value::String(String::from("Foo")).to_datastore()
     );
-    assert_eq!(r#""op":"EQUAL""#.to_string(), Operator::Equal.to_json());
+    assert_eq!(json!({"integerValue": "42"}), Value::Integer(42).to_datastore());
+    assert_eq!(json!({"doubleValue": 4.2}), Value::Double(4.2).to_datastore());
     assert_eq!(
-      r#""op":"HAS_ANCESTOR""#.to_string(),
-      Operator::HasAncestor.to_json()
+      r#"{"stringValue":"say \"hi\""}"#.to_string(),
+      Value::String(String::from("say \"hi\"")).to_datastore().to_string()
     );
   }
 
+  // the examples of:
+  // https://cloud.google.com/datastore/docs/reference/data/rest/v1/projects/runQuery#Value
   #[test]
-  fn test_value() {
+  fn test_value_round_trip() {
+    let values = vec![
+      json!({"nullValue": null}),
+      json!({"booleanValue": true}),
+      json!({"integerValue": "-9223372036854775808"}),
+      json!({"doubleValue": 4.2}),
+      json!({"doubleValue": "NaN"}),
+      json!({"doubleValue": "-Infinity"}),
+      json!({"timestampValue": "2014-10-02T15:01:23.045123456Z"}),
+      json!({"keyValue": {
+        "partitionId": {"projectId": "goheros-207118", "namespaceId": "heroes"},
+        "path": [{"kind": "Hero", "id": "5629499534213120"}]
+      }}),
+      json!({"stringValue": "Foo", "excludeFromIndexes": true}),
+      json!({"blobValue": "aGVsbG8gd29ybGQ=", "meaning": 16}),
+      json!({"geoPointValue": {"latitude": 52.52, "longitude": 13.405}}),
+      json!({"arrayValue": {"values": [{"integerValue": "1"}, {"stringValue": "two"}]}}),
+      json!({"entityValue": {
+        "key": {
+          "partitionId": {"projectId": "goheros-207118", "namespaceId": ""},
+          "path": [{"kind": "Power"}]
+        },
+        "properties": {
+          "Name": {"stringValue": "Fly"},
+          "Level": {"entityValue": {"properties": {"Max": {"doubleValue": 9.5}}}},
+          "Tags": {"arrayValue": {"values": [{"booleanValue": false}]}, "excludeFromIndexes": true}
+        }
+      }}),
+    ];
+    for v in values {
+      assert_eq!(v, Value::from_datastore(&v).unwrap().to_datastore(), "{}", v);
+    }
+
     assert_eq!(
-      r#""value":{"nullValue":null}"#.to_string(),
-      Value::Null.to_json()
+      Value::Blob(b"hello world".to_vec()).with_meaning(16),
+      Value::from_datastore(&json!({"blobValue": "aGVsbG8gd29ybGQ=", "meaning": 16})).unwrap()
     );
     assert_eq!(
-      r#""value":{"booleanValue":"true"}"#.to_string(),
-      Value::Bool(true).to_json()
+      Value::String(String::from("Foo")).exclude_from_indexes(),
+      Value::from_datastore(&json!({"stringValue": "Foo", "excludeFromIndexes": true})).unwrap()
     );
     assert_eq!(
-      r#""value":{"stringValue":"Foo"}"#.to_string(),
-      Value::String(String::from("Foo")).to_json()
+      Value::Array(vec![]),
+      Value::from_datastore(&json!({"arrayValue": {}})).unwrap()
     );
     assert_eq!(
-      r#""value":{"integerValue":42}"#.to_string(),
-      Value::Integer(42).to_json()
+      Value::Integer(42),
+      Value::from_datastore(&json!({"integerValue": 42})).unwrap()
     );
+  }
+
+  #[test]
+  fn test_value_invalid() {
+    for v in &[
+      json!("Foo"),
+      json!({}),
+      json!({"excludeFromIndexes": true}),
+      json!({"integerValue": "4.2"}),
+      json!({"booleanValue": "true"}),
+      json!({"blobValue": "not base64!"}),
+      json!({"unknownValue": 1}),
+    ] {
+      let err = Value::from_datastore(v).unwrap_err();
+      assert_eq!(ErrorKind::Decode, err.kind(), "{}", v);
+    }
+  }
+
+  #[test]
+  fn test_value_to_plain_json() {
+    let v = json!({"entityValue": {"properties": {
+      "Blob": {"blobValue": "aGVsbG8gd29ybGQ="},
+      "Place": {"geoPointValue": {"latitude": 52.52, "longitude": 13.405}},
+      "Count": {"integerValue": "42", "excludeFromIndexes": true},
+      "List": {"arrayValue": {"values": [{"doubleValue": 1.5}, {"nullValue": null}]}}
+    }}});
     assert_eq!(
-      r#""value":{"doubleValue":4.2}"#.to_string(),
-      Value::Double(4.2).to_json()
+      json!({
+        "Blob": "aGVsbG8gd29ybGQ=",
+        "Place": {"latitude": 52.52, "longitude": 13.405},
+        "Count": 42,
+        "List": [1.5, null]
+      }),
+      Value::from_datastore(&v).unwrap().to_plain_json()
     );
   }
 }
@@ -0,0 +1,862 @@
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::Error;
+
+use super::query;
+
+use serde::ser::{self, Serialize};
+use serde_json::{json, Map, Value};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The properties of an entity, the value must be a struct or a map:
+///
+/// ```ignore
+/// #[derive(Serialize)]
+/// struct Hero {
+///     #[serde(rename = "HeroID")]
+///     hero_id: isize,
+/// }
+/// // {"HeroID": {"integerValue": "2"}}
+/// let properties = serializer::to_properties(&Hero { hero_id: 2 })?;
+/// ```
+///
+/// A date time must be annotated with `#[serde(with = "serializer::timestamp")]`
+/// (or `serializer::timestamp::naive` for a `NaiveDateTime`) to be a `timestampValue`,
+/// without it the date time is a `stringValue`, which is not ordered as time in a query.
+/// A `query::Value` is written as it is, e.g. a long string with `exclude_from_indexes`.
+pub fn to_properties<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    match to_value(value)? {
+        Value::Object(mut v) if v.contains_key("entityValue") => Ok(v
+            .remove("entityValue")
+            .and_then(|mut entity| entity.get_mut("properties").map(Value::take))
+            .unwrap_or_else(|| json!({}))),
+        v => Err(invalid(format!(
+            "an entity must be a struct or a map, not: {}",
+            v
+        ))),
+    }
+}
+
+/// The Datastore value, e.g. `{"integerValue": "42"}`.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(ValueSerializer)
+}
+
+/// The name of the newtype, which is serialized as `timestampValue`, see: `timestamp`.
+const TIMESTAMP: &str = "$datastore::Timestamp";
+
+/// A `chrono::DateTime` (e.g. `DateTime<Utc>` or `DateTime<FixedOffset>`) as `timestampValue`
+/// in UTC, other serializers (e.g. json) get the RFC 3339 string:
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Hero {
+///     #[serde(rename = "Time", with = "serializer::timestamp")]
+///     time: DateTime<Utc>,
+///     #[serde(rename = "Created", with = "serializer::timestamp::naive")]
+///     created: NaiveDateTime,
+/// }
+/// ```
+pub mod timestamp {
+    use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S, Tz>(v: &DateTime<Tz>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        Tz: TimeZone,
+    {
+        s.serialize_newtype_struct(
+            super::TIMESTAMP,
+            &v.with_timezone(&Utc)
+                .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        )
+    }
+
+    /// The offset of a `DateTime<FixedOffset>` is the offset of the stored string,
+    /// Datastore returns the time in UTC.
+    pub fn deserialize<'de, D, Tz>(d: D) -> Result<DateTime<Tz>, D::Error>
+    where
+        D: Deserializer<'de>,
+        Tz: TimeZone,
+        DateTime<Tz>: Deserialize<'de>,
+    {
+        DateTime::deserialize(d)
+    }
+
+    /// A `chrono::NaiveDateTime` as `timestampValue`, the naive date time is in UTC.
+    pub mod naive {
+        use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+        use serde::{Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(v: &NaiveDateTime, s: S) -> Result<S::Ok, S::Error> {
+            super::serialize(&Utc.from_utc_datetime(v), s)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<NaiveDateTime, D::Error> {
+            super::deserialize(d).map(|v: DateTime<Utc>| v.naive_utc())
+        }
+    }
+}
+
+/// The name of the newtype, which is a `query::Value` in the Datastore format.
+const DATASTORE_VALUE: &str = "$datastore::Value";
+
+/// A `query::Value` is a property with the options of the value, other serializers (e.g. json)
+/// get the Datastore format:
+///
+/// ```ignore
+/// #[derive(Serialize)]
+/// struct Hero {
+///     // query::Value::String(note).exclude_from_indexes()
+///     #[serde(rename = "Note")]
+///     note: query::Value,
+/// }
+/// ```
+impl Serialize for query::Value {
+    fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_newtype_struct(DATASTORE_VALUE, &self.to_datastore())
+    }
+}
+
+fn typed(datatype: &str, v: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(datatype.to_string(), v);
+    Value::Object(map)
+}
+
+fn integer(v: i64) -> Value {
+    query::Value::Integer(v).to_datastore()
+}
+
+fn double(v: f64) -> Value {
+    query::Value::Double(v).to_datastore()
+}
+
+fn string(v: &str) -> Value {
+    query::Value::String(v.to_string()).to_datastore()
+}
+
+fn array(values: Vec<Value>) -> Result<Value, Error> {
+    if values.iter().any(|v| v.get("arrayValue").is_some()) {
+        return Err(invalid("an array can not contain an array"));
+    }
+    Ok(typed("arrayValue", json!({ "values": values })))
+}
+
+fn entity(properties: Map<String, Value>) -> Value {
+    typed("entityValue", json!({ "properties": properties }))
+}
+
+// an enum variant with data is an entity with the variant as property: {"Circle": {...}}
+fn variant(name: &str, v: Value) -> Value {
+    let mut properties = Map::new();
+    properties.insert(name.to_string(), v);
+    entity(properties)
+}
+
+fn invalid<T: fmt::Display>(msg: T) -> Error {
+    Error::with_kind(ErrorKind::InvalidArgument, msg.to_string())
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        invalid(msg)
+    }
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+    type SerializeMap = SerializeEntity;
+    type SerializeStruct = SerializeEntity;
+    type SerializeStructVariant = SerializeVariant<SerializeEntity>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(query::Value::Bool(v).to_datastore())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(integer(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value, Error> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(integer(v)),
+            Err(_) => Err(invalid(format!("the integer {} is not a i64", v))),
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(integer(i64::from(v)))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        self.serialize_i128(i128::from(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value, Error> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(integer(v)),
+            Err(_) => Err(invalid(format!("the integer {} is not a i64", v))),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        Ok(double(f64::from(v)))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(string(&v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(string(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(query::Value::Blob(v.to_vec()).to_datastore())
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(query::Value::Null.to_datastore())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(string(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        if name == DATASTORE_VALUE {
+            // the value is in the Datastore format already
+            return serde_json::to_value(value).map_err(invalid);
+        }
+        if name != TIMESTAMP {
+            return value.serialize(self);
+        }
+        let v = value.serialize(self)?;
+        match v.get("stringValue").and_then(Value::as_str) {
+            Some(v) => Ok(query::Value::Timestamp(v.to_string()).to_datastore()),
+            None => Err(invalid("a timestamp must be a string")),
+        }
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(self::variant(variant, to_value(value)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray, Error> {
+        Ok(SerializeArray {
+            values: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeArray>, Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_seq(Some(len))?,
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeEntity, Error> {
+        Ok(SerializeEntity {
+            properties: Map::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeEntity, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeEntity>, Error> {
+        Ok(SerializeVariant {
+            variant,
+            inner: self.serialize_map(Some(len))?,
+        })
+    }
+}
+
+struct SerializeArray {
+    values: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.values.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        array(self.values)
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeEntity {
+    properties: Map<String, Value>,
+    next_key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeEntity {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.next_key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| invalid("a value without a key"))?;
+        self.properties.insert(key, to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(entity(self.properties))
+    }
+}
+
+impl ser::SerializeStruct for SerializeEntity {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.properties.insert(key.to_string(), to_value(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(entity(self.properties))
+    }
+}
+
+struct SerializeVariant<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeArray> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(variant(self.variant, ser::SerializeSeq::end(self.inner)?))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeEntity> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(variant(
+            self.variant,
+            ser::SerializeStruct::end(self.inner)?,
+        ))
+    }
+}
+
+// the name of a property is a string, a number (e.g. of a HashMap<u32, _>) is converted
+struct KeySerializer;
+
+impl KeySerializer {
+    fn invalid(kind: &str) -> Error {
+        invalid(format!("the name of a property can not be a {}", kind))
+    }
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = Error;
+
+    type SerializeSeq = ser::Impossible<String, Error>;
+    type SerializeTuple = ser::Impossible<String, Error>;
+    type SerializeTupleStruct = ser::Impossible<String, Error>;
+    type SerializeTupleVariant = ser::Impossible<String, Error>;
+    type SerializeMap = ser::Impossible<String, Error>;
+    type SerializeStruct = ser::Impossible<String, Error>;
+    type SerializeStructVariant = ser::Impossible<String, Error>;
+
+    fn serialize_bool(self, v: bool) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_f32(self, _v: f32) -> Result<String, Error> {
+        Err(KeySerializer::invalid("float"))
+    }
+
+    fn serialize_f64(self, _v: f64) -> Result<String, Error> {
+        Err(KeySerializer::invalid("float"))
+    }
+
+    fn serialize_char(self, v: char) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<String, Error> {
+        Ok(v.to_string())
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String, Error> {
+        Err(KeySerializer::invalid("byte array"))
+    }
+
+    fn serialize_none(self) -> Result<String, Error> {
+        Err(KeySerializer::invalid("none"))
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String, Error> {
+        Err(KeySerializer::invalid("unit"))
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, Error> {
+        Err(KeySerializer::invalid("unit"))
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<String, Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<String, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<String, Error> {
+        Err(KeySerializer::invalid("enum"))
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Err(KeySerializer::invalid("sequence"))
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Error> {
+        Err(KeySerializer::invalid("tuple"))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        Err(KeySerializer::invalid("tuple"))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Err(KeySerializer::invalid("enum"))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Err(KeySerializer::invalid("map"))
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        Err(KeySerializer::invalid("struct"))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Err(KeySerializer::invalid("enum"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::datastore::converter::to_object;
+    use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone, Utc};
+    use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Hero {
+        #[serde(rename = "HeroID")]
+        hero_id: isize,
+        #[serde(rename = "Note")]
+        note: String,
+        #[serde(rename = "Action")]
+        action: String,
+        #[serde(rename = "Time", with = "timestamp")]
+        time: DateTime<Utc>,
+    }
+
+    #[test]
+    fn test_hero_round_trip() {
+        let hero = Hero {
+            hero_id: 2,
+            note: "hero found".to_string(),
+            action: "GetByID".to_string(),
+            time: Utc.with_ymd_and_hms(2019, 1, 5, 10, 51, 35).unwrap()
+                + chrono::Duration::milliseconds(771),
+        };
+        let properties = to_properties(&hero).unwrap();
+        assert_eq!(
+            json!({
+                "HeroID": {"integerValue": "2"},
+                "Note": {"stringValue": "hero found"},
+                "Action": {"stringValue": "GetByID"},
+                "Time": {"timestampValue": "2019-01-05T10:51:35.771Z"}
+            }),
+            properties
+        );
+
+        let read: Hero = serde_json::from_value(to_object(&properties).unwrap()).unwrap();
+        assert_eq!(hero, read);
+    }
+
+    #[derive(Serialize)]
+    struct Review {
+        text: query::Value,
+        ratings: Vec<query::Value>,
+    }
+
+    #[test]
+    fn test_query_value_with_options() {
+        let review = Review {
+            text: query::Value::String("long text".to_string()).exclude_from_indexes(),
+            ratings: vec![query::Value::Integer(5).with_meaning(22)],
+        };
+        assert_eq!(
+            json!({
+                "text": {"stringValue": "long text", "excludeFromIndexes": true},
+                "ratings": {"arrayValue": {"values": [{"integerValue": "5", "meaning": 22}]}}
+            }),
+            to_properties(&review).unwrap()
+        );
+    }
+
+    #[derive(Serialize)]
+    enum Shape {
+        Point,
+        Circle(f64),
+        Rect { width: u32, height: u32 },
+    }
+
+    #[derive(Serialize)]
+    struct Entity {
+        score: f64,
+        tags: Vec<&'static str>,
+        note: Option<String>,
+        address: BTreeMap<&'static str, &'static str>,
+        shapes: Vec<Shape>,
+        #[serde(with = "serde_bytes_as_slice")]
+        blob: Vec<u8>,
+        #[serde(with = "timestamp::naive")]
+        created: NaiveDateTime,
+    }
+
+    mod serde_bytes_as_slice {
+        pub fn serialize<S: serde::Serializer>(v: &[u8], s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(v)
+        }
+    }
+
+    #[test]
+    fn test_to_properties() {
+        let entity = Entity {
+            score: 1.5,
+            tags: vec!["a"],
+            note: None,
+            address: vec![("City", "Bern")].into_iter().collect(),
+            shapes: vec![
+                Shape::Point,
+                Shape::Circle(2.0),
+                Shape::Rect {
+                    width: 1,
+                    height: 2,
+                },
+            ],
+            blob: b"rust".to_vec(),
+            created: chrono::NaiveDate::from_ymd_opt(2020, 4, 1)
+                .and_then(|date| date.and_hms_opt(10, 0, 0))
+                .unwrap(),
+        };
+
+        assert_eq!(
+            json!({
+                "score": {"doubleValue": 1.5},
+                "tags": {"arrayValue": {"values": [{"stringValue": "a"}]}},
+                "note": {"nullValue": null},
+                "address": {"entityValue": {"properties": {"City": {"stringValue": "Bern"}}}},
+                "shapes": {"arrayValue": {"values": [
+                    {"stringValue": "Point"},
+                    {"entityValue": {"properties": {"Circle": {"doubleValue": 2.0}}}},
+                    {"entityValue": {"properties": {"Rect": {"entityValue": {"properties": {
+                        "width": {"integerValue": "1"},
+                        "height": {"integerValue": "2"}
+                    }}}}}}
+                ]}},
+                "blob": {"blobValue": "cnVzdA=="},
+                "created": {"timestampValue": "2020-04-01T10:00:00Z"}
+            }),
+            to_properties(&entity).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_string_is_not_a_timestamp() {
+        assert_eq!(
+            json!({"stringValue": "2019-01-05T10:51:35Z"}),
+            to_value("2019-01-05T10:51:35Z").unwrap()
+        );
+
+        // the json of the helper is the plain string
+        #[derive(Serialize)]
+        struct Event {
+            #[serde(with = "timestamp")]
+            at: DateTime<Utc>,
+        }
+        let event = Event {
+            at: Utc.with_ymd_and_hms(2019, 1, 5, 10, 51, 35).unwrap(),
+        };
+        assert_eq!(
+            json!({"at": {"timestampValue": "2019-01-05T10:51:35Z"}}),
+            to_properties(&event).unwrap()
+        );
+        assert_eq!(
+            json!({"at": "2019-01-05T10:51:35Z"}),
+            serde_json::to_value(&event).unwrap()
+        );
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Appointment {
+        #[serde(with = "timestamp")]
+        at: DateTime<FixedOffset>,
+        #[serde(with = "timestamp::naive")]
+        created: NaiveDateTime,
+    }
+
+    #[test]
+    fn test_fixed_offset_and_naive_timestamps() {
+        let at = FixedOffset::east_opt(2 * 3600)
+            .and_then(|offset| offset.with_ymd_and_hms(2020, 4, 1, 12, 0, 0).single())
+            .unwrap();
+        let appointment = Appointment {
+            at,
+            created: at.naive_utc(),
+        };
+        let properties = to_properties(&appointment).unwrap();
+        assert_eq!(
+            json!({
+                "at": {"timestampValue": "2020-04-01T10:00:00Z"},
+                "created": {"timestampValue": "2020-04-01T10:00:00Z"}
+            }),
+            properties
+        );
+
+        // the same time in UTC
+        let read: Appointment = serde_json::from_value(to_object(&properties).unwrap()).unwrap();
+        assert_eq!(appointment, read);
+        assert_eq!(0, read.at.offset().local_minus_utc());
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert!(to_properties(&42).is_err());
+        assert!(to_value(&vec![vec![1]]).is_err());
+        assert!(to_value(&u64::MAX).is_err());
+        assert_eq!(json!({"doubleValue": "NaN"}), to_value(&f64::NAN).unwrap());
+
+        let mut map = BTreeMap::new();
+        map.insert(vec![1], 1);
+        assert!(to_value(&map).is_err());
+    }
+}
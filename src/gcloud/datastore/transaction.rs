@@ -0,0 +1,429 @@
+use crate::gcloud::error::ErrorKind;
+use crate::gcloud::http::RetryPolicy;
+use crate::gcloud::Error;
+
+use super::commit::{self, Mutation, TransactionMode};
+use super::query::Filter;
+use super::{lookup, observe, query, Datastore, Key, PartitionId, ReadOptions};
+
+use log::debug;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::cell::RefCell;
+use std::thread;
+
+/// The options of `Datastore::run_in_transaction_with`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransactionOptions {
+    read_only: bool,
+    retry: RetryPolicy,
+}
+
+impl TransactionOptions {
+    /// A read-only transaction reads a consistent snapshot, it can not write.
+    pub fn read_only() -> Self {
+        TransactionOptions {
+            read_only: true,
+            ..TransactionOptions::default()
+        }
+    }
+
+    /// The retries of a transaction, which is aborted by a concurrent transaction.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+}
+
+/// Transaction reads with the transaction and collects the writes until the commit,
+/// see: `Datastore::run_in_transaction`.
+pub struct Transaction<'d, 'a> {
+    datastore: &'d Datastore<'a>,
+    id: String,
+    read_only: bool,
+    mutations: RefCell<Vec<Mutation>>,
+}
+
+impl<'d, 'a> Transaction<'d, 'a> {
+    pub fn lookup<D>(&self, key: &Key) -> Result<D, Error>
+    where
+        D: DeserializeOwned,
+    {
+        let ds = self.datastore;
+        observe("lookup", key.kind(), || {
+            lookup::lookup(
+                &ds.client,
+                ds.auth,
+                &ds.endpoint,
+                ds.project,
+                &ReadOptions::Transaction(&self.id),
+                key,
+            )
+        })
+    }
+
+    pub fn query<D>(
+        &self,
+        partition: &PartitionId,
+        kind: &str,
+        filter: &Filter,
+    ) -> Result<Vec<D>, Error>
+    where
+        D: DeserializeOwned,
+    {
+        let ds = self.datastore;
+        observe("query", kind, || {
+            query::query(
+                &ds.client,
+                ds.auth,
+                &ds.endpoint,
+                &ReadOptions::Transaction(&self.id),
+                partition,
+                kind,
+                filter,
+            )
+        })
+    }
+
+    /// Insert or update the entity with the commit.
+    pub fn put<T: Serialize>(&self, key: Key, entity: &T) -> Result<(), Error> {
+        self.mutate(Mutation::upsert(key, entity)?)
+    }
+
+    /// Delete the entity with the commit.
+    pub fn delete(&self, key: Key) -> Result<(), Error> {
+        self.mutate(Mutation::delete(key))
+    }
+
+    /// Any mutation with the commit, e.g. an insert, which fails for an existing entity.
+    pub fn mutate(&self, mutation: Mutation) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::with_kind(
+                ErrorKind::InvalidArgument,
+                format!(
+                    "the read-only transaction can not write: {:?}",
+                    mutation.key()
+                ),
+            ));
+        }
+        self.mutations.borrow_mut().push(mutation);
+        Ok(())
+    }
+}
+
+impl<'a> Datastore<'a> {
+    /// Run the closure in a read-write transaction, see: `run_in_transaction_with`.
+    pub fn run_in_transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnMut(&Transaction) -> Result<T, Error>,
+    {
+        self.run_in_transaction_with(TransactionOptions::default(), f)
+    }
+
+    /// Run the closure in a transaction: the writes are committed after the closure,
+    /// after an error the transaction is rolled back. An aborted transaction
+    /// (by a concurrent transaction) is retried with the closure.
+    pub fn run_in_transaction_with<T, F>(
+        &self,
+        options: TransactionOptions,
+        mut f: F,
+    ) -> Result<T, Error>
+    where
+        F: FnMut(&Transaction) -> Result<T, Error>,
+    {
+        let mut previous: Option<String> = None;
+        let mut attempt = 1;
+        loop {
+            let mode = if options.read_only {
+                TransactionMode::ReadOnly
+            } else {
+                TransactionMode::ReadWrite {
+                    previous_transaction: previous.as_deref(),
+                }
+            };
+            let id = commit::transaction(
+                &self.client,
+                self.auth,
+                &self.endpoint,
+                self.project,
+                &self.database,
+                &mode,
+            )?;
+            let tx = Transaction {
+                datastore: self,
+                id,
+                read_only: options.read_only,
+                mutations: RefCell::new(vec![]),
+            };
+
+            let (err, rollback) = match f(&tx) {
+                Ok(value) => {
+                    let mutations = tx.mutations.into_inner();
+                    let kind = mutations
+                        .first()
+                        .map(|m| m.key().kind())
+                        .unwrap_or_default();
+                    let id = tx.id.as_str();
+                    let committed = observe("commit", kind, || {
+                        commit::commit(
+                            &self.client,
+                            self.auth,
+                            &self.endpoint,
+                            self.project,
+                            &self.database,
+                            Some(id),
+                            &mutations,
+                        )
+                    });
+                    match committed {
+                        Ok(_) => return Ok(value),
+                        Err(err) => {
+                            let rollback = err.kind() != ErrorKind::Aborted;
+                            (err, rollback)
+                        }
+                    }
+                }
+                Err(err) => (err, true),
+            };
+            if rollback {
+                self.rollback(&tx.id);
+            }
+
+            if err.kind() != ErrorKind::Aborted || attempt >= options.retry.max_attempts() {
+                return Err(err);
+            }
+            let delay = options.retry.delay(attempt);
+            debug!(
+                "transaction attempt {} is aborted, retry in {:?}: {}",
+                attempt, delay, err
+            );
+            thread::sleep(delay);
+            previous = Some(tx.id);
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::auth::ApiKey;
+    use crate::gcloud::testutil::ScriptedTransport;
+    use crate::gcloud::transport::{Request, Response};
+    use http::StatusCode;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn ok(body: Value) -> Response {
+        Response::json(StatusCode::OK, &body)
+    }
+
+    fn aborted() -> Response {
+        Response::json(
+            StatusCode::CONFLICT,
+            &json!({"error": {"code": 409, "message": "too much contention", "status": "ABORTED"}}),
+        )
+    }
+
+    fn found(counter: i64) -> Response {
+        ok(json!({"found": [{"entity": {
+            "key": {"partitionId": {"projectId": "p"}, "path": [{"kind": "Counter", "id": "1"}]},
+            "properties": {"Count": {"integerValue": counter.to_string()}}
+        }}]}))
+    }
+
+    fn body(request: &Request) -> Value {
+        serde_json::from_str(&request.body).unwrap()
+    }
+
+    fn run(
+        responses: Vec<Response>,
+        options: TransactionOptions,
+    ) -> (Result<i64, Error>, Vec<Request>) {
+        let a = ApiKey::new("my-key");
+        let transport = Arc::new(ScriptedTransport::new(responses));
+        let s = Datastore::new("p", &a).with_transport(Box::new(Arc::clone(&transport)));
+
+        let result = s.run_in_transaction_with(
+            options.with_retry_policy(
+                RetryPolicy::default().with_initial_backoff(Duration::from_millis(1)),
+            ),
+            |tx| {
+                let counter: Value = tx.lookup(&s.key("", "Counter", Some(1)))?;
+                let count = counter["Count"].as_i64().unwrap() + 1;
+                tx.put(s.key("", "Counter", Some(1)), &json!({ "Count": count }))?;
+                Ok(count)
+            },
+        );
+        (result, transport.requests())
+    }
+
+    #[test]
+    fn test_commit() {
+        let (result, requests) = run(
+            vec![
+                ok(json!({"transaction": "tx-1"})),
+                found(41),
+                ok(json!({"mutationResults": [{"version": "2"}]})),
+            ],
+            TransactionOptions::default(),
+        );
+        assert_eq!(42, result.unwrap());
+
+        assert_eq!(
+            json!({"readWrite": {}}),
+            body(&requests[0])["transactionOptions"]
+        );
+        assert_eq!(
+            json!({"transaction": "tx-1"}),
+            body(&requests[1])["readOptions"]
+        );
+        let commit = body(&requests[2]);
+        assert_eq!("tx-1", commit["transaction"]);
+        assert_eq!(
+            json!({"integerValue": "42"}),
+            commit["mutations"][0]["upsert"]["properties"]["Count"]
+        );
+    }
+
+    #[test]
+    fn test_retry_aborted_with_previous_transaction() {
+        let (result, requests) = run(
+            vec![
+                ok(json!({"transaction": "tx-1"})),
+                found(41),
+                aborted(),
+                ok(json!({"transaction": "tx-2"})),
+                found(42),
+                ok(json!({"mutationResults": [{"version": "3"}]})),
+            ],
+            TransactionOptions::default(),
+        );
+        assert_eq!(43, result.unwrap());
+
+        assert_eq!(6, requests.len());
+        assert_eq!(
+            json!({"readWrite": {"previousTransaction": "tx-1"}}),
+            body(&requests[3])["transactionOptions"]
+        );
+        assert_eq!("tx-2", body(&requests[5])["transaction"]);
+    }
+
+    #[test]
+    fn test_aborted_read_retries_the_transaction() {
+        let (result, requests) = run(
+            vec![
+                ok(json!({"transaction": "tx-1"})),
+                aborted(),
+                ok(json!({})),
+                ok(json!({"transaction": "tx-2"})),
+                found(41),
+                ok(json!({"mutationResults": [{"version": "2"}]})),
+            ],
+            TransactionOptions::default(),
+        );
+        assert_eq!(42, result.unwrap());
+
+        // the read is not sent again with the aborted transaction
+        assert_eq!(6, requests.len());
+        assert!(requests[2].url.ends_with("p:rollback"));
+        assert_eq!(
+            json!({"transaction": "tx-2"}),
+            body(&requests[4])["readOptions"]
+        );
+    }
+
+    #[test]
+    fn test_rollback_after_commit_error() {
+        let (result, requests) = run(
+            vec![
+                ok(json!({"transaction": "tx-1"})),
+                found(41),
+                Response::json(
+                    StatusCode::BAD_REQUEST,
+                    &json!({"error": {"code": 400, "message": "invalid", "status": "INVALID_ARGUMENT"}}),
+                ),
+                ok(json!({})),
+            ],
+            TransactionOptions::default(),
+        );
+        assert_eq!(ErrorKind::InvalidArgument, result.unwrap_err().kind());
+
+        assert_eq!(4, requests.len());
+        assert!(requests[3].url.ends_with("p:rollback"));
+        assert_eq!(json!({"transaction": "tx-1"}), body(&requests[3]));
+    }
+
+    #[test]
+    fn test_rollback_after_error() {
+        let (result, requests) = run(
+            vec![
+                ok(json!({"transaction": "tx-1"})),
+                Response::json(
+                    StatusCode::FORBIDDEN,
+                    &json!({"error": {"code": 403, "message": "denied", "status": "PERMISSION_DENIED"}}),
+                ),
+                ok(json!({})),
+            ],
+            TransactionOptions::default(),
+        );
+        assert_eq!(ErrorKind::PermissionDenied, result.unwrap_err().kind());
+
+        assert_eq!(3, requests.len());
+        assert!(requests[2].url.ends_with("p:rollback"));
+        assert_eq!(json!({"transaction": "tx-1"}), body(&requests[2]));
+    }
+
+    #[test]
+    fn test_database() {
+        let a = ApiKey::new("my-key");
+        let transport = Arc::new(ScriptedTransport::new(vec![
+            ok(json!({"transaction": "tx-1"})),
+            ok(json!({"mutationResults": [{"version": "2"}]})),
+            ok(json!({"transaction": "tx-2"})),
+            ok(json!({})),
+        ]));
+        let s = Datastore::new("p", &a)
+            .with_database("db-1")
+            .with_transport(Box::new(Arc::clone(&transport)));
+
+        s.run_in_transaction(|tx| tx.put(s.key("", "Counter", Some(1)), &json!({"Count": 1})))
+            .unwrap();
+        let err = s
+            .run_in_transaction::<(), _>(|_| {
+                Err(Error::with_kind(ErrorKind::Decode, "invalid".to_string()))
+            })
+            .unwrap_err();
+        assert_eq!(ErrorKind::Decode, err.kind());
+
+        let requests = transport.requests();
+        assert_eq!(4, requests.len());
+        assert!(requests[0].url.ends_with("p:beginTransaction"));
+        assert!(requests[1].url.ends_with("p:commit"));
+        assert!(requests[3].url.ends_with("p:rollback"));
+        for request in &requests {
+            assert_eq!("db-1", body(request)["databaseId"]);
+        }
+        assert_eq!(
+            "db-1",
+            body(&requests[1])["mutations"][0]["upsert"]["key"]["partitionId"]["databaseId"]
+        );
+    }
+
+    #[test]
+    fn test_read_only() {
+        let (result, requests) = run(
+            vec![ok(json!({"transaction": "tx-1"})), found(41), ok(json!({}))],
+            TransactionOptions::read_only(),
+        );
+        assert_eq!(ErrorKind::InvalidArgument, result.unwrap_err().kind());
+
+        assert_eq!(
+            json!({"readOnly": {}}),
+            body(&requests[0])["transactionOptions"]
+        );
+        assert!(requests[2].url.ends_with("p:rollback"));
+    }
+}
@@ -0,0 +1,557 @@
+use http::StatusCode;
+use reqwest::blocking;
+use reqwest::Url;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+// replacement for credentials in Debug outputs
+pub const REDACTED: &str = "<redacted>";
+
+const TYPE_BAD_REQUEST: &str = "type.googleapis.com/google.rpc.BadRequest";
+const TYPE_RETRY_INFO: &str = "type.googleapis.com/google.rpc.RetryInfo";
+const TYPE_ERROR_INFO: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+/// The kind of the error, it is the canonical status of google (`google.rpc.Code`)
+/// or a problem of the request (`Transport`) or of the response (`Decode`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidArgument,
+    Unauthenticated,
+    PermissionDenied,
+    NotFound,
+    AlreadyExists,
+    /// A concurrency conflict, e.g. of a Datastore transaction.
+    Aborted,
+    /// The quota is exceeded (429).
+    ResourceExhausted,
+    Unavailable,
+    DeadlineExceeded,
+    Internal,
+    /// The request failed without a response, e.g. the connection was refused.
+    Transport,
+    /// The response (or a value of it) could not be read.
+    Decode,
+    Unknown,
+}
+
+impl ErrorKind {
+    // https://cloud.google.com/apis/design/errors#handling_errors
+    fn from_status(status: &str) -> Option<ErrorKind> {
+        let kind = match status {
+            "INVALID_ARGUMENT" | "FAILED_PRECONDITION" | "OUT_OF_RANGE" => {
+                ErrorKind::InvalidArgument
+            }
+            "UNAUTHENTICATED" => ErrorKind::Unauthenticated,
+            "PERMISSION_DENIED" => ErrorKind::PermissionDenied,
+            "NOT_FOUND" => ErrorKind::NotFound,
+            "ALREADY_EXISTS" => ErrorKind::AlreadyExists,
+            "ABORTED" => ErrorKind::Aborted,
+            "RESOURCE_EXHAUSTED" => ErrorKind::ResourceExhausted,
+            "UNAVAILABLE" => ErrorKind::Unavailable,
+            "DEADLINE_EXCEEDED" => ErrorKind::DeadlineExceeded,
+            "INTERNAL" | "DATA_LOSS" => ErrorKind::Internal,
+            "UNKNOWN" => ErrorKind::Unknown,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    fn from_code(code: u16) -> ErrorKind {
+        match code {
+            400 => ErrorKind::InvalidArgument,
+            401 => ErrorKind::Unauthenticated,
+            403 => ErrorKind::PermissionDenied,
+            404 => ErrorKind::NotFound,
+            // 409 is ABORTED or ALREADY_EXISTS, only the status tells them apart
+            429 => ErrorKind::ResourceExhausted,
+            500 => ErrorKind::Internal,
+            502 | 503 => ErrorKind::Unavailable,
+            504 => ErrorKind::DeadlineExceeded,
+            _ => ErrorKind::Unknown,
+        }
+    }
+
+    /// The canonical status of google, e.g. `NOT_FOUND`.
+    pub fn status(self) -> &'static str {
+        match self {
+            ErrorKind::InvalidArgument => "INVALID_ARGUMENT",
+            ErrorKind::Unauthenticated => "UNAUTHENTICATED",
+            ErrorKind::PermissionDenied => "PERMISSION_DENIED",
+            ErrorKind::NotFound => "NOT_FOUND",
+            ErrorKind::AlreadyExists => "ALREADY_EXISTS",
+            ErrorKind::Aborted => "ABORTED",
+            ErrorKind::ResourceExhausted => "RESOURCE_EXHAUSTED",
+            ErrorKind::Unavailable | ErrorKind::Transport => "UNAVAILABLE",
+            ErrorKind::DeadlineExceeded => "DEADLINE_EXCEEDED",
+            ErrorKind::Internal | ErrorKind::Decode => "INTERNAL",
+            ErrorKind::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// A field of the request, which is invalid.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub description: String,
+}
+
+/// The details of the error response (`google.rpc.Status`).
+///
+/// https://cloud.google.com/apis/design/errors#error_details
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorDetail {
+    BadRequest {
+        field_violations: Vec<FieldViolation>,
+    },
+    /// The client should wait the delay before the retry.
+    RetryInfo { retry_delay: Duration },
+    ErrorInfo {
+        reason: String,
+        domain: String,
+        metadata: HashMap<String, String>,
+    },
+    /// A detail of another type, e.g. `google.rpc.QuotaFailure`.
+    Other(Value),
+}
+
+impl ErrorDetail {
+    fn from_value(v: Value) -> ErrorDetail {
+        let string = |v: &Value, name: &str| {
+            v.get(name)
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        match v.get("@type").and_then(Value::as_str) {
+            Some(TYPE_BAD_REQUEST) => ErrorDetail::BadRequest {
+                field_violations: v
+                    .get("fieldViolations")
+                    .and_then(Value::as_array)
+                    .map(|violations| {
+                        violations
+                            .iter()
+                            .map(|fv| FieldViolation {
+                                field: string(fv, "field"),
+                                description: string(fv, "description"),
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            },
+            Some(TYPE_RETRY_INFO) => match v.get("retryDelay").and_then(parse_duration) {
+                Some(retry_delay) => ErrorDetail::RetryInfo { retry_delay },
+                None => ErrorDetail::Other(v),
+            },
+            Some(TYPE_ERROR_INFO) => ErrorDetail::ErrorInfo {
+                reason: string(&v, "reason"),
+                domain: string(&v, "domain"),
+                metadata: v
+                    .get("metadata")
+                    .and_then(Value::as_object)
+                    .map(|metadata| {
+                        metadata
+                            .iter()
+                            .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            },
+            _ => ErrorDetail::Other(v),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        match self {
+            ErrorDetail::BadRequest { field_violations } => json!({
+                "@type": TYPE_BAD_REQUEST,
+                "fieldViolations": field_violations
+                    .iter()
+                    .map(|fv| json!({"field": fv.field, "description": fv.description}))
+                    .collect::<Vec<_>>()
+            }),
+            ErrorDetail::RetryInfo { retry_delay } => json!({
+                "@type": TYPE_RETRY_INFO,
+                "retryDelay": format!("{}s", retry_delay.as_secs_f64())
+            }),
+            ErrorDetail::ErrorInfo {
+                reason,
+                domain,
+                metadata,
+            } => json!({
+                "@type": TYPE_ERROR_INFO,
+                "reason": reason,
+                "domain": domain,
+                "metadata": metadata
+            }),
+            ErrorDetail::Other(v) => v.clone(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorDetail {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Value::deserialize(deserializer).map(ErrorDetail::from_value)
+    }
+}
+
+impl Serialize for ErrorDetail {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_value().serialize(serializer)
+    }
+}
+
+// the json of a protobuf Duration: "1.500s" or {"seconds": 1, "nanos": 500000000}
+fn parse_duration(v: &Value) -> Option<Duration> {
+    match v {
+        // a negative, infinite or too large value is not a duration
+        Value::String(s) => s
+            .strip_suffix('s')
+            .and_then(|secs| secs.parse::<f64>().ok())
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok()),
+        Value::Object(o) => {
+            let seconds = o.get("seconds").and_then(|s| match s {
+                Value::String(s) => s.parse::<u64>().ok(),
+                s => s.as_u64(),
+            })?;
+            let nanos = o.get("nanos").and_then(Value::as_u64).unwrap_or(0);
+            Duration::from_secs(seconds).checked_add(Duration::from_nanos(nanos))
+        }
+        _ => None,
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub(crate) struct ResponseError {
+    pub(crate) error: Error,
+}
+
+/// The error of a google api: `{"error": {"code": 404, "message": "...", "status": "NOT_FOUND", "details": [...]}}`,
+/// or of the request or the response.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Error {
+    /// The http status code.
+    pub code: u16,
+    pub message: String,
+    /// The canonical status of google, e.g. `NOT_FOUND`.
+    #[serde(default)]
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<ErrorDetail>,
+    // Transport and Decode can not be derived from the status
+    #[serde(skip)]
+    kind: Option<ErrorKind>,
+}
+
+impl Error {
+    pub fn new(status_code: StatusCode, msg: String) -> Self {
+        Error {
+            code: status_code.as_u16(),
+            message: msg,
+            status: ErrorKind::from_code(status_code.as_u16())
+                .status()
+                .to_string(),
+            details: vec![],
+            kind: None,
+        }
+    }
+
+    /// An error without a response (`Transport`) or with an invalid response (`Decode`).
+    pub fn with_kind(kind: ErrorKind, msg: String) -> Self {
+        let code = match kind {
+            ErrorKind::Transport => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Error {
+            kind: Some(kind),
+            status: kind.status().to_string(),
+            ..Error::new(code, msg)
+        }
+    }
+
+    /// Read the error of the response, a body without the json of google is the message.
+    pub fn from_response(resp: blocking::Response) -> Self {
+        let status = resp.status();
+        match resp.text() {
+            Ok(body) => Error::from_body(status, &body),
+            Err(err) => err.into(),
+        }
+    }
+
+    /// The error of the status and the body of a response.
+    pub fn from_body(status: StatusCode, body: &str) -> Self {
+        match serde_json::from_str::<ResponseError>(body) {
+            Ok(ResponseError { mut error }) => {
+                if error.status.is_empty() {
+                    error.status = ErrorKind::from_code(error.code).status().to_string();
+                }
+                error
+            }
+            Err(_) => {
+                let message = match body.trim() {
+                    "" => status
+                        .canonical_reason()
+                        .unwrap_or("unknown error")
+                        .to_string(),
+                    body => body.to_string(),
+                };
+                Error::new(status, message)
+            }
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+            .or_else(|| ErrorKind::from_status(&self.status))
+            .unwrap_or_else(|| ErrorKind::from_code(self.code))
+    }
+
+    /// A retry can succeed: a conflict (ABORTED), the quota, an unavailable service or a lost connection.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::Aborted
+                | ErrorKind::ResourceExhausted
+                | ErrorKind::Unavailable
+                | ErrorKind::DeadlineExceeded
+                | ErrorKind::Internal
+                | ErrorKind::Transport
+        )
+    }
+
+    /// The delay of the RetryInfo detail.
+    pub fn retry_delay(&self) -> Option<Duration> {
+        self.details.iter().find_map(|detail| match detail {
+            ErrorDetail::RetryInfo { retry_delay } => Some(*retry_delay),
+            _ => None,
+        })
+    }
+
+    pub fn field_violations(&self) -> Vec<&FieldViolation> {
+        self.details
+            .iter()
+            .filter_map(|detail| match detail {
+                ErrorDetail::BadRequest { field_violations } => Some(field_violations),
+                _ => None,
+            })
+            .flatten()
+            .collect()
+    }
+
+    /// The reason of the ErrorInfo detail, e.g. `SERVICE_DISABLED`.
+    pub fn reason(&self) -> Option<&str> {
+        self.details.iter().find_map(|detail| match detail {
+            ErrorDetail::ErrorInfo { reason, .. } => Some(reason.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} ({})", self.status, self.message, self.code)?;
+        for fv in self.field_violations() {
+            write!(f, ", {}: {}", fv.field, fv.description)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for String {
+    fn from(err: Error) -> Self {
+        format!("{} ({})", err.message, err.code)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::with_kind(
+            ErrorKind::Decode,
+            format!("JSON_ERROR: {} (l{} : c{})", err, err.line(), err.column()),
+        )
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        // the Display of reqwest::Error contains the url (with query)
+        let message = match err.url() {
+            Some(u) => {
+                let url = redact_url(u);
+                format!(
+                    "{} (url: {})",
+                    err.to_string().replace(u.as_str(), &url),
+                    url
+                )
+            }
+            None => format!("{} (url: no url available)", err),
+        };
+
+        match err.status() {
+            Some(status) => Error::new(status, message),
+            None if is_decode(&err) => Error::with_kind(ErrorKind::Decode, message),
+            None => Error::with_kind(ErrorKind::Transport, message),
+        }
+    }
+}
+
+// reqwest 0.10 has no is_decode, the json of the body is read with serde_json
+fn is_decode(err: &reqwest::Error) -> bool {
+    std::error::Error::source(err).is_some_and(|source| source.is::<serde_json::Error>())
+}
+
+// the query can contain credentials (e.g. key=...), so it is never part of an error message
+pub(crate) fn redact_url(url: &Url) -> String {
+    let url = url.as_str();
+    match url.find('?') {
+        Some(pos) => format!("{}?{}", &url[..pos], REDACTED),
+        None => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_with_nonstandard_code() {
+        let err = Error::new(StatusCode::from_u16(599).unwrap(), "oops".to_string());
+        assert_eq!("UNKNOWN", err.status);
+        assert_eq!(ErrorKind::Unknown, err.kind());
+
+        let err = Error::new(StatusCode::NOT_FOUND, "missing".to_string());
+        assert_eq!("NOT_FOUND", err.status);
+        assert_eq!("NOT_FOUND: missing (404)", err.to_string());
+    }
+
+    #[test]
+    fn test_details() {
+        let json = r#"{"error": {
+            "code": 400,
+            "message": "Invalid value",
+            "status": "INVALID_ARGUMENT",
+            "details": [
+                {"@type": "type.googleapis.com/google.rpc.BadRequest",
+                 "fieldViolations": [{"field": "keys[0].path", "description": "missing kind"}]},
+                {"@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                 "reason": "SERVICE_DISABLED", "domain": "googleapis.com",
+                 "metadata": {"service": "datastore.googleapis.com"}},
+                {"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "1.500s"},
+                {"@type": "type.googleapis.com/google.rpc.Help", "links": []}
+            ]
+        }}"#;
+        let err = serde_json::from_str::<ResponseError>(json).unwrap().error;
+
+        assert_eq!(ErrorKind::InvalidArgument, err.kind());
+        assert!(!err.is_retryable());
+        assert_eq!(
+            vec![&FieldViolation {
+                field: "keys[0].path".to_string(),
+                description: "missing kind".to_string()
+            }],
+            err.field_violations()
+        );
+        assert_eq!(Some("SERVICE_DISABLED"), err.reason());
+        assert_eq!(Some(Duration::from_millis(1500)), err.retry_delay());
+        assert!(matches!(err.details[3], ErrorDetail::Other(_)));
+        assert_eq!(
+            "INVALID_ARGUMENT: Invalid value (400), keys[0].path: missing kind",
+            err.to_string()
+        );
+
+        // the details are written back as they are read
+        let v = serde_json::to_value(&err).unwrap();
+        assert_eq!("SERVICE_DISABLED", v["details"][1]["reason"]);
+        assert_eq!("1.5s", v["details"][2]["retryDelay"]);
+    }
+
+    #[test]
+    fn test_kind_and_retryable() {
+        let err = |code: u16, status: &str| Error {
+            status: status.to_string(),
+            ..Error::new(StatusCode::from_u16(code).unwrap(), String::new())
+        };
+        assert_eq!(ErrorKind::Aborted, err(409, "ABORTED").kind());
+        assert_eq!(ErrorKind::AlreadyExists, err(409, "ALREADY_EXISTS").kind());
+        assert!(err(409, "ABORTED").is_retryable());
+        assert!(!err(409, "ALREADY_EXISTS").is_retryable());
+        // a conflict without a status is not retried, it can be an existing entity
+        assert_eq!(ErrorKind::Unknown, err(409, "").kind());
+        assert!(!err(409, "").is_retryable());
+        assert!(!Error::from_body(StatusCode::CONFLICT, "conflict").is_retryable());
+        assert!(err(429, "").is_retryable());
+        assert!(err(502, "").is_retryable());
+        assert!(!err(403, "PERMISSION_DENIED").is_retryable());
+
+        let err = Error::with_kind(ErrorKind::Transport, "connection refused".to_string());
+        assert_eq!(ErrorKind::Transport, err.kind());
+        assert!(err.is_retryable());
+        let err: Error = serde_json::from_str::<Value>("{").unwrap_err().into();
+        assert_eq!(ErrorKind::Decode, err.kind());
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Some(Duration::from_secs(30)), parse_duration(&json!("30s")));
+        assert_eq!(
+            Some(Duration::new(1, 500_000_000)),
+            parse_duration(&json!({"seconds": "1", "nanos": 500000000}))
+        );
+        assert_eq!(None, parse_duration(&json!("soon")));
+
+        // too large for a duration, it is not a panic
+        assert_eq!(None, parse_duration(&json!("1e30s")));
+        assert_eq!(None, parse_duration(&json!("infs")));
+        assert_eq!(None, parse_duration(&json!("-1s")));
+        assert_eq!(
+            None,
+            parse_duration(&json!({"seconds": u64::MAX, "nanos": 1_000_000_000}))
+        );
+        match ErrorDetail::from_value(json!({
+            "@type": "type.googleapis.com/google.rpc.RetryInfo",
+            "retryDelay": "1e30s"
+        })) {
+            ErrorDetail::Other(_) => {}
+            other => panic!("expected Other, got: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_redact_url() {
+        let url =
+            Url::parse("https://datastore.googleapis.com/v1/projects/p:lookup?key=secret").unwrap();
+        assert_eq!(
+            "https://datastore.googleapis.com/v1/projects/p:lookup?<redacted>",
+            redact_url(&url)
+        );
+
+        let url = Url::parse("https://datastore.googleapis.com/v1/projects/p:lookup").unwrap();
+        assert_eq!(
+            "https://datastore.googleapis.com/v1/projects/p:lookup",
+            redact_url(&url)
+        );
+    }
+
+    #[test]
+    fn test_reqwest_error_without_credentials() {
+        // nobody is listening on port 1
+        let err = reqwest::blocking::Client::new()
+            .get("http://127.0.0.1:1/token?access_token=secret")
+            .send()
+            .unwrap_err();
+        let err: Error = err.into();
+        assert!(!err.message.contains("secret"), "{}", err.message);
+        assert!(err.message.contains("127.0.0.1"), "{}", err.message);
+        assert_eq!(ErrorKind::Transport, err.kind());
+        assert_eq!("UNAVAILABLE", err.status);
+    }
+}
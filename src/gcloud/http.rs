@@ -0,0 +1,370 @@
+use super::error::ErrorKind;
+use super::transport::{Request, ReqwestTransport, Response, Transport};
+use super::{with_trace, Error};
+use crate::logging::{self, trace::latency};
+
+use chrono::{DateTime, Utc};
+use http::header::RETRY_AFTER;
+use log::{debug, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::json;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// RetryPolicy retries the failed calls of an idempotent request (429, 500, 502, 503, 504, ABORTED
+/// or without a response) with an exponential backoff and full jitter:
+/// the delay of the n-th retry is random in `[0, min(max_backoff, initial_backoff * multiplier^n)]`.
+/// A `Retry-After` header or a RetryInfo detail of the response is the delay instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    multiplier: f64,
+    deadline: Duration,
+    retry_aborted: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            multiplier: 2.0,
+            deadline: Duration::from_secs(60),
+            retry_aborted: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exactly one attempt.
+    pub fn none() -> Self {
+        RetryPolicy::default().with_max_attempts(1)
+    }
+
+    /// The number of attempts including the first call, at least 1.
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The maximum delay before the first retry, it grows with the multiplier.
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    /// The total time of all attempts, there is no retry after the deadline.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// ABORTED is not retried, e.g. a read in a transaction: the transaction is aborted
+    /// and must be retried as a whole.
+    pub fn without_aborted(mut self) -> Self {
+        self.retry_aborted = false;
+        self
+    }
+
+    // the delay before the retry after the attempt (1, 2, ...), random is in [0, 1]
+    fn backoff(&self, attempt: u32, random: f64) -> Duration {
+        let exp = self
+            .multiplier
+            .powi(attempt.saturating_sub(1).min(64) as i32);
+        let max = (self.initial_backoff.as_secs_f64() * exp).min(self.max_backoff.as_secs_f64());
+        Duration::from_secs_f64(max * random.clamp(0.0, 1.0))
+    }
+
+    pub(crate) fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// The delay with jitter before the retry after the attempt, e.g. of an aborted transaction.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        self.backoff(attempt, random())
+    }
+}
+
+/// Client sends the requests of the google apis with a transport and retries them with the policy.
+pub struct Client {
+    transport: Box<dyn Transport>,
+    retry: RetryPolicy,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::new(Box::new(ReqwestTransport::default()))
+    }
+}
+
+impl Client {
+    pub fn new(transport: Box<dyn Transport>) -> Self {
+        Client {
+            transport,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// The retries of the requests, the default is: `RetryPolicy::default()`.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    #[cfg(test)]
+    pub fn with_transport(mut self, transport: Box<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Send the request and retry it with the policy, the closure creates the request for every
+    /// attempt (e.g. with a refreshed token). The response has a success status.
+    pub fn send<F>(&self, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Result<Request, Error>,
+    {
+        self.send_with(&self.retry, request)
+    }
+
+    /// Send the request and retry it with the policy, but not after ABORTED, see: `RetryPolicy::without_aborted`.
+    pub fn send_without_aborted<F>(&self, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Result<Request, Error>,
+    {
+        self.send_with(&self.retry.clone().without_aborted(), request)
+    }
+
+    /// Send the request without a retry, e.g. a commit, which is not idempotent.
+    pub fn send_once(&self, request: Request) -> Result<Response, Error> {
+        self.send_with(&RetryPolicy::none(), || Ok(request.clone()))
+    }
+
+    fn send_with<F>(&self, retry: &RetryPolicy, request: F) -> Result<Response, Error>
+    where
+        F: Fn() -> Result<Request, Error>,
+    {
+        let start = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let (err, retry_after) = match self.transport.send(with_trace(request()?)) {
+                Ok(resp) if resp.status.is_success() => return Ok(resp),
+                Ok(resp) => {
+                    let retry_after = resp
+                        .header(RETRY_AFTER.as_str())
+                        .and_then(|v| parse_retry_after(v, Utc::now()));
+                    (resp.error(), retry_after)
+                }
+                Err(err) => (err, None),
+            };
+
+            let retryable =
+                err.is_retryable() && (retry.retry_aborted || err.kind() != ErrorKind::Aborted);
+            if !retryable || attempt >= retry.max_attempts {
+                return Err(err);
+            }
+
+            let delay = retry_after
+                .or_else(|| err.retry_delay())
+                .unwrap_or_else(|| retry.backoff(attempt, random()));
+            // a delay, which overflows, is after the deadline
+            let retry_at = start.elapsed().checked_add(delay);
+            if retry_at.is_none_or(|retry_at| retry_at > retry.deadline) {
+                debug!(
+                    "no retry after attempt {}, the deadline {} is exceeded",
+                    attempt,
+                    latency(retry.deadline)
+                );
+                return Err(err);
+            }
+
+            logging::with_fields(
+                vec![
+                    ("attempt", json!(attempt)),
+                    ("delay", json!(latency(delay))),
+                    ("status", json!(err.status)),
+                ],
+                || {
+                    warn!(
+                        "attempt {}/{} failed: {}, retry in {}",
+                        attempt,
+                        retry.max_attempts,
+                        err,
+                        latency(delay)
+                    )
+                },
+            );
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+// Retry-After: 120 or Retry-After: Fri, 31 Dec 1999 23:59:59 GMT
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past is no delay
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+// without a random generator, the backoff is the maximum
+fn random() -> f64 {
+    let mut bytes = [0u8; 4];
+    match SystemRandom::new().fill(&mut bytes) {
+        Ok(()) => f64::from(u32::from_be_bytes(bytes)) / f64::from(u32::MAX),
+        Err(_) => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::{MockResponse, MockServer};
+    use chrono::TimeZone;
+
+    fn fast() -> RetryPolicy {
+        RetryPolicy::default().with_initial_backoff(Duration::from_millis(1))
+    }
+
+    fn get(server: &MockServer, retry: &RetryPolicy) -> Result<Response, Error> {
+        let client = Client::default().with_retry_policy(retry.clone());
+        let url = server.url();
+        client.send(|| Ok(Request::get(&url)))
+    }
+
+    fn unavailable() -> MockResponse {
+        MockResponse::json(
+            503,
+            json!({"error": {"code": 503, "message": "try again", "status": "UNAVAILABLE"}}),
+        )
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry = RetryPolicy::default();
+        assert_eq!(Duration::from_millis(100), retry.backoff(1, 1.0));
+        assert_eq!(Duration::from_millis(400), retry.backoff(3, 1.0));
+        assert_eq!(Duration::from_millis(200), retry.backoff(3, 0.5));
+        assert_eq!(Duration::from_secs(10), retry.backoff(30, 1.0));
+        assert_eq!(Duration::from_secs(0), retry.backoff(3, 0.0));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = Utc.with_ymd_and_hms(1999, 12, 31, 23, 59, 0).unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after("120", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(59)),
+            parse_retry_after("Fri, 31 Dec 1999 23:59:59 GMT", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            parse_retry_after("Fri, 31 Dec 1999 23:00:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+    }
+
+    #[test]
+    fn test_retry_until_success() {
+        let server = MockServer::start(vec![
+            unavailable(),
+            MockResponse::json(429, json!({})).with_header("Retry-After", "0"),
+            MockResponse::json(
+                409,
+                json!({"error": {"code": 409, "message": "conflict", "status": "ABORTED"}}),
+            ),
+            MockResponse::json(200, json!({"found": []})),
+        ]);
+
+        let resp = get(&server, &fast()).unwrap();
+        assert_eq!(200, resp.status.as_u16());
+        assert_eq!(4, server.requests().len());
+    }
+
+    #[test]
+    fn test_no_retry_of_client_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                409,
+                json!({"error": {"code": 409, "message": "exists", "status": "ALREADY_EXISTS"}}),
+            ),
+            MockResponse::json(200, json!({})),
+        ]);
+
+        let err = get(&server, &fast()).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let server = MockServer::start(vec![unavailable(), unavailable(), unavailable()]);
+
+        let err = get(&server, &fast().with_max_attempts(2)).unwrap_err();
+        assert_eq!(503, err.code);
+        assert_eq!(2, server.requests().len());
+    }
+
+    #[test]
+    fn test_retry_info_and_deadline() {
+        // the RetryInfo delay is longer than the deadline
+        let server = MockServer::start(vec![
+            MockResponse::json(
+                429,
+                json!({"error": {
+                    "code": 429,
+                    "message": "quota",
+                    "status": "RESOURCE_EXHAUSTED",
+                    "details": [{
+                        "@type": "type.googleapis.com/google.rpc.RetryInfo",
+                        "retryDelay": "30s"
+                    }]
+                }}),
+            ),
+            MockResponse::json(200, json!({})),
+        ]);
+
+        let start = Instant::now();
+        let err = get(&server, &fast().with_deadline(Duration::from_secs(5))).unwrap_err();
+        assert_eq!(ErrorKind::ResourceExhausted, err.kind());
+        assert_eq!(1, server.requests().len());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_huge_retry_after() {
+        let server = MockServer::start(vec![
+            MockResponse::json(429, json!({})).with_header("Retry-After", "18446744073709551615"),
+            MockResponse::json(200, json!({})),
+        ]);
+
+        let err = get(&server, &fast()).unwrap_err();
+        assert_eq!(429, err.code);
+        assert_eq!(1, server.requests().len());
+    }
+
+    #[test]
+    fn test_retry_without_response() {
+        // nobody is listening on port 1
+        let client = Client::default().with_retry_policy(fast().with_max_attempts(2));
+        let err = client
+            .send(|| Ok(Request::get("http://127.0.0.1:1/")))
+            .unwrap_err();
+        assert_eq!(ErrorKind::Transport, err.kind());
+    }
+}
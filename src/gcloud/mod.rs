@@ -1,63 +1,47 @@
 pub mod auth;
 pub mod datastore;
-
-use http::StatusCode;
-use serde::{Deserialize, Serialize};
-use serde_json::{self};
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ResponseError {
-    error: Error,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Error {
-    pub code: u16,
-    pub message: String,
-    pub status: String,
-}
-
-impl Error {
-    fn new(status_code: StatusCode, msg: String) -> Self {
-        Error {
-            code: status_code.as_u16(),
-            message: msg,
-            status: status_code.canonical_reason().unwrap().to_string(),
+pub mod error;
+pub mod http;
+#[cfg(test)]
+pub mod testutil;
+pub mod transport;
+
+pub use error::{Error, REDACTED};
+
+use crate::logging::trace::{TraceContext, HEADER_CLOUD_TRACE_CONTEXT};
+
+use ::http::header::{HeaderName, HeaderValue};
+use transport::Request;
+
+// the trace of the current request is sent to the google api, so the calls are correlated
+pub(crate) fn with_trace(request: Request) -> Request {
+    let trace = TraceContext::current()
+        .and_then(|trace| HeaderValue::from_str(&trace.to_cloud_trace_context()).ok());
+    match trace {
+        Some(value) => {
+            request.with_header(HeaderName::from_static(HEADER_CLOUD_TRACE_CONTEXT), value)
         }
+        None => request,
     }
 }
 
-impl Into<String> for Error {
-    fn into(self) -> String {
-        format!("{} ({})", self.message, self.code)
-    }
-}
-
-impl From<serde_json::Error> for Error {
-    fn from(err: serde_json::Error) -> Self {
-        Error::new(
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("JSON_ERROR: {} (l{} : c{})", err, err.line(), err.column()),
-        )
-    }
-}
-
-impl From<reqwest::Error> for Error {
-    fn from(err: reqwest::Error) -> Self {
-        let status = match err.status() {
-            Some(s) => s.as_u16(),
-            None => StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-        };
-
-        let url = match err.url() {
-            Some(u) => u.as_str(),
-            None => "no url available",
-        };
-
-        Error {
-            code: status,
-            message: format!("{} (url: {})", err, url),
-            status: status.to_string(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_trace() {
+        let request = with_trace(Request::get("https://any"));
+        assert_eq!(None, request.header(HEADER_CLOUD_TRACE_CONTEXT));
+
+        let _trace =
+            TraceContext::from_cloud_trace_context("105445aa7843bc8bf206b12000100000/1;o=1")
+                .unwrap()
+                .enter();
+        let request = with_trace(Request::get("https://any"));
+        assert_eq!(
+            Some("105445aa7843bc8bf206b12000100000/1;o=1"),
+            request.header(HEADER_CLOUD_TRACE_CONTEXT)
+        );
     }
 }
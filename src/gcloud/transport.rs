@@ -0,0 +1,192 @@
+use super::Error;
+
+use http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use http::{HeaderMap, Method, StatusCode};
+use reqwest::blocking;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// A request of a google api.
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: String,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Request {
+    pub fn new(method: Method, url: &str) -> Self {
+        Request {
+            method,
+            url: url.to_string(),
+            headers: HeaderMap::new(),
+            body: String::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn get(url: &str) -> Self {
+        Request::new(Method::GET, url)
+    }
+
+    pub fn post(url: &str) -> Self {
+        Request::new(Method::POST, url)
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: String) -> Self {
+        self.body = body;
+        self
+    }
+
+    /// The json body with the header `Content-Type: application/json`.
+    pub fn with_json<T: Serialize>(self, body: &T) -> Result<Self, Error> {
+        Ok(self
+            .with_header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+            .with_body(serde_json::to_string(body)?))
+    }
+
+    #[cfg(test)]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// The response of a google api, with any status.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Response {
+    #[cfg(test)]
+    pub fn new(status: StatusCode, body: String) -> Self {
+        Response {
+            status,
+            headers: HeaderMap::new(),
+            body,
+        }
+    }
+
+    /// A json response, e.g. in a test: `Response::json(StatusCode::OK, &json!({"found": []}))`.
+    #[cfg(test)]
+    pub fn json<T: Serialize>(status: StatusCode, body: &T) -> Self {
+        let mut resp = Response::new(status, serde_json::to_string(body).unwrap_or_default());
+        resp.headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        resp
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|v| v.to_str().ok())
+    }
+
+    /// Read the json of the body.
+    pub fn deserialize<D: DeserializeOwned>(&self) -> Result<D, Error> {
+        Ok(serde_json::from_str(&self.body)?)
+    }
+
+    /// The error of the response, see: `Error::from_body`.
+    pub fn error(&self) -> Error {
+        Error::from_body(self.status, &self.body)
+    }
+}
+
+/// Transport sends a request and returns the response with any status,
+/// an `Err` is a request without a response (e.g. a refused connection).
+pub trait Transport: Send + Sync {
+    fn send(&self, request: Request) -> Result<Response, Error>;
+}
+
+/// The transport with reqwest, the default of all clients.
+#[derive(Debug, Default)]
+pub struct ReqwestTransport {
+    client: blocking::Client,
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        let resp = self
+            .client
+            .request(request.method, &request.url)
+            .headers(request.headers)
+            .body(request.body)
+            .send()?;
+        let status = resp.status();
+        let headers = resp.headers().clone();
+        let body = resp.text()?;
+        Ok(Response {
+            status,
+            headers,
+            body,
+        })
+    }
+}
+
+// a shared transport, e.g. to read the requests of a ScriptedTransport after the calls
+impl<T: Transport + ?Sized> Transport for std::sync::Arc<T> {
+    fn send(&self, request: Request) -> Result<Response, Error> {
+        (**self).send(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::error::ErrorKind;
+    use crate::gcloud::testutil::{MockResponse, MockServer, ScriptedTransport};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_reqwest_transport() {
+        let server = MockServer::start(vec![MockResponse::json(
+            404,
+            json!({"error": {"code": 404, "message": "missing", "status": "NOT_FOUND"}}),
+        )
+        .with_header("Retry-After", "1")]);
+
+        let resp = ReqwestTransport::default()
+            .send(
+                Request::post(&format!("{}/v1/projects/p:lookup", server.url()))
+                    .with_json(&json!({"keys": []}))
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, resp.status);
+        assert_eq!(Some("1"), resp.header("retry-after"));
+        assert_eq!(ErrorKind::NotFound, resp.error().kind());
+
+        let requests = server.requests();
+        assert_eq!("POST", requests[0].method);
+        assert_eq!("/v1/projects/p:lookup", requests[0].path);
+        assert_eq!(Some("application/json"), requests[0].header("content-type"));
+        assert_eq!(r#"{"keys":[]}"#, requests[0].body);
+    }
+
+    #[test]
+    fn test_scripted_transport() {
+        let transport =
+            ScriptedTransport::new(vec![Response::json(StatusCode::OK, &json!({"batch": {}}))])
+                .with_error(Error::with_kind(ErrorKind::Transport, "reset".to_string()));
+
+        let resp = transport.send(Request::get("https://any/1")).unwrap();
+        assert_eq!(json!({"batch": {}}), resp.deserialize::<Value>().unwrap());
+        let err = transport.send(Request::get("https://any/2")).unwrap_err();
+        assert_eq!("reset", err.message);
+        let err = transport.send(Request::get("https://any/3")).unwrap_err();
+        assert_eq!(ErrorKind::Transport, err.kind());
+
+        let urls: Vec<String> = transport.requests().into_iter().map(|r| r.url).collect();
+        assert_eq!(
+            vec!["https://any/1", "https://any/2", "https://any/3"],
+            urls
+        );
+    }
+}
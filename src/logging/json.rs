@@ -0,0 +1,230 @@
+use super::trace::{self, TraceContext};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Map, Value};
+use simplelog::{Config, SharedLogger};
+
+use std::io::Write;
+use std::sync::Mutex;
+
+const KEY_TRACE: &str = "logging.googleapis.com/trace";
+const KEY_SPAN_ID: &str = "logging.googleapis.com/spanId";
+const KEY_TRACE_SAMPLED: &str = "logging.googleapis.com/trace_sampled";
+const KEY_SOURCE_LOCATION: &str = "logging.googleapis.com/sourceLocation";
+
+/// JsonLogger writes a log entry as one json line, which Cloud Logging (Cloud Run, GKE) reads
+/// from stdout as a structured log:
+///
+/// ```text
+/// {"severity":"INFO","message":"...","time":"2020-04-01T10:00:00.000Z",
+///  "logging.googleapis.com/trace":"projects/goheros-207118/traces/105445aa7843bc8bf206b12000100000", ...}
+/// ```
+pub struct JsonLogger<W: Write + Send> {
+    project_id: Option<String>,
+    writer: Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLogger<W> {
+    /// The project id is needed for the trace: `projects/{project_id}/traces/{trace_id}`.
+    pub fn new(project_id: Option<String>, writer: W) -> Box<Self> {
+        Box::new(JsonLogger {
+            project_id,
+            writer: Mutex::new(writer),
+        })
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let entry = entry(
+            record,
+            self.project_id.as_deref(),
+            TraceContext::current().as_ref(),
+            Utc::now(),
+        );
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // the logger can not log its own errors
+        let _ = writeln!(writer, "{}", entry);
+    }
+
+    fn flush(&self) {
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _ = writer.flush();
+    }
+}
+
+impl<W: Write + Send + 'static> SharedLogger for JsonLogger<W> {
+    fn level(&self) -> LevelFilter {
+        LevelFilter::Trace
+    }
+
+    fn config(&self) -> Option<&Config> {
+        None
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn Log> {
+        Box::new(*self)
+    }
+}
+
+// https://cloud.google.com/logging/docs/reference/v2/rest/v2/LogEntry#LogSeverity
+fn severity(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARNING",
+        Level::Info => "INFO",
+        Level::Debug | Level::Trace => "DEBUG",
+    }
+}
+
+// https://cloud.google.com/logging/docs/structured-logging#special-payload-fields
+fn entry(
+    record: &Record,
+    project_id: Option<&str>,
+    trace: Option<&TraceContext>,
+    time: DateTime<Utc>,
+) -> Value {
+    // the fields (e.g. operation, kind, latency) can not override the special fields
+    let mut entry: Map<String, Value> = trace::fields().into_iter().collect();
+
+    entry.insert("severity".to_string(), json!(severity(record.level())));
+    entry.insert("message".to_string(), json!(record.args().to_string()));
+    entry.insert(
+        "time".to_string(),
+        json!(time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+    );
+    entry.insert("target".to_string(), json!(record.target()));
+
+    if let Some(file) = record.file() {
+        let mut location = json!({ "file": file });
+        if let Some(line) = record.line() {
+            location["line"] = json!(line.to_string());
+        }
+        if let Some(module) = record.module_path() {
+            location["function"] = json!(module);
+        }
+        entry.insert(KEY_SOURCE_LOCATION.to_string(), location);
+    }
+
+    if let Some(trace) = trace {
+        let trace_name = match project_id {
+            Some(project_id) => format!("projects/{}/traces/{}", project_id, trace.trace_id),
+            None => trace.trace_id.clone(),
+        };
+        entry.insert(KEY_TRACE.to_string(), json!(trace_name));
+        entry.insert(KEY_SPAN_ID.to_string(), json!(trace.span_id_hex()));
+        entry.insert(KEY_TRACE_SAMPLED.to_string(), json!(trace.sampled));
+    }
+
+    Value::Object(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn log_entry(trace: Option<&TraceContext>) -> Value {
+        entry(
+            &Record::builder()
+                .args(format_args!("lookup done"))
+                .level(Level::Warn)
+                .target("portfolio::gcloud::datastore")
+                .module_path(Some("portfolio::gcloud::datastore"))
+                .file(Some("src/gcloud/datastore/mod.rs"))
+                .line(Some(42))
+                .build(),
+            Some("goheros-207118"),
+            trace,
+            Utc.with_ymd_and_hms(2020, 4, 1, 10, 0, 0).unwrap()
+                + chrono::Duration::milliseconds(123),
+        )
+    }
+
+    #[test]
+    fn test_entry() {
+        assert_eq!(
+            json!({
+                "severity": "WARNING",
+                "message": "lookup done",
+                "time": "2020-04-01T10:00:00.123Z",
+                "target": "portfolio::gcloud::datastore",
+                "logging.googleapis.com/sourceLocation": {
+                    "file": "src/gcloud/datastore/mod.rs",
+                    "line": "42",
+                    "function": "portfolio::gcloud::datastore"
+                }
+            }),
+            log_entry(None)
+        );
+    }
+
+    #[test]
+    fn test_entry_with_trace_and_fields() {
+        let trace =
+            TraceContext::from_cloud_trace_context("105445aa7843bc8bf206b12000100000/10;o=1")
+                .unwrap();
+        let entry = trace::with_fields(
+            vec![("operation", json!("lookup")), ("severity", json!("nope"))],
+            || log_entry(Some(&trace)),
+        );
+
+        assert_eq!(
+            "projects/goheros-207118/traces/105445aa7843bc8bf206b12000100000",
+            entry[KEY_TRACE]
+        );
+        assert_eq!("000000000000000a", entry[KEY_SPAN_ID]);
+        assert_eq!(true, entry[KEY_TRACE_SAMPLED]);
+        assert_eq!("lookup", entry["operation"]);
+        assert_eq!("WARNING", entry["severity"]);
+    }
+
+    #[test]
+    fn test_logger_writes_lines() {
+        #[derive(Clone, Default)]
+        struct Buffer(std::sync::Arc<Mutex<Vec<u8>>>);
+        impl Write for Buffer {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = Buffer::default();
+        let logger = JsonLogger::new(None, buffer.clone());
+        logger.log(
+            &Record::builder()
+                .args(format_args!("first"))
+                .level(Level::Info)
+                .build(),
+        );
+        logger.log(
+            &Record::builder()
+                .args(format_args!("second"))
+                .level(Level::Error)
+                .build(),
+        );
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(2, lines.len());
+        assert_eq!("ERROR", lines[1]["severity"]);
+        assert_eq!("second", lines[1]["message"]);
+    }
+}
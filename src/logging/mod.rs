@@ -0,0 +1,574 @@
+pub mod json;
+pub mod trace;
+
+pub use trace::{with_fields, TraceContext};
+
+use json::JsonLogger;
+
+use chrono::{DateTime, Utc};
+use log::{debug, Level, LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use simplelog::{Config, SharedLogger, SimpleLogger, TermLogger, TerminalMode, WriteLogger};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const DEFAULT_FILTER: &str = "info";
+const DEFAULT_RETENTION: usize = 5;
+
+/// The configuration of the logging, it can be deserialized from the `Config`:
+///
+/// ```text
+/// RUST_LOG=info,portfolio::gcloud=debug,reqwest=warn
+/// LOG_FILE=log/portfolio.log
+/// LOG_MAX_SIZE=10485760
+/// LOG_ROTATION=daily
+/// LOG_RETENTION=7
+/// LOG_FORMAT=json
+/// ```
+///
+/// `RUST_LOG` has precedence over `LOG_LEVEL`, the default is `info`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct LogConfig {
+    #[serde(rename = "RUST_LOG")]
+    pub rust_log: Option<String>,
+    #[serde(rename = "LOG_LEVEL")]
+    pub level: Option<String>,
+    /// Without a file, the log is written only to the terminal.
+    #[serde(rename = "LOG_FILE")]
+    pub file: Option<PathBuf>,
+    /// Rotate the file, if it is larger than the size in bytes.
+    #[serde(rename = "LOG_MAX_SIZE")]
+    pub max_size: Option<u64>,
+    #[serde(rename = "LOG_ROTATION", default)]
+    pub rotation: Rotation,
+    /// The number of the rotated files, which are kept (`portfolio.log.1`, `portfolio.log.2`, ...).
+    #[serde(rename = "LOG_RETENTION", default = "default_retention")]
+    pub retention: usize,
+    #[serde(rename = "LOG_FORMAT", default)]
+    pub format: LogFormat,
+    /// The project of the trace in the json format.
+    #[serde(rename = "PROJECT_ID")]
+    pub project_id: Option<String>,
+}
+
+fn default_retention() -> usize {
+    DEFAULT_RETENTION
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            rust_log: None,
+            level: None,
+            file: None,
+            max_size: None,
+            rotation: Rotation::Never,
+            retention: DEFAULT_RETENTION,
+            format: LogFormat::Text,
+            project_id: None,
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn filter(&self) -> &str {
+        self.rust_log
+            .as_deref()
+            .or(self.level.as_deref())
+            .unwrap_or(DEFAULT_FILTER)
+    }
+}
+
+/// The format of the log entries.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// The lines of simplelog, for the terminal.
+    #[default]
+    Text,
+    /// One json per line for Cloud Logging, see: `JsonLogger`.
+    Json,
+}
+
+/// The time based rotation of the log file.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    #[default]
+    Never,
+    Hourly,
+    Daily,
+}
+
+impl Rotation {
+    // the period of the time, a new period starts a new file
+    fn period(self, time: DateTime<Utc>) -> Option<String> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Hourly => Some(time.format("%Y%m%d%H").to_string()),
+            Rotation::Daily => Some(time.format("%Y%m%d").to_string()),
+        }
+    }
+}
+
+/// Filter is a `RUST_LOG` like filter: a default level and levels per module,
+/// e.g. `info,portfolio::gcloud=debug,reqwest=warn`. The longest matching module wins.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    default: LevelFilter,
+    // (module, level), sorted by the length of the module, the longest first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        let mut default = LevelFilter::Error;
+        let mut modules = vec![];
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            let name = parts.next().unwrap_or_default().trim();
+            match parts.next() {
+                Some(level) => modules.push((name.to_string(), parse_level(level.trim())?)),
+                // a single level is the default, a single module logs everything
+                None => match LevelFilter::from_str(name) {
+                    Ok(level) => default = level,
+                    Err(_) => modules.push((name.to_string(), LevelFilter::Trace)),
+                },
+            }
+        }
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(Filter { default, modules })
+    }
+
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level(target)
+    }
+
+    /// The most verbose level of the filter, for `log::set_max_level`.
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, |max, level| max.max(level))
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    LevelFilter::from_str(level).map_err(|_| {
+        format!(
+            "invalid log level: '{}', expected: off, error, warn, info, debug or trace",
+            level
+        )
+    })
+}
+
+// the filter is checked before the record is passed to the loggers (terminal, file)
+struct FilteredLogger {
+    filter: Filter,
+    loggers: Vec<Box<dyn SharedLogger>>,
+}
+
+impl Log for FilteredLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata.target(), metadata.level())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            for logger in &self.loggers {
+                logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for logger in &self.loggers {
+            logger.flush();
+        }
+    }
+}
+
+// set by the first successful init
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Initialize the logging to the terminal and to the optional file.
+///
+/// The logging can be initialized only once, a second call (e.g. in the tests) is ignored.
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    // the log file is not opened (and not rotated) for a config, which is ignored
+    if INITIALIZED.load(Ordering::SeqCst) {
+        debug!("the logging is already initialized, ignore: {:?}", config);
+        return Ok(());
+    }
+    let filter = Filter::parse(config.filter())?;
+
+    let file = match &config.file {
+        Some(path) => Some(
+            RotatingFile::open(path, config.max_size, config.rotation, config.retention)
+                .map_err(|err| format!("could not open log file '{}': {}", path.display(), err))?,
+        ),
+        None => None,
+    };
+
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+    match config.format {
+        LogFormat::Text => {
+            match TermLogger::new(LevelFilter::Trace, Config::default(), TerminalMode::Mixed) {
+                Some(logger) => loggers.push(logger),
+                // e.g. in a container without a tty
+                None => loggers.push(SimpleLogger::new(LevelFilter::Trace, Config::default())),
+            }
+            if let Some(file) = file {
+                loggers.push(WriteLogger::new(
+                    LevelFilter::Trace,
+                    Config::default(),
+                    file,
+                ));
+            }
+        }
+        // Cloud Run reads the structured log from stdout
+        LogFormat::Json => {
+            loggers.push(JsonLogger::new(config.project_id.clone(), io::stdout()));
+            if let Some(file) = file {
+                loggers.push(JsonLogger::new(config.project_id.clone(), file));
+            }
+        }
+    }
+
+    let max_level = filter.max_level();
+    match log::set_boxed_logger(Box::new(FilteredLogger { filter, loggers })) {
+        Ok(()) => {
+            INITIALIZED.store(true, Ordering::SeqCst);
+            log::set_max_level(max_level);
+            Ok(())
+        }
+        Err(_) => {
+            debug!("the logging is already initialized, ignore: {:?}", config);
+            Ok(())
+        }
+    }
+}
+
+/// RotatingFile is a log file, which is rotated by size or time:
+/// `portfolio.log` is renamed to `portfolio.log.1`, `portfolio.log.1` to `portfolio.log.2`, ...
+/// The files above the retention are deleted.
+///
+/// The loggers write a record in several calls, so the writes are buffered until the end of
+/// the line and a record is never split by a rotation.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    line: Vec<u8>,
+    size: u64,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    period: Option<String>,
+    retention: usize,
+}
+
+impl RotatingFile {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_size: Option<u64>,
+        rotation: Rotation,
+        retention: usize,
+    ) -> io::Result<RotatingFile> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        // an existing file belongs to the period of the last write
+        let modified: DateTime<Utc> = metadata
+            .modified()
+            .map(DateTime::from)
+            .unwrap_or_else(|_| Utc::now());
+
+        Ok(RotatingFile {
+            size: metadata.len(),
+            period: rotation.period(modified),
+            path,
+            file,
+            line: vec![],
+            max_size,
+            rotation,
+            retention,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{}", self.path.display(), n))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.retention));
+            for n in (1..self.retention).rev() {
+                let from = self.rotated_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn needs_rotation(&self, len: usize, period: &Option<String>) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_large = match self.max_size {
+            Some(max_size) => self.size + len as u64 > max_size,
+            None => false,
+        };
+        too_large || *period != self.period
+    }
+
+    // the file is rotated only before a complete line
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let period = self.rotation.period(Utc::now());
+        if self.needs_rotation(line.len(), &period) {
+            self.rotate()?;
+        }
+        self.period = period;
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            self.write_line(&line)?;
+        }
+        Ok(buf.len())
+    }
+
+    // a line without the end is written, too
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.write_line(&line)?;
+        }
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil::temp_dir;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse(
+            "warn, portfolio::gcloud=debug,portfolio::gcloud::auth=trace,reqwest=off",
+        )
+        .unwrap();
+        assert_eq!(LevelFilter::Warn, filter.level("portfolio"));
+        assert_eq!(LevelFilter::Debug, filter.level("portfolio::gcloud"));
+        assert_eq!(
+            LevelFilter::Debug,
+            filter.level("portfolio::gcloud::datastore")
+        );
+        assert_eq!(
+            LevelFilter::Trace,
+            filter.level("portfolio::gcloud::auth::token")
+        );
+        // a prefix of the name is not the module
+        assert_eq!(LevelFilter::Warn, filter.level("portfolio::gcloudx"));
+        assert_eq!(LevelFilter::Off, filter.level("reqwest::blocking"));
+        assert_eq!(LevelFilter::Trace, filter.max_level());
+
+        assert!(filter.enabled("portfolio::gcloud", Level::Info));
+        assert!(!filter.enabled("portfolio", Level::Info));
+    }
+
+    #[test]
+    fn test_filter_default_and_invalid() {
+        assert_eq!(
+            LevelFilter::Info,
+            Filter::parse("info").unwrap().level("portfolio")
+        );
+        assert_eq!(
+            LevelFilter::Error,
+            Filter::parse("").unwrap().level("portfolio")
+        );
+        assert_eq!(
+            LevelFilter::Trace,
+            Filter::parse("hyper").unwrap().level("hyper::client")
+        );
+        assert!(Filter::parse("portfolio=loud").is_err());
+    }
+
+    #[test]
+    fn test_log_config_filter() {
+        let mut config = LogConfig::default();
+        assert_eq!("info", config.filter());
+        config.level = Some("debug".to_string());
+        assert_eq!("debug", config.filter());
+        config.rust_log = Some("warn,portfolio=trace".to_string());
+        assert_eq!("warn,portfolio=trace", config.filter());
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = temp_dir("log_size");
+        let path = dir.join("portfolio.log");
+        let mut file = RotatingFile::open(&path, Some(10), Rotation::Never, 2).unwrap();
+
+        for line in &["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!("fourth\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "third\n",
+            fs::read_to_string(dir.join("portfolio.log.1")).unwrap()
+        );
+        assert_eq!(
+            "second\n",
+            fs::read_to_string(dir.join("portfolio.log.2")).unwrap()
+        );
+        // only 2 rotated files are kept
+        assert!(!dir.join("portfolio.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_time() {
+        let dir = temp_dir("log_time");
+        let path = dir.join("portfolio.log");
+        let mut file = RotatingFile::open(&path, None, Rotation::Daily, 1).unwrap();
+        file.write_all(b"today\n").unwrap();
+
+        // the file was written yesterday
+        file.period = Some("19700101".to_string());
+        file.write_all(b"tomorrow\n").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!("tomorrow\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "today\n",
+            fs::read_to_string(dir.join("portfolio.log.1")).unwrap()
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // every line in every file is a complete record
+    fn assert_complete_lines(dir: &Path, count: usize, is_complete: impl Fn(&str) -> bool) {
+        let mut lines = 0;
+        for entry in fs::read_dir(dir).unwrap() {
+            let content = fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(content.ends_with('\n'), "incomplete file: {:?}", content);
+            for line in content.lines() {
+                assert!(is_complete(line), "incomplete line: {:?}", line);
+                lines += 1;
+            }
+        }
+        assert_eq!(count, lines);
+    }
+
+    fn log_records(logger: &dyn Log, count: usize) {
+        for n in 0..count {
+            logger.log(
+                &Record::builder()
+                    .level(Level::Info)
+                    .target("portfolio")
+                    .args(format_args!("record {} of the test", n))
+                    .build(),
+            );
+        }
+        logger.flush();
+    }
+
+    #[test]
+    fn test_rotate_write_logger_at_record_boundaries() {
+        let dir = temp_dir("log_write_logger");
+        let file =
+            RotatingFile::open(dir.join("portfolio.log"), Some(64), Rotation::Never, 20).unwrap();
+        let logger = WriteLogger::new(LevelFilter::Trace, Config::default(), file);
+
+        log_records(logger.as_ref(), 10);
+        assert!(dir.join("portfolio.log.1").exists());
+        assert_complete_lines(&dir, 10, |line| {
+            line.contains("INFO] record ") && line.ends_with(" of the test")
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_json_logger_at_record_boundaries() {
+        let dir = temp_dir("log_json_logger");
+        let file =
+            RotatingFile::open(dir.join("portfolio.log"), Some(64), Rotation::Never, 20).unwrap();
+        let logger = JsonLogger::new(None, file);
+
+        log_records(logger.as_ref(), 10);
+        assert!(dir.join("portfolio.log.1").exists());
+        assert_complete_lines(&dir, 10, |line| {
+            serde_json::from_str::<serde_json::Value>(line)
+                .map(|entry| entry["severity"] == "INFO")
+                .unwrap_or(false)
+        });
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_init_twice() {
+        assert!(init(&LogConfig {
+            rust_log: Some("portfolio=loud".to_string()),
+            ..LogConfig::default()
+        })
+        .is_err());
+        assert!(init(&LogConfig::default()).is_ok());
+
+        // the second config is ignored, the file is not opened
+        let dir = temp_dir("log_init_twice");
+        assert!(init(&LogConfig {
+            rust_log: Some("portfolio=loud".to_string()),
+            file: Some(dir.join("portfolio.log")),
+            ..LogConfig::default()
+        })
+        .is_ok());
+        assert!(!dir.join("portfolio.log").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::Value;
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+/// The header of the Google Cloud trace: `TRACE_ID/SPAN_ID;o=TRACE_TRUE`.
+pub const HEADER_CLOUD_TRACE_CONTEXT: &str = "x-cloud-trace-context";
+/// The header of the W3C trace context: `00-TRACE_ID-SPAN_ID-FLAGS`.
+pub const HEADER_TRACEPARENT: &str = "traceparent";
+
+thread_local! {
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+    static FIELDS: RefCell<Vec<(String, Value)>> = const { RefCell::new(vec![]) };
+}
+
+/// TraceContext correlates the log entries of a request in Cloud Logging,
+/// it is read from the header of the incoming request and sent with the outgoing requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    /// 32 hex characters.
+    pub trace_id: String,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// A new trace with a random trace id and span id, e.g. for a batch job.
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; 24];
+        // without a random generator, the trace is not unique, but the logging still works
+        let _ = SystemRandom::new().fill(&mut bytes);
+        let mut span = [0u8; 8];
+        span.copy_from_slice(&bytes[16..]);
+        TraceContext {
+            trace_id: bytes[..16].iter().map(|b| format!("{:02x}", b)).collect(),
+            span_id: u64::from_be_bytes(span).max(1),
+            sampled: false,
+        }
+    }
+
+    /// Parse the header `X-Cloud-Trace-Context: 105445aa7843bc8bf206b12000100000/1;o=1`,
+    /// the span id is a decimal number.
+    pub fn from_cloud_trace_context(header: &str) -> Option<Self> {
+        let (trace_id, rest) = match header.find('/') {
+            Some(i) => (&header[..i], &header[i + 1..]),
+            None => (header, ""),
+        };
+        if !is_trace_id(trace_id) {
+            return None;
+        }
+        let mut parts = rest.splitn(2, ';');
+        let span_id = parts.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+        let sampled = parts.next().map(|o| o.trim() == "o=1").unwrap_or(false);
+        Some(TraceContext {
+            trace_id: trace_id.to_lowercase(),
+            span_id,
+            sampled,
+        })
+    }
+
+    /// Parse the header `traceparent: 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01`.
+    pub fn from_traceparent(header: &str) -> Option<Self> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        match parts.as_slice() {
+            [_version, trace_id, span_id, flags] if is_trace_id(trace_id) => Some(TraceContext {
+                trace_id: trace_id.to_lowercase(),
+                span_id: u64::from_str_radix(span_id, 16).ok()?,
+                sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The trace of the incoming request, `traceparent` has precedence.
+    pub fn from_headers(headers: &http::HeaderMap) -> Option<Self> {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        header(HEADER_TRACEPARENT)
+            .and_then(TraceContext::from_traceparent)
+            .or_else(|| {
+                header(HEADER_CLOUD_TRACE_CONTEXT).and_then(TraceContext::from_cloud_trace_context)
+            })
+    }
+
+    /// The value for the header `X-Cloud-Trace-Context` of an outgoing request.
+    pub fn to_cloud_trace_context(&self) -> String {
+        format!(
+            "{}/{};o={}",
+            self.trace_id,
+            self.span_id,
+            if self.sampled { 1 } else { 0 }
+        )
+    }
+
+    /// The span id as 16 hex characters, like in Cloud Logging.
+    pub fn span_id_hex(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    /// The trace for the log entries of this thread, until the guard is dropped.
+    pub fn enter(self) -> TraceGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self)));
+        TraceGuard { previous }
+    }
+
+    /// The trace of this thread.
+    pub fn current() -> Option<TraceContext> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+}
+
+fn is_trace_id(trace_id: &str) -> bool {
+    trace_id.len() == 32
+        && trace_id.chars().all(|c| c.is_ascii_hexdigit())
+        && trace_id.chars().any(|c| c != '0')
+}
+
+/// TraceGuard restores the previous trace, when it is dropped.
+pub struct TraceGuard {
+    previous: Option<TraceContext>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// Log with additional fields, they are a part of the json log entries in the closure:
+///
+/// ```ignore
+/// logging::with_fields(vec![("operation", json!("lookup"))], || info!("done"));
+/// ```
+pub fn with_fields<R, F: FnOnce() -> R>(fields: Vec<(&str, Value)>, f: F) -> R {
+    let len = FIELDS.with(|current| {
+        let mut current = current.borrow_mut();
+        let len = current.len();
+        current.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+        len
+    });
+    // the fields are removed by the guard, also if the closure panics
+    let _guard = FieldsGuard { len };
+    f()
+}
+
+// FieldsGuard removes the fields of `with_fields`, when it is dropped.
+struct FieldsGuard {
+    len: usize,
+}
+
+impl Drop for FieldsGuard {
+    fn drop(&mut self) {
+        FIELDS.with(|current| current.borrow_mut().truncate(self.len));
+    }
+}
+
+/// The fields of this thread, see: `with_fields`.
+pub fn fields() -> Vec<(String, Value)> {
+    FIELDS.with(|current| current.borrow().clone())
+}
+
+/// The duration in the format of Cloud Logging: `0.123s`.
+pub fn latency(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const TRACE_ID: &str = "105445aa7843bc8bf206b12000100000";
+
+    #[test]
+    fn test_cloud_trace_context() {
+        let trace = TraceContext::from_cloud_trace_context(&format!("{}/1;o=1", TRACE_ID)).unwrap();
+        assert_eq!(TRACE_ID, trace.trace_id);
+        assert_eq!(1, trace.span_id);
+        assert!(trace.sampled);
+        assert_eq!(
+            format!("{}/1;o=1", TRACE_ID),
+            trace.to_cloud_trace_context()
+        );
+        assert_eq!("0000000000000001", trace.span_id_hex());
+
+        let trace = TraceContext::from_cloud_trace_context(TRACE_ID).unwrap();
+        assert_eq!(0, trace.span_id);
+        assert!(!trace.sampled);
+
+        assert_eq!(None, TraceContext::from_cloud_trace_context("abc/1;o=1"));
+    }
+
+    #[test]
+    fn test_traceparent() {
+        let trace = TraceContext::from_traceparent(
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        )
+        .unwrap();
+        assert_eq!("0af7651916cd43dd8448eb211c80319c", trace.trace_id);
+        assert_eq!("b7ad6b7169203331", trace.span_id_hex());
+        assert!(trace.sampled);
+
+        assert_eq!(
+            None,
+            TraceContext::from_traceparent(
+                "00-00000000000000000000000000000000-b7ad6b7169203331-01"
+            )
+        );
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(
+            HEADER_CLOUD_TRACE_CONTEXT,
+            format!("{}/7;o=0", TRACE_ID).parse().unwrap(),
+        );
+        assert_eq!(7, TraceContext::from_headers(&headers).unwrap().span_id);
+
+        headers.insert(
+            HEADER_TRACEPARENT,
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            "0af7651916cd43dd8448eb211c80319c",
+            TraceContext::from_headers(&headers).unwrap().trace_id
+        );
+    }
+
+    #[test]
+    fn test_enter_and_fields() {
+        let trace = TraceContext::generate();
+        assert_eq!(32, trace.trace_id.len());
+        {
+            let _guard = trace.clone().enter();
+            assert_eq!(Some(trace.clone()), TraceContext::current());
+
+            let inner = with_fields(vec![("operation", json!("lookup"))], || {
+                with_fields(vec![("kind", json!("Protocol"))], fields)
+            });
+            assert_eq!(2, inner.len());
+            assert!(fields().is_empty());
+        }
+        assert_eq!(None, TraceContext::current());
+    }
+
+    #[test]
+    fn test_fields_removed_after_panic() {
+        let result = std::panic::catch_unwind(|| {
+            with_fields(vec![("operation", json!("lookup"))], || panic!("failed"))
+        });
+        assert!(result.is_err());
+        assert!(fields().is_empty());
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!("0.123s", latency(Duration::from_millis(123)));
+    }
+}
@@ -1,77 +1,419 @@
 mod authentication;
+mod config;
 mod dotenv;
 mod logging;
+mod secret;
 
 mod gcloud;
-use gcloud::auth::{Auth, JwtToken};
+use gcloud::auth::adc::DefaultCredentials;
+use gcloud::auth::impersonate::Impersonated;
+use gcloud::auth::introspection::token_info;
+use gcloud::auth::self_signed::SelfSignedJwt;
+use gcloud::auth::service_account::ServiceAccountKey;
+use gcloud::auth::token::{CachedTokenProvider, TokenProvider};
+use gcloud::auth::{ApiKey, Auth, AuthError, PrivateKeyGrant};
 use gcloud::datastore::query::{Filter, Operator, Value};
+use gcloud::datastore::serializer::timestamp;
 use gcloud::datastore::Datastore;
+use gcloud::http::RetryPolicy;
 use gcloud::Error;
 
-use log::error;
+use authentication::scope;
+use chrono::{DateTime, Utc};
+use config::{Config, Settings};
+use http::header::AUTHORIZATION;
+use log::{debug, error, info, warn};
+use logging::{LogConfig, TraceContext};
+use secret::Cipher;
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
 
 #[derive(Deserialize, Serialize, Debug)]
 struct Hero {
-    #[serde(rename(deserialize = "HeroID"))]
+    #[serde(rename = "HeroID")]
     hero_id: isize,
-    #[serde(rename(deserialize = "Note"))]
+    #[serde(rename = "Note")]
     note: String,
-    #[serde(rename(deserialize = "Action"))]
+    #[serde(rename = "Action")]
     action: String,
-    #[serde(rename(deserialize = "Time"))]
-    time: String,
+    #[serde(rename = "Time", with = "timestamp")]
+    time: DateTime<Utc>,
+}
+
+const URL_DATASTORE: &str = "https://datastore.googleapis.com/";
+// the passphrase for `secret rotate`, without it a new key file is generated
+const ENV_NEW_PASSPHRASE: &str = "PORTFOLIO_NEW_PASSPHRASE";
+
+// the credentials of the login, a self-signed jwt (or an api key) is not an oauth2 token
+struct Login {
+    auth: Box<dyn Auth>,
+    self_signed: bool,
+}
+
+impl Login {
+    fn oauth2(auth: Box<dyn Auth>) -> Self {
+        Login {
+            auth,
+            self_signed: false,
+        }
+    }
+}
+
+// an api key (GOOGLE_API_KEY) is sent as it is, a service account key signs the jwt-token self
+// (without the request to oauth2), otherwise the application default credentials,
+// fallback is the private key from env PRIVATE_KEY
+fn login(settings: &Settings) -> Result<Login, AuthError> {
+    if let Some(api_key) = &settings.api_key {
+        info!("use the api key from GOOGLE_API_KEY");
+        // the scopes of an api key can not be checked, it is no oauth2 token
+        return Ok(Login {
+            auth: Box::new(ApiKey::new(api_key.clone())),
+            self_signed: true,
+        });
+    }
+
+    // another type of credentials (e.g. authorized_user) is read by the application default credentials
+    if let Some(Ok(key)) = settings
+        .credentials
+        .as_ref()
+        .map(ServiceAccountKey::from_file)
+    {
+        return Ok(service_account(key, settings));
+    }
+
+    // the credentials can be set in a dotenv file, so not only in the env
+    let adc = DefaultCredentials::new().with_credentials_file(settings.credentials.as_ref());
+    match adc.find() {
+        Ok(provider) => Ok(Login::oauth2(Box::new(provider))),
+        // an invalid file of GOOGLE_APPLICATION_CREDENTIALS is an error and not skipped
+        Err(err) if settings.credentials.is_some() => Err(err),
+        Err(msg) => {
+            info!("{}, try PRIVATE_KEY", msg);
+            let private_key = settings.private_key.as_ref().ok_or_else(|| {
+                AuthError::MissingCredentials("PRIVATE_KEY is not set".to_string())
+            })?;
+            let client_email = settings.client_email.as_ref().ok_or_else(|| {
+                AuthError::MissingCredentials("CLIENT_EMAIL is not set".to_string())
+            })?;
+            let grant = PrivateKeyGrant::new(private_key, client_email);
+            let jwt_token = grant.jwt_token()?;
+            // a new grant is minted before the access token expires
+            let auth = CachedTokenProvider::new(grant);
+            auth.token()?;
+
+            // write to dot-env-file, encrypted if there is a key file or a passphrase
+            // temporary solution, a file which could not be loaded is not overwritten
+            let stored = dotenv::Dotenv::new().and_then(|mut dotenv| {
+                store_jwt_token(&mut dotenv, jwt_token)?;
+                dotenv.write_to_file().map_err(|err| err.to_string())
+            });
+            if let Err(msg) = stored {
+                error!("the jwt-token is not written: {}", msg);
+            }
+            Ok(Login::oauth2(Box::new(auth)))
+        }
+    }
+}
+
+// a self-signed jwt has no scopes and no subject, so with the settings GOOGLE_SCOPES or GOOGLE_SUBJECT
+// the key is exchanged for an oauth2 access token
+fn service_account(key: ServiceAccountKey, settings: &Settings) -> Login {
+    if settings.scopes.is_empty() && settings.subject.is_none() {
+        return Login {
+            auth: Box::new(SelfSignedJwt::new(key)),
+            self_signed: true,
+        };
+    }
+
+    let key = key.with_scopes(settings.scopes.clone());
+    let key = match &settings.subject {
+        Some(subject) => key.with_subject(subject.as_str()),
+        None => key,
+    };
+    Login::oauth2(Box::new(CachedTokenProvider::new(key)))
+}
+
+// the jwt-token is encrypted, if there is a key file or a passphrase
+fn store_jwt_token(dotenv: &mut dotenv::Dotenv, jwt_token: String) -> Result<(), String> {
+    match Cipher::from_env() {
+        Ok(Some(cipher)) => dotenv
+            .put_encrypted(dotenv::KEY_JWT_TOKEN.to_string(), &jwt_token, &cipher)
+            .map_err(|err| err.to_string()),
+        Ok(None) => {
+            warn!("no secret key, the jwt-token is written in plain text");
+            dotenv.put(dotenv::KEY_JWT_TOKEN.to_string(), jwt_token);
+            Ok(())
+        }
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// the login, which acts as the service account of the setting GOOGLE_IMPERSONATE_SERVICE_ACCOUNT
+fn credentials(settings: &Settings) -> Result<Login, AuthError> {
+    let login = login(settings)?;
+    if settings.impersonate_service_account.is_empty() {
+        return Ok(login);
+    }
+    let impersonated = impersonate(login.auth, settings)?;
+    Ok(Login::oauth2(Box::new(CachedTokenProvider::new(
+        impersonated,
+    ))))
+}
+
+// act as the last service account of the setting, the service accounts before are the delegates
+fn impersonate(source: Box<dyn Auth>, settings: &Settings) -> Result<Impersonated, AuthError> {
+    let (target, delegates) = settings
+        .impersonate_service_account
+        .split_last()
+        .ok_or_else(|| {
+            AuthError::InvalidArgument("no service account to impersonate".to_string())
+        })?;
+    info!("impersonate service account: {}", target);
+    let impersonated = Impersonated::new(source, target.as_str())
+        .with_delegates(delegates.to_vec())
+        // the access token is only used for the datastore
+        .with_scopes(vec![scope::DATASTORE.to_string()]);
+    match settings.impersonate_lifetime {
+        Some(lifetime_secs) => impersonated.with_lifetime(lifetime_secs),
+        None => Ok(impersonated),
+    }
+}
+
+// the retries of the datastore calls, a missing setting is the default of the policy
+fn retry_policy(settings: &Settings) -> RetryPolicy {
+    let mut retry = RetryPolicy::default();
+    if let Some(max_attempts) = settings.retry_max_attempts {
+        retry = retry.with_max_attempts(max_attempts);
+    }
+    if let Some(millis) = settings.retry_initial_backoff_millis {
+        retry = retry.with_initial_backoff(Duration::from_millis(millis));
+    }
+    if let Some(secs) = settings.retry_deadline_secs {
+        retry = retry.with_deadline(Duration::from_secs(secs));
+    }
+    retry
+}
+
+// check the scopes of the token before the api calls, so a missing scope is not a 403 of the api,
+// only an oauth2 token is known by the tokeninfo endpoint
+fn verify_scopes(auth: &dyn Auth, url: &str, required: &[&str]) -> Result<(), String> {
+    let (name, value) = auth.header(url).map_err(|err| -> String { err.into() })?;
+    let token = match value.to_str().ok().and_then(|v| v.strip_prefix("Bearer ")) {
+        Some(token) if name == AUTHORIZATION => token,
+        _ => {
+            debug!("no bearer token, skip the check of the scopes");
+            return Ok(());
+        }
+    };
+
+    match token_info(token) {
+        Ok(info) if info.is_expired() => Err("the token is expired".to_string()),
+        Ok(info) if info.has_scopes(required) => Ok(()),
+        Ok(info) => Err(format!(
+            "the token misses the scopes: {}",
+            info.missing_scopes(required).join(" ")
+        )),
+        Err(err) => {
+            warn!("could not verify the scopes: {}", err);
+            Ok(())
+        }
+    }
+}
+
+// the config from the dotenv files and the env, the profile is selected by the flag --profile
+fn load_config() -> Result<Config, String> {
+    let profile = config::select_profile(env::args().skip(1));
+    Config::load(profile.as_deref()).map_err(|err| err.to_string())
+}
+
+const USAGE_SECRET: &str =
+    "usage: portfolio secret (keygen | encrypt KEY... | decrypt KEY... | rotate) [--file .env]";
+
+// portfolio secret encrypt PRIVATE_KEY jwt_token --file .env
+//
+// the key is read from the key file (env PORTFOLIO_SECRET_KEY_FILE, default .env.key)
+// or from the env PORTFOLIO_PASSPHRASE, rotate uses env PORTFOLIO_NEW_PASSPHRASE or a new key file
+fn secret_command(args: &[String]) -> Result<String, String> {
+    let mut file = PathBuf::from(".env");
+    let mut keys = vec![];
+    let mut args = args.iter();
+    let command = args.next().ok_or(USAGE_SECRET)?;
+    while let Some(arg) = args.next() {
+        if arg == "--file" {
+            file = args.next().map(PathBuf::from).ok_or(USAGE_SECRET)?;
+        } else {
+            keys.push(arg.clone());
+        }
+    }
+
+    let cipher = || -> Result<Cipher, String> {
+        match Cipher::from_env() {
+            Ok(Some(cipher)) => Ok(cipher),
+            Ok(None) => Err(format!(
+                "no key file and no passphrase, run: portfolio secret keygen or set env {}",
+                secret::ENV_PASSPHRASE
+            )),
+            Err(err) => Err(err.to_string()),
+        }
+    };
+    let mut dotenv = dotenv::Dotenv::from_file(&file)?;
+
+    let msg = match command.as_str() {
+        "keygen" => {
+            let path = Cipher::key_file();
+            if path.exists() {
+                return Err(format!("the key file '{}' exists already", path.display()));
+            }
+            Cipher::generate()
+                .and_then(|cipher| cipher.write_key_file(&path))
+                .map_err(|err| err.to_string())?;
+            return Ok(format!(
+                "key written to '{}', do not commit this file",
+                path.display()
+            ));
+        }
+        "encrypt" if !keys.is_empty() => {
+            let count = dotenv
+                .encrypt(&keys, &cipher()?)
+                .map_err(|err| err.to_string())?;
+            format!("{} value(s) encrypted", count)
+        }
+        "decrypt" if !keys.is_empty() => {
+            let count = dotenv
+                .decrypt(&keys, &cipher()?)
+                .map_err(|err| err.to_string())?;
+            format!("{} value(s) decrypted", count)
+        }
+        "rotate" => {
+            let old = cipher()?;
+            let (new, key_file) = match env::var(ENV_NEW_PASSPHRASE) {
+                Ok(passphrase) => (Cipher::from_passphrase(passphrase), None),
+                Err(_) => (Cipher::generate(), Some(Cipher::key_file())),
+            };
+            let new = new.map_err(|err| err.to_string())?;
+            let count = dotenv.rotate(&old, &new).map_err(|err| err.to_string())?;
+
+            // the new key is written first, so the old key is kept as long as the values are not written
+            if let Some(key_file) = key_file {
+                let new_key_file = PathBuf::from(format!("{}.new", key_file.display()));
+                new.write_key_file(&new_key_file)
+                    .map_err(|err| err.to_string())?;
+                dotenv.write_to_file().map_err(|err| err.to_string())?;
+                fs::rename(&new_key_file, &key_file).map_err(|err| err.to_string())?;
+                return Ok(format!(
+                    "{} value(s) encrypted with the new key in '{}'",
+                    count,
+                    key_file.display()
+                ));
+            }
+            format!("{} value(s) encrypted with the new passphrase", count)
+        }
+        _ => return Err(USAGE_SECRET.to_string()),
+    };
+
+    dotenv.write_to_file().map_err(|err| err.to_string())?;
+    Ok(format!("{}: {}", file.display(), msg))
 }
 
 fn main() {
-    logging::init();
-
-    match JwtToken::from_env_private_key(authentication::Claim::new()) {
-        Ok(auth) => {
-            // write to dot-env-file
-            // temporary solution
-            let mut dotenv = dotenv::Dotenv::new();
-            dotenv.put(
-                dotenv::KEY_JWT_TOKEN.to_string(),
-                auth.jwt_token.to_string(),
-            );
-            if let Err(msg) = dotenv.write_to_file() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("secret") {
+        if let Err(msg) = logging::init(&LogConfig::default()) {
+            eprintln!("{}", msg);
+        }
+        match secret_command(&args[1..]) {
+            Ok(msg) => println!("{}", msg),
+            Err(msg) => {
                 error!("{}", msg);
+                process::exit(1);
             }
+        }
+        return;
+    }
 
-            // do a lookup to the datastore
-            let q = auth.to_url_query();
-            let s = Datastore::new("goheros-207118", &q);
-            let now = Instant::now();
-            let r: Result<Hero, Error> = s.lookup("heroes", "Protocol", 4851027920551936);
-            println!("lookup result ({}ms): \n{:?}", now.elapsed().as_millis(), r);
-
-            let now = Instant::now();
-            let r: Result<Hero, Error> = s.lookup("heroes", "Protocol", 5066702320566272);
-            println!("lookup result ({}ms): \n{:?}", now.elapsed().as_millis(), r);
-
-            let now = Instant::now();
-            let filter = Filter {
-                property: "Action",
-                op: Operator::Equal,
-                value: Value::String(String::from("Delete")),
-            };
-            let r: Result<Vec<Hero>, Error> = s.query("heroes", "Protocol", &filter);
-            println!(
-                "query result: {} ({}ms): \n",
-                r.unwrap().len(),
-                now.elapsed().as_millis()
-            );
-
-            let now = Instant::now();
-            let r: Result<String, Error> = s.commit("heroes", "Rust-Test");
-            println!(
-                "commit result: {:?} ({}ms): \n",
-                r,
-                now.elapsed().as_millis()
-            );
-        }
-        Err(msg) => error!("{}", msg),
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(1);
+        }
+    };
+    let log_config = config
+        .deserialize::<LogConfig>()
+        .map_err(|err| err.to_string())
+        .and_then(|log_config| logging::init(&log_config));
+    if let Err(msg) = log_config {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+    if let Some(profile) = config.profile() {
+        info!("profile: {}", profile);
+    }
+
+    // all log entries and api calls of this run are one trace
+    let _trace = TraceContext::generate().enter();
+
+    let settings: Settings = match config.deserialize() {
+        Ok(settings) => settings,
+        Err(msg) => {
+            error!("{}", msg);
+            return;
+        }
+    };
+
+    let login = match credentials(&settings) {
+        Ok(login) => login,
+        Err(msg) => {
+            error!("{}", msg);
+            return;
+        }
+    };
+    let auth = login.auth;
+
+    if !login.self_signed {
+        if let Err(msg) = verify_scopes(auth.as_ref(), URL_DATASTORE, &[scope::DATASTORE]) {
+            error!("{}", msg);
+            return;
+        }
+    }
+
+    // do a lookup to the datastore
+    let s = Datastore::new(&settings.project_id, auth.as_ref())
+        .with_retry_policy(retry_policy(&settings));
+    let s = match &settings.datastore_emulator_host {
+        Some(host) => s.with_endpoint(&format!("http://{}", host)),
+        None => s,
+    };
+    let s = match &settings.database {
+        Some(database) => s.with_database(database),
+        None => s,
+    };
+
+    let now = Instant::now();
+    let r: Result<Hero, Error> =
+        s.lookup(&s.key(&settings.namespace, "Protocol", Some(4851027920551936)));
+    println!("lookup result ({}ms): \n{:?}", now.elapsed().as_millis(), r);
+
+    let now = Instant::now();
+    let r: Result<Hero, Error> =
+        s.lookup(&s.key(&settings.namespace, "Protocol", Some(5066702320566272)));
+    println!("lookup result ({}ms): \n{:?}", now.elapsed().as_millis(), r);
+
+    let now = Instant::now();
+    let filter = Filter {
+        property: "Action",
+        op: Operator::Equal,
+        value: Value::String(String::from("Delete")),
     };
+    let r: Result<Vec<Hero>, Error> =
+        s.query(&s.partition(&settings.namespace), "Protocol", &filter);
+    println!(
+        "query result: {} ({}ms): \n",
+        r.unwrap().len(),
+        now.elapsed().as_millis()
+    );
 }
@@ -0,0 +1,425 @@
+use crate::dotenv;
+
+use log::debug;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The env with the path of the key file, default: `.env.key`.
+pub const ENV_SECRET_KEY_FILE: &str = "PORTFOLIO_SECRET_KEY_FILE";
+/// The env with the passphrase, it is used, if there is no key file.
+pub const ENV_PASSPHRASE: &str = "PORTFOLIO_PASSPHRASE";
+pub const DEFAULT_KEY_FILE: &str = ".env.key";
+
+const PREFIX: &str = "ENC[AES256_GCM,";
+const SUFFIX: &str = "]";
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+// owasp recommendation for PBKDF2-HMAC-SHA256 (2020)
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// The errors of the encryption and decryption of the secrets.
+#[derive(Debug, PartialEq)]
+pub enum SecretError {
+    /// There is an encrypted value, but no key file and no passphrase.
+    MissingKey(String),
+    /// The key file does not contain a base64 encoded 256-bit key.
+    InvalidKey(String),
+    /// The value does not have the format `ENC[AES256_GCM,data:...,iv:...]`.
+    Malformed(String),
+    /// The key is wrong or the value (or the name of the value) was modified.
+    Decrypt(String),
+    /// The name is not in the dotenv file.
+    NotFound(String),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::MissingKey(msg) => write!(f, "missing secret key: {}", msg),
+            SecretError::InvalidKey(msg) => write!(f, "invalid secret key: {}", msg),
+            SecretError::Malformed(msg) => write!(f, "malformed encrypted value: {}", msg),
+            SecretError::Decrypt(name) => write!(
+                f,
+                "could not decrypt the value of '{}', the key is wrong or the value was modified",
+                name
+            ),
+            SecretError::NotFound(name) => write!(f, "'{}' not found", name),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+/// Is the value encrypted: `ENC[AES256_GCM,...]`.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(PREFIX) && value.ends_with(SUFFIX)
+}
+
+enum Secret {
+    Key([u8; KEY_LEN]),
+    // the derived keys are cached by salt, the derivation is slow by intention
+    Passphrase {
+        passphrase: String,
+        salt: [u8; SALT_LEN],
+        keys: Mutex<HashMap<Vec<u8>, [u8; KEY_LEN]>>,
+    },
+}
+
+/// Cipher encrypts a value with AES-256-GCM into `ENC[AES256_GCM,data:...,iv:...]`.
+///
+/// The key is a random 256-bit key from a key file or it is derived from a passphrase
+/// with PBKDF2-HMAC-SHA256, then the salt is part of the encrypted value.
+/// The name of the value is authenticated too, so an encrypted value can not be moved to another name.
+pub struct Cipher {
+    secret: Secret,
+    rng: SystemRandom,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let source = match self.secret {
+            Secret::Key(_) => "key",
+            Secret::Passphrase { .. } => "passphrase",
+        };
+        f.debug_struct("Cipher").field("secret", &source).finish()
+    }
+}
+
+impl Cipher {
+    pub fn from_key(key: [u8; KEY_LEN]) -> Self {
+        Cipher {
+            secret: Secret::Key(key),
+            rng: SystemRandom::new(),
+        }
+    }
+
+    /// A new random key.
+    pub fn generate() -> Result<Self, SecretError> {
+        let mut key = [0u8; KEY_LEN];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| SecretError::InvalidKey("could not generate a random key".to_string()))?;
+        Ok(Cipher::from_key(key))
+    }
+
+    /// Read the base64 encoded key from the file.
+    pub fn from_key_file<P: AsRef<Path>>(path: P) -> Result<Self, SecretError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|err| {
+            SecretError::MissingKey(format!("could not read file '{}': {}", path.display(), err))
+        })?;
+        let bytes = base64::decode(content.trim())
+            .map_err(|err| SecretError::InvalidKey(format!("{}: {}", path.display(), err)))?;
+        if bytes.len() != KEY_LEN {
+            return Err(SecretError::InvalidKey(format!(
+                "{}: expected {} bytes, found {}",
+                path.display(),
+                KEY_LEN,
+                bytes.len()
+            )));
+        }
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(&bytes);
+        Ok(Cipher::from_key(key))
+    }
+
+    pub fn from_passphrase<S: Into<String>>(passphrase: S) -> Result<Self, SecretError> {
+        let passphrase = passphrase.into();
+        if passphrase.is_empty() {
+            return Err(SecretError::InvalidKey("empty passphrase".to_string()));
+        }
+        let rng = SystemRandom::new();
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill(&mut salt)
+            .map_err(|_| SecretError::InvalidKey("could not generate a random salt".to_string()))?;
+        Ok(Cipher {
+            secret: Secret::Passphrase {
+                passphrase,
+                salt,
+                keys: Mutex::new(HashMap::new()),
+            },
+            rng,
+        })
+    }
+
+    /// The cipher from the key file (env `PORTFOLIO_SECRET_KEY_FILE` or `.env.key`),
+    /// otherwise from the env `PORTFOLIO_PASSPHRASE`. `None`, if there is neither.
+    pub fn from_env() -> Result<Option<Self>, SecretError> {
+        if let Ok(path) = env::var(ENV_SECRET_KEY_FILE) {
+            return Cipher::from_key_file(path).map(Some);
+        }
+        if Path::new(DEFAULT_KEY_FILE).exists() {
+            return Cipher::from_key_file(DEFAULT_KEY_FILE).map(Some);
+        }
+        match env::var(ENV_PASSPHRASE) {
+            Ok(passphrase) => Cipher::from_passphrase(passphrase).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// The path of the key file, the env `PORTFOLIO_SECRET_KEY_FILE` or `.env.key`.
+    pub fn key_file() -> PathBuf {
+        env::var(ENV_SECRET_KEY_FILE)
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from(DEFAULT_KEY_FILE))
+    }
+
+    /// The base64 encoded key for the key file, `None` for a passphrase.
+    pub fn export_key(&self) -> Option<String> {
+        match &self.secret {
+            Secret::Key(key) => Some(base64::encode(key)),
+            Secret::Passphrase { .. } => None,
+        }
+    }
+
+    /// Write the key to the key file (0600), only for a key and not for a passphrase.
+    pub fn write_key_file<P: AsRef<Path>>(&self, path: P) -> Result<(), SecretError> {
+        let path = path.as_ref();
+        let key = self.export_key().ok_or_else(|| {
+            SecretError::InvalidKey("a passphrase is not written to a file".to_string())
+        })?;
+        dotenv::write_atomic(path, format!("{}\n", key).as_bytes()).map_err(|err| {
+            SecretError::InvalidKey(format!(
+                "could not write file '{}': {}",
+                path.display(),
+                err
+            ))
+        })
+    }
+
+    /// Encrypt the value, the name is authenticated (additional data).
+    pub fn encrypt(&self, name: &str, plaintext: &str) -> Result<String, SecretError> {
+        let mut iv = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut iv)
+            .map_err(|_| SecretError::InvalidKey("could not generate a random iv".to_string()))?;
+
+        let (key, salt) = match &self.secret {
+            Secret::Key(key) => (*key, None),
+            Secret::Passphrase { salt, .. } => (self.derive(salt)?, Some(salt)),
+        };
+
+        let mut data = plaintext.as_bytes().to_vec();
+        less_safe_key(&key)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| SecretError::Malformed(format!("could not encrypt '{}'", name)))?;
+
+        let mut encrypted = format!(
+            "{}data:{},iv:{}",
+            PREFIX,
+            base64::encode(&data),
+            base64::encode(&iv)
+        );
+        if let Some(salt) = salt {
+            encrypted.push_str(&format!(",salt:{}", base64::encode(salt)));
+        }
+        encrypted.push_str(SUFFIX);
+        Ok(encrypted)
+    }
+
+    /// Decrypt the value, a value, which is not encrypted, is returned as it is.
+    pub fn decrypt(&self, name: &str, value: &str) -> Result<String, SecretError> {
+        if !is_encrypted(value) {
+            return Ok(value.to_string());
+        }
+        let parts = Parts::parse(value)?;
+
+        let key = match (&self.secret, &parts.salt) {
+            (Secret::Key(key), None) => *key,
+            (Secret::Passphrase { .. }, Some(salt)) => self.derive(salt)?,
+            (Secret::Key(_), Some(_)) => {
+                return Err(SecretError::MissingKey(format!(
+                    "'{}' is encrypted with a passphrase, set env {}",
+                    name, ENV_PASSPHRASE
+                )))
+            }
+            (Secret::Passphrase { .. }, None) => {
+                return Err(SecretError::MissingKey(format!(
+                    "'{}' is encrypted with a key file, set env {}",
+                    name, ENV_SECRET_KEY_FILE
+                )))
+            }
+        };
+
+        let mut iv = [0u8; NONCE_LEN];
+        if parts.iv.len() != NONCE_LEN {
+            return Err(SecretError::Malformed(format!("invalid iv of '{}'", name)));
+        }
+        iv.copy_from_slice(&parts.iv);
+
+        let mut data = parts.data;
+        let plaintext = less_safe_key(&key)?
+            .open_in_place(
+                Nonce::assume_unique_for_key(iv),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .map_err(|_| SecretError::Decrypt(name.to_string()))?;
+        String::from_utf8(plaintext.to_vec()).map_err(|_| SecretError::Decrypt(name.to_string()))
+    }
+
+    fn derive(&self, salt: &[u8]) -> Result<[u8; KEY_LEN], SecretError> {
+        let (passphrase, keys) = match &self.secret {
+            Secret::Passphrase {
+                passphrase, keys, ..
+            } => (passphrase, keys),
+            Secret::Key(key) => return Ok(*key),
+        };
+
+        let mut keys = keys.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(key) = keys.get(salt) {
+            return Ok(*key);
+        }
+
+        debug!("derive the key from the passphrase");
+        let mut key = [0u8; KEY_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PBKDF2_ITERATIONS).unwrap(),
+            salt,
+            passphrase.as_bytes(),
+            &mut key,
+        );
+        keys.insert(salt.to_vec(), key);
+        Ok(key)
+    }
+}
+
+fn less_safe_key(key: &[u8; KEY_LEN]) -> Result<LessSafeKey, SecretError> {
+    UnboundKey::new(&AES_256_GCM, key)
+        .map(LessSafeKey::new)
+        .map_err(|_| SecretError::InvalidKey("not an AES-256 key".to_string()))
+}
+
+// ENC[AES256_GCM,data:<base64>,iv:<base64>,salt:<base64>]
+struct Parts {
+    data: Vec<u8>,
+    iv: Vec<u8>,
+    salt: Option<Vec<u8>>,
+}
+
+impl Parts {
+    fn parse(value: &str) -> Result<Parts, SecretError> {
+        let inner = &value[PREFIX.len()..value.len() - SUFFIX.len()];
+        let mut data = None;
+        let mut iv = None;
+        let mut salt = None;
+        for part in inner.split(',') {
+            let (name, encoded) = match part.find(':') {
+                Some(i) => (&part[..i], &part[i + 1..]),
+                None => return Err(SecretError::Malformed(format!("invalid part: '{}'", part))),
+            };
+            let bytes = base64::decode(encoded)
+                .map_err(|err| SecretError::Malformed(format!("{}: {}", name, err)))?;
+            match name {
+                "data" => data = Some(bytes),
+                "iv" => iv = Some(bytes),
+                "salt" => salt = Some(bytes),
+                _ => return Err(SecretError::Malformed(format!("unknown part: '{}'", name))),
+            }
+        }
+
+        match (data, iv) {
+            (Some(data), Some(iv)) => Ok(Parts { data, iv, salt }),
+            _ => Err(SecretError::Malformed("data or iv is missing".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud::testutil;
+
+    #[test]
+    fn test_encrypt_decrypt_with_key() {
+        let cipher = Cipher::generate().unwrap();
+        let encrypted = cipher.encrypt("jwt_token", "a.b.c").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("a.b.c"));
+        assert_eq!("a.b.c", cipher.decrypt("jwt_token", &encrypted).unwrap());
+
+        // the iv is random
+        assert_ne!(encrypted, cipher.encrypt("jwt_token", "a.b.c").unwrap());
+        // a plain value is not changed
+        assert_eq!("plain", cipher.decrypt("jwt_token", "plain").unwrap());
+    }
+
+    #[test]
+    fn test_decrypt_wrong_key_or_name() {
+        let cipher = Cipher::generate().unwrap();
+        let encrypted = cipher.encrypt("PRIVATE_KEY", "secret").unwrap();
+
+        assert_eq!(
+            SecretError::Decrypt("PRIVATE_KEY".to_string()),
+            Cipher::generate()
+                .unwrap()
+                .decrypt("PRIVATE_KEY", &encrypted)
+                .unwrap_err()
+        );
+        // the value is bound to the name
+        assert_eq!(
+            SecretError::Decrypt("OTHER".to_string()),
+            cipher.decrypt("OTHER", &encrypted).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt_with_passphrase() {
+        let cipher = Cipher::from_passphrase("correct horse battery staple").unwrap();
+        let encrypted = cipher.encrypt("PRIVATE_KEY", "secret").unwrap();
+        assert!(encrypted.contains(",salt:"));
+
+        // a new cipher has an other salt, the key is derived from the salt of the value
+        let other = Cipher::from_passphrase("correct horse battery staple").unwrap();
+        assert_eq!("secret", other.decrypt("PRIVATE_KEY", &encrypted).unwrap());
+
+        let wrong = Cipher::from_passphrase("wrong").unwrap();
+        assert!(wrong.decrypt("PRIVATE_KEY", &encrypted).is_err());
+        assert!(Cipher::from_passphrase("").is_err());
+    }
+
+    #[test]
+    fn test_key_file() {
+        let cipher = Cipher::generate().unwrap();
+        let path = testutil::temp_dir("secret_key_file").join(".env.key");
+        cipher.write_key_file(&path).unwrap();
+
+        let encrypted = cipher.encrypt("jwt_token", "a.b.c").unwrap();
+        let from_file = Cipher::from_key_file(&path).unwrap();
+        assert_eq!("a.b.c", from_file.decrypt("jwt_token", &encrypted).unwrap());
+
+        fs::write(&path, "c2hvcnQ=").unwrap();
+        assert!(matches!(
+            Cipher::from_key_file(&path),
+            Err(SecretError::InvalidKey(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_malformed() {
+        let cipher = Cipher::generate().unwrap();
+        assert!(matches!(
+            cipher.decrypt("A", "ENC[AES256_GCM,data:AAAA]"),
+            Err(SecretError::Malformed(_))
+        ));
+        assert!(matches!(
+            cipher.decrypt("A", "ENC[AES256_GCM,data:AAAA,iv:!!]"),
+            Err(SecretError::Malformed(_))
+        ));
+    }
+}